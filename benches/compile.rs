@@ -0,0 +1,151 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use inkwell::OptimizationLevel;
+
+use bf_llvm::interpreter::{self, Limits, Tape};
+use bf_llvm::jit::run_jit;
+use bf_llvm::lexer::parse;
+use bf_llvm::optimizer::Passes;
+use bf_llvm::{compile_to_object, CompileOptions};
+
+const PASSES: [&str; 5] = [
+    "fold_moves",
+    "fold_loops",
+    "fold_clears",
+    "partial_eval",
+    "remove_dead_loops",
+];
+
+/// Three nested counting loops, whose innermost body runs `n^3` times.
+fn nested_loops(n: usize) -> String {
+    format!("{c}[>{c}[>{c}[>+<-]<-]<-]", c = "+".repeat(n))
+}
+
+/// The programs to compile, by name. A long program gives the op level
+/// passes and LLVM something to chew on.
+fn programs() -> Vec<(&'static str, String)> {
+    let hello = include_str!("../examples/hello_world.bf");
+    vec![
+        ("hello_world", hello.to_string()),
+        ("procs", include_str!("../examples/procs.bf").to_string()),
+        ("nested_loops", nested_loops(200)),
+        ("hello_world_x1000", hello.repeat(1000)),
+    ]
+}
+
+fn opt_levels(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compile");
+    group.sample_size(10);
+    for (name, source) in programs() {
+        for (level, opt_level) in [
+            ("O0", OptimizationLevel::None),
+            ("O1", OptimizationLevel::Less),
+            ("O2", OptimizationLevel::Default),
+            ("O3", OptimizationLevel::Aggressive),
+        ] {
+            let opts = CompileOptions {
+                opt_level,
+                ..Default::default()
+            };
+            group.bench_with_input(BenchmarkId::new(name, level), &source, |b, source| {
+                b.iter(|| compile_to_object(black_box(source), &opts).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Compile time with all op level passes, and with each one left out.
+fn passes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("passes");
+    group.sample_size(10);
+    for (name, source) in programs() {
+        let configs =
+            std::iter::once(("all", Passes::default())).chain(PASSES.iter().map(|pass| {
+                let mut passes = Passes::default();
+                *passes.by_name(pass).unwrap() = false;
+                (*pass, passes)
+            }));
+        for (label, passes) in configs {
+            let opts = CompileOptions {
+                passes,
+                ..Default::default()
+            };
+            let id = if label == "all" {
+                BenchmarkId::new(name, "all")
+            } else {
+                BenchmarkId::new(name, format!("without {}", label))
+            };
+            group.bench_with_input(id, &source, |b, source| {
+                b.iter(|| compile_to_object(black_box(source), &opts).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The reference interpreter on unoptimized ops, as a baseline for what
+/// the compiled code should beat. Throughput is innermost loop iterations,
+/// each of which updates two cells.
+fn interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+    group.sample_size(10);
+    for n in [50, 100, 200] {
+        let ops = parse(&nested_loops(n)).unwrap();
+        group.throughput(Throughput::Elements((n * n * n) as u64));
+        group.bench_with_input(BenchmarkId::new("nested_loops", n), &ops, |b, ops| {
+            b.iter(|| {
+                interpreter::interpret(black_box(ops), b"", Tape::default(), Limits::default())
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Sets 29,835 cells of a 30,000 cell tape to 1, after cell 0 and a zero
+/// cell 1, then walks over them with `[>]` and back with `[<]` as many
+/// times as the byte read into cell 0 says. Reading the count first keeps
+/// the filling from being evaluated at compile time.
+fn scans() -> String {
+    let fill = "-[-[->+<]+>]".repeat(117);
+    format!(",>>{}<[<]<[>>[>]<[<]<-]", fill)
+}
+
+/// Running a program that is all scans in the JIT, compile time included,
+/// where `[>]` is a memchr over the rest of the tape. Throughput is cells
+/// scanned in both directions.
+fn scan(c: &mut Criterion) {
+    let source = scans();
+    let opts = CompileOptions {
+        cells: 30_000,
+        ..Default::default()
+    };
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    for rounds in [10u8, 100] {
+        group.throughput(Throughput::Elements(rounds as u64 * 2 * 29_835));
+        group.bench_with_input(
+            BenchmarkId::new("tape_30k", rounds),
+            &rounds,
+            |b, &rounds| b.iter(|| run_jit(black_box(&source), &[rounds], &opts).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+/// Lexing alone, on a program as big as those generated by compilers to BF,
+/// comments and all.
+fn lexer(c: &mut Criterion) {
+    let hello = include_str!("../examples/hello_world.bf");
+    let source = hello.repeat(100_000_000 / hello.len());
+    let mut group = c.benchmark_group("lexer");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_with_input("hello_world_100MB", &source, |b, source| {
+        b.iter(|| parse(black_box(source)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, opt_levels, passes, interpreter, scan, lexer);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+"""Compiles hello_world.bf through the C API with ctypes, as a smoke test.
+
+Build the library first with
+`cargo rustc --lib --features ffi --crate-type cdylib`, then run
+`python3 examples/ffi_smoke.py [path/to/library]`.
+"""
+
+import ctypes
+import pathlib
+import sys
+
+
+class BfOptions(ctypes.Structure):
+    _fields_ = [
+        ("cells", ctypes.c_uint64),
+        ("cell_bits", ctypes.c_uint32),
+        ("opt_level", ctypes.c_uint32),
+        ("wrap_pointer", ctypes.c_int),
+        ("signed_cells", ctypes.c_int),
+        ("exit_cell", ctypes.c_int),
+    ]
+
+
+ROOT = pathlib.Path(__file__).resolve().parent.parent
+NAMES = {"win32": "bf_llvm.dll", "darwin": "libbf_llvm.dylib"}
+# The magic numbers an ELF, COFF or Mach-O object starts with.
+MAGIC = (b"\x7fELF", b"\x64\x86", b"\x4c\x01", b"\x64\xaa", b"\xcf\xfa\xed\xfe")
+
+default = ROOT / "target" / "debug" / NAMES.get(sys.platform, "libbf_llvm.so")
+path = sys.argv[1] if len(sys.argv) > 1 else default
+lib = ctypes.CDLL(str(path))
+lib.bf_default_options.argtypes = [ctypes.POINTER(BfOptions)]
+lib.bf_compile_to_object.argtypes = [
+    ctypes.c_char_p,
+    ctypes.POINTER(BfOptions),
+    ctypes.POINTER(ctypes.POINTER(ctypes.c_uint8)),
+    ctypes.POINTER(ctypes.c_size_t),
+]
+lib.bf_compile_to_object.restype = ctypes.c_int
+lib.bf_free_buffer.argtypes = [ctypes.POINTER(ctypes.c_uint8), ctypes.c_size_t]
+lib.bf_last_error_message.restype = ctypes.c_char_p
+
+opts = BfOptions()
+lib.bf_default_options(ctypes.byref(opts))
+assert (opts.cells, opts.cell_bits) == (1000, 8), (opts.cells, opts.cell_bits)
+buf = ctypes.POINTER(ctypes.c_uint8)()
+length = ctypes.c_size_t()
+
+source = (ROOT / "examples" / "hello_world.bf").read_bytes()
+code = lib.bf_compile_to_object(source, ctypes.byref(opts), ctypes.byref(buf), ctypes.byref(length))
+assert code == 0, (code, lib.bf_last_error_message())
+size = length.value
+object = ctypes.string_at(buf, size)
+assert object.startswith(MAGIC), object[:4]
+lib.bf_free_buffer(buf, length)
+
+code = lib.bf_compile_to_object(b"+]", None, ctypes.byref(buf), ctypes.byref(length))
+assert code == -2, code
+assert b"unmatched bracket" in lib.bf_last_error_message()
+opts.cell_bits = 12
+assert lib.bf_compile_to_object(b"+", ctypes.byref(opts), ctypes.byref(buf), ctypes.byref(length)) == -1
+
+print("ok: hello_world.bf compiled to a {} byte object".format(size))
@@ -1,4 +1,4 @@
-?>[-]?
-|?+[------->++<]>--.|
-*?+[------->++<]>+.*
+%>[-]%
+|%+[------->++<]>--.|
+*%+[------->++<]>+.*
 |*|
\ No newline at end of file
@@ -0,0 +1,38 @@
+#![no_main]
+
+use bf_llvm::dump;
+use bf_llvm::lexer::Lexer;
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks the lexer's modes, the rest is the source, decoded
+// the way `read_source` does so invalid UTF-8 gets in too.
+fuzz_target!(|data: &[u8]| {
+    let Some((&modes, source)) = data.split_first() else {
+        return;
+    };
+    let source = String::from_utf8_lossy(source).into_owned();
+    let lexer = |max_errors| {
+        let mut lexer = Lexer::new(source.clone());
+        lexer.set_strict(modes & 1 != 0);
+        lexer.set_debug_ops(modes & 2 != 0);
+        lexer.set_max_errors(max_errors);
+        lexer
+    };
+
+    let mut first = lexer(1);
+    let result = first.run();
+    // Whatever the lexer lets through has every bracket and proc matched.
+    if let Ok(ops) = &result {
+        assert_eq!(dump::validate(ops), Ok(()));
+    }
+
+    // Going on after errors finds the same first one, and the `Iterator`
+    // agrees with `run`.
+    let mut all = lexer(20);
+    let found: Result<Vec<_>, _> = all.by_ref().map(|op| op.map(|op| op.value)).collect();
+    assert_eq!(found.as_ref().err(), result.as_ref().err());
+    if let (Ok(found), Ok(ops)) = (&found, &result) {
+        assert_eq!(found.len(), ops.len());
+    }
+    assert!(all.take_errors().len() <= 20);
+});
@@ -0,0 +1,430 @@
+use std::fmt::Write;
+
+use crate::codegen::proc_symbol;
+use crate::ops::{print_utf8, Op};
+use crate::{CompileOptions, OutputEncoding};
+
+const UTF8_HELPER: &str = "static void bf_put_utf8(uint32_t c)
+{
+    if (c > 0x10FFFF || (c >= 0xD800 && c < 0xE000))
+        c = 0xFFFD;
+    if (c < 0x80) {
+        putchar(c);
+    } else if (c < 0x800) {
+        putchar(0xC0 | c >> 6);
+        putchar(0x80 | (c & 0x3F));
+    } else if (c < 0x10000) {
+        putchar(0xE0 | c >> 12);
+        putchar(0x80 | (c >> 6 & 0x3F));
+        putchar(0x80 | (c & 0x3F));
+    } else {
+        putchar(0xF0 | c >> 18);
+        putchar(0x80 | (c >> 12 & 0x3F));
+        putchar(0x80 | (c >> 6 & 0x3F));
+        putchar(0x80 | (c & 0x3F));
+    }
+}
+";
+
+const DUMP_CELLS_HELPER: &str = "static void bf_dump_cells(cell *p, long start, long end)
+{
+    long index = p - tape;
+    fprintf(stderr, \"ptr=%ld\\n\", index);
+    for (long i = start; i < end; i++)
+        fprintf(stderr, \"%0*lx \", BF_CELL_DIGITS, (unsigned long)tape[i]);
+    fprintf(stderr, \"\\n%*s^\\n\", (int)((index - start) * (BF_CELL_DIGITS + 1)), \"\");
+}
+";
+
+const DUMP_HELPER: &str = "static void bf_dump_tape(cell *p)
+{
+    long index = p - tape;
+    long start = index > 8 ? index - 8 : 0;
+    bf_dump_cells(p, start, start + 16 < BF_TAPE_SIZE ? start + 16 : BF_TAPE_SIZE);
+}
+";
+
+const STEP_LIMIT_HELPER: &str = "static void bf_step_limit(void)
+{
+    fputs(\"step limit exceeded\\n\", stderr);
+    exit(3);
+}
+";
+
+/// A C function being written: main or a proc.
+struct Function {
+    /// The proc's symbol, `None` for main.
+    symbol: Option<String>,
+    body: String,
+    depth: usize,
+}
+
+struct Translator<'a> {
+    options: &'a CompileOptions,
+    /// Finished procs, in the order their definitions ended.
+    procs: Vec<Function>,
+    /// Functions still being written, innermost last. Procs can be defined
+    /// inside other code, but C functions can't nest, so each gets a body
+    /// of its own.
+    open: Vec<Function>,
+    uses_input: bool,
+    uses_utf8: bool,
+    uses_dump: bool,
+}
+
+impl<'a> Translator<'a> {
+    fn line(&mut self, text: &str) {
+        let f = self.open.last_mut().unwrap();
+        let _ = writeln!(f.body, "{}{}", "    ".repeat(f.depth), text);
+    }
+
+    /// Statement moving `p` by `count` cells, around the ends of the tape
+    /// with `wrap_pointer`.
+    fn move_ptr(&mut self, count: usize, dec: bool) {
+        if !self.options.wrap_pointer {
+            let op = if dec { "-=" } else { "+=" };
+            self.line(&format!("p {} {};", op, count));
+            return;
+        }
+        let step = count as u64 % self.options.cells;
+        let step = if dec { self.options.cells - step } else { step };
+        self.line(&format!("p = tape + (p - tape + {}) % BF_TAPE_SIZE;", step));
+    }
+
+    /// Counts a jump back to a loop condition against `max_steps`.
+    fn count_step(&mut self) {
+        if self.options.max_steps > 0 {
+            self.line(&format!(
+                "if (++bf_steps > {}ULL) bf_step_limit();",
+                self.options.max_steps
+            ));
+        }
+    }
+
+    fn open_block(&mut self, header: &str) {
+        self.line(&format!("{} {{", header));
+        self.open.last_mut().unwrap().depth += 1;
+    }
+
+    fn close_block(&mut self) {
+        self.open.last_mut().unwrap().depth -= 1;
+        self.line("}");
+    }
+
+    fn output(&mut self, count: usize) {
+        let put = match self.options.output_encoding {
+            OutputEncoding::Byte => "putchar(*p);".to_string(),
+            OutputEncoding::Utf8 => {
+                self.uses_utf8 = true;
+                // Cells are unsigned in C, so signed ones go through the
+                // signed type of their width to sign-extend.
+                if self.options.signed_cells {
+                    format!("bf_put_utf8((int{}_t)*p);", self.options.cell_bits)
+                } else {
+                    "bf_put_utf8(*p);".to_string()
+                }
+            }
+        };
+        if count == 1 {
+            self.line(&put);
+        } else {
+            self.line(&format!("for (int i = 0; i < {}; i++) {}", count, put));
+        }
+    }
+
+    fn print(&mut self, bytes: &[u8]) {
+        let encoded: Vec<u8>;
+        let bytes = match self.options.output_encoding {
+            OutputEncoding::Byte => bytes,
+            OutputEncoding::Utf8 => {
+                encoded = print_utf8(bytes, self.options.signed_cells);
+                &encoded
+            }
+        };
+        self.line(&format!(
+            "fwrite(\"{}\", 1, {}, stdout);",
+            escape(bytes),
+            bytes.len()
+        ));
+    }
+
+    fn op(&mut self, op: &Op) {
+        match op {
+            Op::PointerInc(v) => self.move_ptr(*v, false),
+            Op::PointerDec(v) => self.move_ptr(*v, true),
+            Op::ValueInc(v) => self.line(&format!("*p += {};", v)),
+            Op::ValueDec(v) => self.line(&format!("*p -= {};", v)),
+            Op::Output(v) => self.output(*v),
+            Op::Input => {
+                self.uses_input = true;
+                self.line("*p = bf_getchar();");
+            }
+            Op::LLoop => self.open_block("while (*p)"),
+            Op::RLoop => {
+                self.count_step();
+                self.close_block();
+            }
+            Op::ScanInc(v) | Op::ScanDec(v) => {
+                self.open_block("while (*p)");
+                self.move_ptr(*v, matches!(op, Op::ScanDec(_)));
+                self.count_step();
+                self.close_block();
+            }
+            Op::Set(v) => self.line(&format!("*p = {};", v)),
+            Op::Print(bytes) => self.print(bytes),
+            Op::Clear(v) => self.line(&format!("memset(p, 0, {} * sizeof(cell));", v)),
+            Op::ProcDef(name) => {
+                let symbol = proc_symbol(name);
+                self.open.push(Function {
+                    symbol: Some(symbol),
+                    body: String::new(),
+                    depth: 1,
+                });
+            }
+            Op::ProcCall(name) => self.line(&format!("{}(p);", proc_symbol(name))),
+            Op::ProcEnd => {
+                // A stray end marker in main has nothing to close.
+                if self.open.len() > 1 {
+                    let f = self.open.pop().unwrap();
+                    self.procs.push(f);
+                }
+            }
+            Op::DumpTape => {
+                self.uses_dump = true;
+                self.line("bf_dump_tape(p);");
+            }
+        }
+    }
+}
+
+/// Escapes `bytes` for a C string literal. Octal escapes are always three
+/// digits, so they can't run into a digit that follows.
+fn escape(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for &b in bytes {
+        match b {
+            // `?` could start a trigraph.
+            0x20..=0x7e if !matches!(b, b'"' | b'\\' | b'?') => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\{:03o}", b);
+            }
+        }
+    }
+    out
+}
+
+/// Translates an op stream into a single portable C file, with a `tape`
+/// array of `cells` cells, a `while` loop per loop and a static function per
+/// proc. `input` is the data to read instead of stdin, if any.
+///
+/// Honors the cell width, `start_cell`, `wrap_pointer`, `exit_cell`,
+/// `max_steps`, `print_cells` and the output encoding. Options that only
+/// make sense for LLVM output, like `shared`, `grow`, the file descriptors,
+/// debug info and tracing, are ignored.
+pub fn translate_to_c(ops: &[Op], input: Option<&[u8]>, options: &CompileOptions) -> String {
+    let mut t = Translator {
+        options,
+        procs: Vec::new(),
+        open: vec![Function {
+            symbol: None,
+            body: String::new(),
+            depth: 1,
+        }],
+        uses_input: false,
+        uses_utf8: false,
+        uses_dump: false,
+    };
+    match options.start_cell {
+        0 => t.line("cell *p = tape;"),
+        start => t.line(&format!("cell *p = tape + {};", start)),
+    }
+    for op in ops {
+        t.op(op);
+    }
+    // An op stream built by hand could end inside a proc body.
+    while t.open.len() > 1 {
+        let f = t.open.pop().unwrap();
+        t.procs.push(f);
+    }
+    let main = t.open.pop().unwrap();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "#include <stdint.h>");
+    let _ = writeln!(out, "#include <stdio.h>");
+    let _ = writeln!(out, "#include <stdlib.h>");
+    let _ = writeln!(out, "#include <string.h>");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#define BF_TAPE_SIZE {}", options.cells);
+    let _ = writeln!(out, "#define BF_CELL_DIGITS {}", options.cell_bits / 4);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "typedef uint{}_t cell;", options.cell_bits);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "static cell tape[BF_TAPE_SIZE];");
+    if options.max_steps > 0 {
+        let _ = writeln!(out, "static unsigned long long bf_steps;");
+    }
+
+    // Input reads zero-extend the byte, so EOF (-1) reads as 0xFF, unless
+    // cells are signed.
+    let byte = if options.signed_cells {
+        "signed char"
+    } else {
+        "unsigned char"
+    };
+    match input {
+        _ if !t.uses_input => {}
+        Some(data) => {
+            let _ = writeln!(out);
+            let bytes: Vec<String> = data.iter().map(|b| b.to_string()).collect();
+            let _ = writeln!(
+                out,
+                "static const unsigned char bf_input[] = {{{}}};",
+                if bytes.is_empty() {
+                    "0".to_string()
+                } else {
+                    bytes.join(", ")
+                }
+            );
+            let _ = writeln!(out, "static size_t bf_input_pos;");
+            let _ = writeln!(out);
+            let _ = writeln!(out, "static {} bf_getchar(void)", byte);
+            let _ = writeln!(out, "{{");
+            let _ = writeln!(
+                out,
+                "    return ({})(bf_input_pos < {} ? bf_input[bf_input_pos++] : 0xFF);",
+                byte,
+                data.len()
+            );
+            let _ = writeln!(out, "}}");
+        }
+        None => {
+            let _ = writeln!(out);
+            let _ = writeln!(out, "static {} bf_getchar(void)", byte);
+            let _ = writeln!(out, "{{");
+            if options.flush_on_input {
+                let _ = writeln!(out, "    fflush(stdout);");
+            }
+            let _ = writeln!(out, "    return ({})getchar();", byte);
+            let _ = writeln!(out, "}}");
+        }
+    }
+    for (used, helper) in [
+        (t.uses_utf8, UTF8_HELPER),
+        (t.uses_dump || options.print_cells > 0, DUMP_CELLS_HELPER),
+        (t.uses_dump, DUMP_HELPER),
+        (options.max_steps > 0, STEP_LIMIT_HELPER),
+    ] {
+        if used {
+            let _ = writeln!(out);
+            out.push_str(helper);
+        }
+    }
+
+    // Declared up front, since procs can be called before their definition.
+    if !t.procs.is_empty() {
+        let _ = writeln!(out);
+    }
+    for f in &t.procs {
+        let _ = writeln!(out, "static void {}(cell *p);", f.symbol.as_ref().unwrap());
+    }
+    for f in &t.procs {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "static void {}(cell *p)", f.symbol.as_ref().unwrap());
+        let _ = writeln!(out, "{{");
+        out.push_str(&f.body);
+        let _ = writeln!(out, "}}");
+    }
+
+    let _ = writeln!(out);
+    let entry = options.entry_symbol.as_deref().unwrap_or("main");
+    let _ = writeln!(out, "int {}(void)", entry);
+    let _ = writeln!(out, "{{");
+    out.push_str(&main.body);
+    if options.print_cells > 0 {
+        let _ = writeln!(
+            out,
+            "    bf_dump_cells(p, 0, {});",
+            options.print_cells.min(options.cells)
+        );
+    }
+    if options.exit_cell {
+        let _ = writeln!(out, "    return (int)*p;");
+    } else {
+        let _ = writeln!(out, "    return 0;");
+    }
+    let _ = writeln!(out, "}}");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::optimize;
+
+    fn c(source: &str, options: &CompileOptions) -> String {
+        let ops = optimize(crate::lexer::parse(source).unwrap(), options);
+        translate_to_c(&ops, None, options)
+    }
+
+    #[test]
+    fn clear_loop_is_a_store() {
+        let code = c(",[-]", &CompileOptions::default());
+        assert!(code.contains("    *p = 0;\n"), "{}", code);
+        assert!(!code.contains("while"), "{}", code);
+    }
+
+    #[test]
+    fn loops_and_io() {
+        let code = c(",[.,]", &CompileOptions::default());
+        let body = "    *p = bf_getchar();
+    while (*p) {
+        putchar(*p);
+        *p = bf_getchar();
+    }
+";
+        assert!(code.contains(body), "{}", code);
+        assert!(code.contains("typedef uint8_t cell;"));
+    }
+
+    #[test]
+    fn procs_are_functions() {
+        let code = c(",@up{+}@up;", &CompileOptions::default());
+        assert!(code.contains("static void bf_proc_up(cell *p)\n{\n    *p += 1;\n}\n"));
+        assert!(code.contains("    bf_proc_up(p);\n"));
+    }
+
+    #[test]
+    fn wide_cells() {
+        let options = CompileOptions {
+            cell_bits: 16,
+            exit_cell: true,
+            ..Default::default()
+        };
+        let code = c(",", &options);
+        assert!(code.contains("typedef uint16_t cell;"));
+        assert!(code.contains("    return (int)*p;\n"));
+    }
+
+    #[test]
+    fn signed_utf8_output() {
+        let options = CompileOptions {
+            cell_bits: 16,
+            signed_cells: true,
+            output_encoding: OutputEncoding::Utf8,
+            ..Default::default()
+        };
+        let code = c(",.", &options);
+        assert!(code.contains("    bf_put_utf8((int16_t)*p);\n"), "{}", code);
+        // 200 printed at compile time is negative too.
+        let options = CompileOptions {
+            cell_bits: 8,
+            ..options
+        };
+        let code = c(&format!("{}.", "+".repeat(200)), &options);
+        assert!(
+            code.contains("fwrite(\"\\357\\277\\275\", 1, 3, stdout);"),
+            "{}",
+            code
+        );
+    }
+}
@@ -0,0 +1,2869 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::basic_block::BasicBlock;
+use inkwell::builder::Builder;
+use inkwell::context::Context;
+use inkwell::debug_info::{
+    AsDIScope, DICompileUnit, DIFile, DIFlags, DIFlagsConstants, DIScope, DWARFEmissionKind,
+    DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::{FlagBehavior, Linkage, Module};
+use inkwell::targets::{
+    FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+};
+use inkwell::types::{BasicType, IntType, PointerType};
+use inkwell::values::{
+    BasicMetadataValueEnum, FunctionValue, InstructionValue, IntValue, PhiValue, PointerValue,
+};
+use inkwell::AddressSpace;
+
+use crate::lexer::Span;
+use crate::ops::{print_utf8, Op};
+use crate::optimizer;
+use crate::{run_linker, CompileError, CompileOptions, LinkArg, OutputEncoding, TapeStorage};
+
+/// `tape` moved to the cell the pointer starts on.
+fn start_ptr<'a>(
+    ctx: &'a Context,
+    builder: &Builder<'a>,
+    tape: PointerValue<'a>,
+    start_cell: u64,
+) -> PointerValue<'a> {
+    if start_cell == 0 {
+        return tape;
+    }
+    let index = ctx.i64_type().const_int(start_cell, false);
+    unsafe { builder.build_gep(tape, &[index], "start").unwrap() }
+}
+
+/// Symbol for a proc. Named procs made of ASCII letters, digits and `_` keep
+/// their name; anything else, like the single character procs, is spelled
+/// by code point so every symbol can be declared from C.
+pub fn proc_symbol(name: &str) -> String {
+    if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return format!("bf_proc_{}", name);
+    }
+    let code_points: Vec<String> = name.chars().map(|c| format!("{:x}", c as u32)).collect();
+    format!("bf_cproc_{}", code_points.join("_"))
+}
+
+/// State of a function whose body is being generated.
+struct Frame<'a> {
+    /// The data pointer, as of the last time it was materialized.
+    ptr: PointerValue<'a>,
+    /// Pointer moves since then. Cell accesses apply them as a constant GEP
+    /// offset, and `ptr` only gets updated where the pointer has to flow
+    /// into other blocks.
+    offset: isize,
+    /// Open loops: the block the back edge goes to, end block, the pointer
+    /// phi and whether the condition is tested at the bottom.
+    loops: Vec<(BasicBlock<'a>, BasicBlock<'a>, PhiValue<'a>, bool)>,
+    /// For procs, the name and the block of the enclosing function to
+    /// continue in once the body ends. `None` for main.
+    proc: Option<(String, BasicBlock<'a>)>,
+    /// Debug info scope of the function and the file it is in, for `-g`
+    /// builds.
+    scope: Option<(DIScope<'a>, DIFile<'a>)>,
+    /// The `OUT_BUF` bytes runs of `.` are written from, allocated on
+    /// first use.
+    out_buf: Option<PointerValue<'a>>,
+}
+
+/// Size of the buffer a run of `.` is written from. Longer runs are
+/// written a buffer at a time.
+const OUT_BUF: u64 = 256;
+
+/// DWARF state for `-g` builds.
+struct DebugInfo<'a> {
+    builder: DebugInfoBuilder<'a>,
+    unit: DICompileUnit<'a>,
+    /// The file for each `Span::file`.
+    files: Vec<DIFile<'a>>,
+}
+
+impl<'a> DebugInfo<'a> {
+    /// The file spans with `index` point into, or the compile unit's when
+    /// there are no spans.
+    fn file(&self, index: usize) -> DIFile<'a> {
+        self.files
+            .get(index)
+            .copied()
+            .unwrap_or_else(|| self.unit.get_file())
+    }
+}
+
+/// The file name and directory of `path` for DWARF, which needs both.
+fn split_path(path: &str) -> (String, String) {
+    let path = Path::new(path);
+    let file = path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dir = match path.parent().map(|d| d.to_string_lossy().into_owned()) {
+        Some(dir) if !dir.is_empty() => dir,
+        _ => ".".to_string(),
+    };
+    (file, dir)
+}
+
+/// Where `,` reads from.
+#[derive(Clone, Copy)]
+enum InputSource<'a> {
+    /// `getchar`.
+    Stdin,
+    /// `read` on a file descriptor.
+    Fd(i32),
+    /// Bytes baked into the module, from `--input-file` or after a `!`,
+    /// with a global holding how many have been read.
+    Embedded {
+        data: PointerValue<'a>,
+        pos: PointerValue<'a>,
+        len: u64,
+    },
+}
+
+pub struct CodeGen<'a> {
+    ctx: &'a Context,
+    builder: Builder<'a>,
+    /// Functions being generated, main first and the innermost proc last.
+    frames: Vec<Frame<'a>>,
+    cell_type: IntType<'a>,
+    /// Zero as a cell, which every loop and scan tests against, built once
+    /// rather than for each of them.
+    cell_zero: IntValue<'a>,
+    /// Zero and one as `i64`, for indices and counters.
+    i64_zero: IntValue<'a>,
+    i64_one: IntValue<'a>,
+    /// Sign-extend input bytes and output cells rather than zero-extend.
+    signed_cells: bool,
+    tape: PointerValue<'a>,
+    /// Global holding the tape base, for procs which only get handed the
+    /// current pointer.
+    tape_global: PointerValue<'a>,
+    cells: u64,
+    start_cell: u64,
+    wrap_pointer: bool,
+    exit_cell: bool,
+    shared: bool,
+    static_link: bool,
+    link_args: Vec<LinkArg>,
+    output_fd: Option<i32>,
+    output_encoding: OutputEncoding,
+    flush_on_input: bool,
+    triple: String,
+    input: InputSource<'a>,
+    module: Module<'a>,
+    ast: Vec<Op>,
+    /// For each op, whether it is a loop known to be entered, from
+    /// `optimizer::entered_loops`.
+    entered_loops: Vec<bool>,
+    /// Procs by name, all declared before any code is generated so calls
+    /// can come before definitions.
+    procs: HashMap<String, FunctionValue<'a>>,
+    debug: Option<DebugInfo<'a>>,
+    /// Where each op came from, for debug info and tracing.
+    spans: Vec<Span>,
+    /// Where the op being generated came from.
+    span: Span,
+    /// Names of the files spans point into, to say which one in trace
+    /// output. Empty when all ops come from one file.
+    trace_files: Vec<String>,
+    trace: bool,
+    loop_hints: bool,
+    max_steps: u64,
+    print_cells: u64,
+    /// Reallocate the tape when the pointer moves past its end. The base
+    /// and capacity then live in globals, since a proc can move the tape.
+    grow: bool,
+    /// Make Linux system calls instead of using the C runtime, see
+    /// `define_runtime`.
+    syscalls: bool,
+}
+
+impl<'a> CodeGen<'a> {
+    /// Address of the current cell in the function currently being
+    /// generated.
+    fn cur_ptr(&self) -> PointerValue<'a> {
+        let frame = self.frames.last().unwrap();
+        match frame.offset {
+            0 => frame.ptr,
+            offset => self.offset_ptr(frame.ptr, offset.unsigned_abs(), offset < 0),
+        }
+    }
+
+    fn set_ptr(&mut self, ptr: PointerValue<'a>) {
+        let frame = self.frames.last_mut().unwrap();
+        frame.ptr = ptr;
+        frame.offset = 0;
+    }
+
+    /// Applies the pending pointer moves to the data pointer.
+    fn flush_ptr(&mut self) {
+        let ptr = self.cur_ptr();
+        self.set_ptr(ptr);
+    }
+
+    fn in_main(&self) -> bool {
+        self.frames.len() == 1
+    }
+
+    fn cell_ptr_type(&self) -> PointerType<'a> {
+        self.cell_type.ptr_type(AddressSpace::default())
+    }
+
+    /// Whether the cell value `val` isn't zero, as loops and scans test.
+    fn is_nonzero(&self, val: IntValue<'a>) -> IntValue<'a> {
+        self.builder
+            .build_int_compare(inkwell::IntPredicate::NE, val, self.cell_zero, "ne_zero")
+            .unwrap()
+    }
+
+    /// Converts a value to the cell type, zero-extending narrower values so
+    /// input bytes keep their unsigned meaning, or with `signed_cells`
+    /// sign-extending them.
+    fn to_cell(&self, val: IntValue<'a>) -> IntValue<'a> {
+        let from = val.get_type().get_bit_width();
+        let to = self.cell_type.get_bit_width();
+        if from < to && self.signed_cells {
+            self.builder
+                .build_int_s_extend(val, self.cell_type, "to_cell")
+                .unwrap()
+        } else if from < to {
+            self.builder
+                .build_int_z_extend(val, self.cell_type, "to_cell")
+                .unwrap()
+        } else if from > to {
+            self.builder
+                .build_int_truncate(val, self.cell_type, "to_cell")
+                .unwrap()
+        } else {
+            val
+        }
+    }
+
+    fn tape_base(&self) -> PointerValue<'a> {
+        if self.in_main() && !self.grow {
+            return self.tape;
+        }
+        self.builder
+            .build_load(self.tape_global, "tape_base")
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    /// Moves `v` by `count` cells. With `wrap_pointer` the tape is circular,
+    /// so the move is done on the cell index modulo the tape size.
+    fn offset_ptr(&self, v: PointerValue<'a>, count: usize, dec: bool) -> PointerValue<'a> {
+        let i64_type = self.ctx.i64_type();
+        if !self.wrap_pointer {
+            let mut int_val = i64_type.const_int(count as u64, false);
+            if dec {
+                int_val = int_val.const_neg();
+            }
+            let moved = unsafe {
+                let inc = self.builder.build_gep(v, &[int_val], "gep");
+                inc.unwrap()
+            };
+            if self.grow && !dec {
+                return self.ensure_capacity(moved);
+            }
+            return moved;
+        }
+
+        let base = self.tape_base();
+        let base_int = self
+            .builder
+            .build_ptr_to_int(base, i64_type, "base_int")
+            .unwrap();
+        let cur_int = self
+            .builder
+            .build_ptr_to_int(v, i64_type, "cur_int")
+            .unwrap();
+        let bytes = self
+            .builder
+            .build_int_sub(cur_int, base_int, "offset")
+            .unwrap();
+        let index = self
+            .builder
+            .build_int_unsigned_div(
+                bytes,
+                i64_type.const_int(self.cell_type.get_bit_width() as u64 / 8, false),
+                "index",
+            )
+            .unwrap();
+        let step = count as u64 % self.cells;
+        let step = if dec { self.cells - step } else { step };
+        let moved = self
+            .builder
+            .build_int_add(index, i64_type.const_int(step, false), "moved")
+            .unwrap();
+        let wrapped = self
+            .builder
+            .build_int_unsigned_rem(moved, i64_type.const_int(self.cells, false), "wrapped")
+            .unwrap();
+        unsafe { self.builder.build_gep(base, &[wrapped], "gep").unwrap() }
+    }
+
+    fn ptr_manipulate(&mut self, count: usize, dec: bool) {
+        // A wrapping move depends on where the pointer is, so it can't be
+        // folded into a constant offset, and a growing tape has to be
+        // checked on every move.
+        if self.wrap_pointer || self.grow {
+            let ptr = self.offset_ptr(self.cur_ptr(), count, dec);
+            self.set_ptr(ptr);
+            return;
+        }
+        let frame = self.frames.last_mut().unwrap();
+        if dec {
+            frame.offset -= count as isize;
+        } else {
+            frame.offset += count as isize;
+        }
+    }
+
+    fn val_manipulate(&mut self, count: usize, dec: bool) {
+        let v = self.cur_ptr();
+        let val = self.builder.build_load(v, "load_val").unwrap();
+        let int_val = self.cell_type.const_int(count as u64, false);
+        let new_val = if !dec {
+            let val = self
+                .builder
+                .build_int_add(val.into_int_value(), int_val, "add")
+                .unwrap();
+            val
+        } else {
+            let val = self
+                .builder
+                .build_int_sub(val.into_int_value(), int_val, "sub")
+                .unwrap();
+            val
+        };
+        let _ = self.builder.build_store(v, new_val).unwrap();
+    }
+
+    fn set(&mut self, value: u8) {
+        let v = self.cur_ptr();
+        let int_val = self.cell_type.const_int(value as u64, false);
+        let _ = self.builder.build_store(v, int_val).unwrap();
+    }
+
+    /// Zeroes `count` cells from the current one with a single memset.
+    fn clear(&mut self, count: usize) {
+        let bytes = count as u64 * (self.cell_type.get_bit_width() as u64 / 8);
+        let ptr = self
+            .builder
+            .build_pointer_cast(
+                self.cur_ptr(),
+                self.ctx.i8_type().ptr_type(AddressSpace::default()),
+                "clear_ptr",
+            )
+            .unwrap();
+        self.builder
+            .build_memset(
+                ptr,
+                1,
+                self.ctx.i8_type().const_zero(),
+                self.ctx.i64_type().const_int(bytes, false),
+            )
+            .unwrap();
+    }
+
+    fn out(&mut self) {
+        if self.output_fd.is_some() || self.output_encoding == OutputEncoding::Utf8 {
+            return self.out_buffered(1);
+        }
+        let v = self.cur_ptr();
+        let val = self.builder.build_load(v, "load_val").unwrap();
+        // putchar takes an int and writes its low byte, so narrower cells
+        // are zero-extended to match, and 32 bit ones go as they are.
+        let c = self
+            .builder
+            .build_int_z_extend_or_bit_cast(val.into_int_value(), self.ctx.i32_type(), "c")
+            .unwrap();
+        let putchar = self.module.get_function("putchar").unwrap();
+        let _call = self
+            .builder
+            .build_call(putchar, &[c.into()], "out")
+            .unwrap();
+    }
+
+    /// Allocates in the entry block of the current function, so that allocas
+    /// emitted from inside loop bodies don't grow the stack every iteration.
+    fn entry_alloca<T: BasicType<'a>>(&self, ty: T, name: &str) -> PointerValue<'a> {
+        let func = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+        let entry = func.get_first_basic_block().unwrap();
+        let builder = self.ctx.create_builder();
+        match entry.get_first_instruction() {
+            Some(inst) => builder.position_before(&inst),
+            None => builder.position_at_end(entry),
+        }
+        builder.build_alloca(ty, name).unwrap()
+    }
+
+    /// The C runtime's `FILE *` for stdout (1) or stderr (2).
+    fn std_stream(&self, fd: u64) -> PointerValue<'a> {
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        let triple = &self.triple;
+        if triple.contains("windows") {
+            let iob = match self.module.get_function("__acrt_iob_func") {
+                Some(f) => f,
+                None => self.module.add_function(
+                    "__acrt_iob_func",
+                    i8_ptr.fn_type(&[self.ctx.i32_type().into()], false),
+                    None,
+                ),
+            };
+            return self
+                .builder
+                .build_call(
+                    iob,
+                    &[self.ctx.i32_type().const_int(fd, false).into()],
+                    "stream",
+                )
+                .unwrap()
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_pointer_value();
+        }
+        let name = match (triple.contains("apple"), fd) {
+            (true, 1) => "__stdoutp",
+            (true, _) => "__stderrp",
+            (false, 1) => "stdout",
+            (false, _) => "stderr",
+        };
+        let global = match self.module.get_global(name) {
+            Some(g) => g,
+            None => self.module.add_global(i8_ptr, None, name),
+        };
+        self.builder
+            .build_load(global.as_pointer_value(), "stream")
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    fn out_buffered(&mut self, count: usize) {
+        let v = self.cur_ptr();
+        let val = self.builder.build_load(v, "load_val").unwrap();
+        let i64_type = self.ctx.i64_type();
+        let len = i64_type.const_int(count as u64, false);
+        if self.output_encoding == OutputEncoding::Utf8 {
+            let put = self.utf8_function();
+            self.debug_location();
+            let (val, i32_type) = (val.into_int_value(), self.ctx.i32_type());
+            let cp = if self.signed_cells {
+                self.builder
+                    .build_int_s_extend_or_bit_cast(val, i32_type, "cp")
+                    .unwrap()
+            } else {
+                self.builder
+                    .build_int_z_extend_or_bit_cast(val, i32_type, "cp")
+                    .unwrap()
+            };
+            self.builder
+                .build_call(put, &[cp.into(), len.into()], "")
+                .unwrap();
+            return;
+        }
+        let i8_type = self.ctx.i8_type();
+        let buf = match self.frames.last().unwrap().out_buf {
+            Some(buf) => buf,
+            None => {
+                let buf = self.entry_alloca(i8_type.array_type(OUT_BUF as u32), "out_buf");
+                self.frames.last_mut().unwrap().out_buf = Some(buf);
+                buf
+            }
+        };
+        let buf = self
+            .builder
+            .build_pointer_cast(
+                buf,
+                i8_type.ptr_type(AddressSpace::default()),
+                "out_buf_ptr",
+            )
+            .unwrap();
+        let byte = self
+            .builder
+            .build_int_cast(val.into_int_value(), i8_type, "byte")
+            .unwrap();
+        let count = count as u64;
+        let filled = i64_type.const_int(count.min(OUT_BUF), false);
+        self.builder.build_memset(buf, 1, byte, filled).unwrap();
+        let (full, rest) = (count / OUT_BUF, count % OUT_BUF);
+        match full {
+            0 => {}
+            1 => self.write_bytes(buf, filled),
+            _ => self.write_full_buffers(buf, full),
+        }
+        if rest > 0 {
+            self.write_bytes(buf, i64_type.const_int(rest, false));
+        }
+    }
+
+    /// Writes the whole of `buf`, `OUT_BUF` bytes, `times` times over in a
+    /// loop.
+    fn write_full_buffers(&mut self, buf: PointerValue<'a>, times: u64) {
+        let i64_type = self.ctx.i64_type();
+        let func = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+        let before = self.builder.get_insert_block().unwrap();
+        let body_block = self.ctx.append_basic_block(func, "write_block");
+        let done_block = self.ctx.append_basic_block(func, "written_block");
+        self.builder.build_unconditional_branch(body_block).unwrap();
+        self.builder.position_at_end(body_block);
+        let i = self.builder.build_phi(i64_type, "written").unwrap();
+        self.write_bytes(buf, i64_type.const_int(OUT_BUF, false));
+        let next = self
+            .builder
+            .build_int_add(
+                i.as_basic_value().into_int_value(),
+                self.i64_one,
+                "next_written",
+            )
+            .unwrap();
+        i.add_incoming(&[(&self.i64_zero, before), (&next, body_block)]);
+        let more = self
+            .builder
+            .build_int_compare(
+                inkwell::IntPredicate::ULT,
+                next,
+                i64_type.const_int(times, false),
+                "more",
+            )
+            .unwrap();
+        self.builder
+            .build_conditional_branch(more, body_block, done_block)
+            .unwrap();
+        self.builder.position_at_end(done_block);
+    }
+
+    /// The `bf_put_utf8` helper, which writes a code point as UTF-8 the
+    /// given number of times. Emitted into the module on first use.
+    fn utf8_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("bf_put_utf8") {
+            return f;
+        }
+        let i8_type = self.ctx.i8_type();
+        let i32_type = self.ctx.i32_type();
+        let i64_type = self.ctx.i64_type();
+        let f = self.module.add_function(
+            "bf_put_utf8",
+            self.ctx
+                .void_type()
+                .fn_type(&[i32_type.into(), i64_type.into()], false),
+            Some(Linkage::Internal),
+        );
+
+        let resume = self.builder.get_insert_block().unwrap();
+        self.builder.unset_current_debug_location();
+        let entry = self.ctx.append_basic_block(f, "entry");
+        let length_blocks: Vec<_> = (1..=4)
+            .map(|n| self.ctx.append_basic_block(f, &format!("bytes{}", n)))
+            .collect();
+        let write_block = self.ctx.append_basic_block(f, "write");
+        let cond_block = self.ctx.append_basic_block(f, "cond");
+        let body_block = self.ctx.append_basic_block(f, "body");
+        let done_block = self.ctx.append_basic_block(f, "done");
+        self.builder.position_at_end(entry);
+        let buf = self
+            .builder
+            .build_alloca(i8_type.array_type(4), "utf8_buf")
+            .unwrap();
+        let buf = self
+            .builder
+            .build_pointer_cast(buf, i8_type.ptr_type(AddressSpace::default()), "utf8_ptr")
+            .unwrap();
+        let cp = f.get_nth_param(0).unwrap().into_int_value();
+        let count = f.get_nth_param(1).unwrap().into_int_value();
+
+        // Surrogates and anything past U+10FFFF can't be encoded.
+        let too_big = self
+            .builder
+            .build_int_compare(
+                inkwell::IntPredicate::UGT,
+                cp,
+                i32_type.const_int(0x10FFFF, false),
+                "too_big",
+            )
+            .unwrap();
+        let from_surrogates = self
+            .builder
+            .build_int_sub(cp, i32_type.const_int(0xD800, false), "from_surrogates")
+            .unwrap();
+        let surrogate = self
+            .builder
+            .build_int_compare(
+                inkwell::IntPredicate::ULT,
+                from_surrogates,
+                i32_type.const_int(0x800, false),
+                "surrogate",
+            )
+            .unwrap();
+        let invalid = self
+            .builder
+            .build_or(too_big, surrogate, "invalid")
+            .unwrap();
+        let cp = self
+            .builder
+            .build_select(invalid, i32_type.const_int(0xFFFD, false), cp, "cp")
+            .unwrap()
+            .into_int_value();
+
+        // Branch on the encoded length, one threshold at a time.
+        for (n, limit) in [0x80, 0x800, 0x10000].into_iter().enumerate() {
+            let fits = self
+                .builder
+                .build_int_compare(
+                    inkwell::IntPredicate::ULT,
+                    cp,
+                    i32_type.const_int(limit, false),
+                    "fits",
+                )
+                .unwrap();
+            let next = self.ctx.append_basic_block(f, "longer");
+            self.builder
+                .build_conditional_branch(fits, length_blocks[n], next)
+                .unwrap();
+            self.builder.position_at_end(next);
+        }
+        self.builder
+            .build_unconditional_branch(length_blocks[3])
+            .unwrap();
+
+        // The first byte carries the length in its top bits and the rest
+        // are continuation bytes with six bits of the code point each.
+        let leads = [0x00, 0xC0, 0xE0, 0xF0];
+        for (n, block) in length_blocks.iter().enumerate() {
+            self.builder.position_at_end(*block);
+            for k in 0..=n {
+                let shifted = self
+                    .builder
+                    .build_right_shift(
+                        cp,
+                        i32_type.const_int(6 * (n - k) as u64, false),
+                        false,
+                        "shifted",
+                    )
+                    .unwrap();
+                let byte = if k == 0 {
+                    self.builder
+                        .build_or(shifted, i32_type.const_int(leads[n], false), "lead")
+                        .unwrap()
+                } else {
+                    let bits = self
+                        .builder
+                        .build_and(shifted, i32_type.const_int(0x3F, false), "bits")
+                        .unwrap();
+                    self.builder
+                        .build_or(bits, i32_type.const_int(0x80, false), "continuation")
+                        .unwrap()
+                };
+                let byte = self
+                    .builder
+                    .build_int_truncate(byte, i8_type, "byte")
+                    .unwrap();
+                let ptr = unsafe {
+                    self.builder
+                        .build_gep(buf, &[i64_type.const_int(k as u64, false)], "utf8_byte")
+                        .unwrap()
+                };
+                self.builder.build_store(ptr, byte).unwrap();
+            }
+            self.builder
+                .build_unconditional_branch(write_block)
+                .unwrap();
+        }
+
+        self.builder.position_at_end(write_block);
+        let len = self.builder.build_phi(i64_type, "len").unwrap();
+        for (n, block) in length_blocks.iter().enumerate() {
+            len.add_incoming(&[(&i64_type.const_int(n as u64 + 1, false), *block)]);
+        }
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+
+        self.builder.position_at_end(cond_block);
+        let i = self.builder.build_phi(i64_type, "i").unwrap();
+        i.add_incoming(&[(&self.i64_zero, write_block)]);
+        let i_val = i.as_basic_value().into_int_value();
+        let more = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::ULT, i_val, count, "more")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(more, body_block, done_block)
+            .unwrap();
+
+        self.builder.position_at_end(body_block);
+        self.write_bytes(buf, len.as_basic_value().into_int_value());
+        let next = self
+            .builder
+            .build_int_add(i_val, self.i64_one, "next")
+            .unwrap();
+        i.add_incoming(&[(&next, body_block)]);
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+
+        self.builder.position_at_end(done_block);
+        self.builder.build_return(None).unwrap();
+        self.builder.position_at_end(resume);
+        f
+    }
+
+    /// Writes bytes known at compile time with a single call.
+    fn print(&mut self, bytes: &[u8]) {
+        let encoded: Vec<u8>;
+        let bytes = match self.output_encoding {
+            OutputEncoding::Byte => bytes,
+            OutputEncoding::Utf8 => {
+                encoded = print_utf8(bytes, self.signed_cells);
+                &encoded
+            }
+        };
+        let data = self.ctx.const_string(bytes, false);
+        let global = self.module.add_global(data.get_type(), None, "print_data");
+        global.set_initializer(&data);
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+        let buf = self
+            .builder
+            .build_pointer_cast(
+                global.as_pointer_value(),
+                self.ctx.i8_type().ptr_type(AddressSpace::default()),
+                "print_ptr",
+            )
+            .unwrap();
+        let len = self.ctx.i64_type().const_int(bytes.len() as u64, false);
+        self.write_bytes(buf, len);
+    }
+
+    /// `write` or `read` from the C runtime, along with the type of its count
+    /// argument. The MSVC runtime only has the underscored versions, which
+    /// take a 32 bit count.
+    fn fd_function(&self, name: &str) -> (FunctionValue<'a>, IntType<'a>) {
+        let windows = self.triple.contains("windows");
+        let name = if windows {
+            format!("_{}", name)
+        } else {
+            name.to_string()
+        };
+        let len_type = if windows {
+            self.ctx.i32_type()
+        } else {
+            self.size_type()
+        };
+        let f = match self.module.get_function(&name) {
+            Some(f) => f,
+            None => self.module.add_function(
+                &name,
+                len_type.fn_type(
+                    &[
+                        self.ctx.i32_type().into(),
+                        self.ctx.i8_type().ptr_type(AddressSpace::default()).into(),
+                        len_type.into(),
+                    ],
+                    false,
+                ),
+                None,
+            ),
+        };
+        (f, len_type)
+    }
+
+    /// Writes `len`, an i64, bytes from `buf` to stdout, or to `output_fd`
+    /// if set. Without a C runtime there is no `FILE *`, so stdout is
+    /// written with the host's `write` too.
+    fn write_bytes(&self, buf: PointerValue<'a>, len: IntValue<'a>) {
+        let fd = match self.output_fd {
+            None if self.freestanding() => Some(1),
+            fd => fd,
+        };
+        if let Some(fd) = fd {
+            let (write, len_type) = self.fd_function("write");
+            let len = self
+                .builder
+                .build_int_truncate_or_bit_cast(len, len_type, "len")
+                .unwrap();
+            let _call = self
+                .builder
+                .build_call(
+                    write,
+                    &[
+                        self.ctx.i32_type().const_int(fd as u64, true).into(),
+                        buf.into(),
+                        len.into(),
+                    ],
+                    "out",
+                )
+                .unwrap();
+            return;
+        }
+        let size_type = self.size_type();
+        let len = self
+            .builder
+            .build_int_truncate_or_bit_cast(len, size_type, "len")
+            .unwrap();
+        let stream = self.std_stream(1);
+        let fwrite = self.module.get_function("fwrite").unwrap();
+        let _call = self
+            .builder
+            .build_call(
+                fwrite,
+                &[
+                    buf.into(),
+                    size_type.const_int(1, false).into(),
+                    len.into(),
+                    stream.into(),
+                ],
+                "out",
+            )
+            .unwrap();
+    }
+
+    /// Calls `fflush(stdout)`, for `flush_on_input`. Without a C runtime
+    /// there is no buffer to flush.
+    fn flush_stdout(&self) {
+        if self.freestanding() {
+            return;
+        }
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        let fflush = match self.module.get_function("fflush") {
+            Some(f) => f,
+            None => self.module.add_function(
+                "fflush",
+                self.ctx.i32_type().fn_type(&[i8_ptr.into()], false),
+                None,
+            ),
+        };
+        let stream = self.std_stream(1);
+        self.builder
+            .build_call(fflush, &[stream.into()], "flush")
+            .unwrap();
+    }
+
+    fn input(&mut self) {
+        let v = self.cur_ptr();
+        if let InputSource::Embedded { data, pos, len } = self.input {
+            let val = self.embedded_input(data, pos, len);
+            let val = self.to_cell(val);
+            let _ = self.builder.build_store(v, val);
+            return;
+        }
+        // Embedded input never blocks, so there is only a prompt to show
+        // when reading for real.
+        if self.flush_on_input {
+            self.flush_stdout();
+        }
+        if let InputSource::Fd(fd) = self.input {
+            // The buffer is reset to -1 first, so EOF or a failed read leaves
+            // the same value getchar would return.
+            let byte = self.ctx.i8_type();
+            let buf = self.entry_alloca(byte, "in_buf");
+            self.builder
+                .build_store(buf, byte.const_all_ones())
+                .unwrap();
+            let (read, len_type) = self.fd_function("read");
+            let _call = self
+                .builder
+                .build_call(
+                    read,
+                    &[
+                        self.ctx.i32_type().const_int(fd as u64, true).into(),
+                        buf.into(),
+                        len_type.const_int(1, false).into(),
+                    ],
+                    "in",
+                )
+                .unwrap();
+            let val = self.builder.build_load(buf, "load_in").unwrap();
+            let val = self.to_cell(val.into_int_value());
+            let _ = self.builder.build_store(v, val);
+            return;
+        }
+        let putchar = self.module.get_function("getchar").unwrap();
+        let call = self
+            .builder
+            .build_call(putchar, &[], "in")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        let call = self.to_cell(call.into_int_value());
+        let _ = self.builder.build_store(v, call);
+    }
+
+    /// Reads the next byte of the embedded input, or EOF (-1) once it has
+    /// been consumed.
+    fn embedded_input(
+        &mut self,
+        data: PointerValue<'a>,
+        pos_ptr: PointerValue<'a>,
+        len: u64,
+    ) -> IntValue<'a> {
+        let i64_type = self.ctx.i64_type();
+        let start_block = self.builder.get_insert_block().unwrap();
+        let func = start_block.get_parent().unwrap();
+        let read_block = self.ctx.append_basic_block(func, "input_read");
+        let eof_block = self.ctx.append_basic_block(func, "input_eof");
+        let done_block = self.ctx.append_basic_block(func, "input_done");
+
+        let pos = self
+            .builder
+            .build_load(pos_ptr, "input_pos")
+            .unwrap()
+            .into_int_value();
+        let has_more = self
+            .builder
+            .build_int_compare(
+                inkwell::IntPredicate::ULT,
+                pos,
+                i64_type.const_int(len, false),
+                "has_more",
+            )
+            .unwrap();
+        self.builder
+            .build_conditional_branch(has_more, read_block, eof_block)
+            .unwrap();
+
+        self.builder.position_at_end(read_block);
+        let byte_ptr = unsafe {
+            self.builder
+                .build_in_bounds_gep(data, &[self.i64_zero, pos], "input_byte")
+                .unwrap()
+        };
+        let byte = self.builder.build_load(byte_ptr, "load_input").unwrap();
+        let next = self
+            .builder
+            .build_int_add(pos, self.i64_one, "next_pos")
+            .unwrap();
+        self.builder.build_store(pos_ptr, next).unwrap();
+        self.builder.build_unconditional_branch(done_block).unwrap();
+
+        self.builder.position_at_end(eof_block);
+        let eof = self.ctx.i8_type().const_all_ones();
+        self.builder.build_unconditional_branch(done_block).unwrap();
+
+        self.builder.position_at_end(done_block);
+        let phi = self.builder.build_phi(self.ctx.i8_type(), "in").unwrap();
+        phi.add_incoming(&[(&byte, read_block), (&eof, eof_block)]);
+        phi.as_basic_value().into_int_value()
+    }
+
+    fn loop_start(&mut self) {
+        self.flush_ptr();
+        let start_block = self.builder.get_insert_block().unwrap();
+        let main = start_block.get_parent().unwrap();
+        let cond_block = self.ctx.append_basic_block(main, "cond_block");
+        let body_block = self.ctx.append_basic_block(main, "body_block");
+        let end_block = self.ctx.append_basic_block(main, "end_block");
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+        self.builder.position_at_end(cond_block);
+        let phi = self
+            .builder
+            .build_phi(self.cell_ptr_type(), "loop_ptr")
+            .unwrap();
+        phi.add_incoming(&[(&self.cur_ptr(), start_block)]);
+        self.frames
+            .last_mut()
+            .unwrap()
+            .loops
+            .push((cond_block, end_block, phi, false));
+        let v = phi.as_basic_value().into_pointer_value();
+        self.set_ptr(v);
+        let val = self.builder.build_load(v, "load_val").unwrap();
+        let comp = self.is_nonzero(val.into_int_value());
+        let branch = self
+            .builder
+            .build_conditional_branch(comp, body_block, end_block)
+            .unwrap();
+        self.hint_loop_branch(branch);
+        self.builder.position_at_end(body_block);
+    }
+
+    /// With `loop_hints`, weights the conditional `branch` back into a loop
+    /// body the way `llvm.expect` would for a likely condition, as BF loops
+    /// tend to run many times.
+    fn hint_loop_branch(&self, branch: InstructionValue<'a>) {
+        if !self.loop_hints {
+            return;
+        }
+        let i32_type = self.ctx.i32_type();
+        let weights = self.ctx.metadata_node(&[
+            self.ctx.metadata_string("branch_weights").into(),
+            i32_type.const_int(2000, false).into(),
+            i32_type.const_int(1, false).into(),
+        ]);
+        branch
+            .set_metadata(weights, self.ctx.get_kind_id("prof"))
+            .unwrap();
+    }
+
+    /// Starts a loop known to be entered, which goes straight into the body
+    /// and only tests the cell at the bottom, saving the branch on entry.
+    fn do_loop_start(&mut self) {
+        self.flush_ptr();
+        let start_block = self.builder.get_insert_block().unwrap();
+        let main = start_block.get_parent().unwrap();
+        let body_block = self.ctx.append_basic_block(main, "body_block");
+        let end_block = self.ctx.append_basic_block(main, "end_block");
+        self.builder.build_unconditional_branch(body_block).unwrap();
+        self.builder.position_at_end(body_block);
+        let phi = self
+            .builder
+            .build_phi(self.cell_ptr_type(), "loop_ptr")
+            .unwrap();
+        phi.add_incoming(&[(&self.cur_ptr(), start_block)]);
+        self.frames
+            .last_mut()
+            .unwrap()
+            .loops
+            .push((body_block, end_block, phi, true));
+        self.set_ptr(phi.as_basic_value().into_pointer_value());
+    }
+
+    fn loop_end(&mut self) {
+        self.flush_ptr();
+        let (loop_block, end_block, phi, bottom_tested) =
+            self.frames.last_mut().unwrap().loops.pop().unwrap();
+        self.count_step();
+        let body_end = self.builder.get_insert_block().unwrap();
+        let ptr = self.cur_ptr();
+        phi.add_incoming(&[(&ptr, body_end)]);
+        if bottom_tested {
+            let val = self.builder.build_load(ptr, "load_val").unwrap();
+            let comp = self.is_nonzero(val.into_int_value());
+            let branch = self
+                .builder
+                .build_conditional_branch(comp, loop_block, end_block)
+                .unwrap();
+            self.hint_loop_branch(branch);
+            self.builder.position_at_end(end_block);
+            // Only the bottom test leaves the loop.
+            self.set_ptr(ptr);
+            return;
+        }
+        self.builder.build_unconditional_branch(loop_block).unwrap();
+        self.builder.position_at_end(end_block);
+        // The loop is only left through the condition check, so the pointer
+        // there is the one we continue with.
+        self.set_ptr(phi.as_basic_value().into_pointer_value());
+    }
+
+    fn scan(&mut self, count: usize, dec: bool) {
+        let start = self.cur_ptr();
+
+        // Inside main the tape bounds are known, so a unit stride to the right
+        // over byte cells can be handed to memchr over the rest of the tape.
+        // A step limit counts every move of the plain loop, which memchr
+        // would skip.
+        if count == 1
+            && !dec
+            && !self.wrap_pointer
+            && !self.grow
+            && self.in_main()
+            && self.cell_type.get_bit_width() == 8
+            && self.max_steps == 0
+            && !self.wasm()
+            && !self.syscalls
+        {
+            let i64_type = self.ctx.i64_type();
+            let base = self
+                .builder
+                .build_ptr_to_int(self.tape, i64_type, "tape_base")
+                .unwrap();
+            let cur = self
+                .builder
+                .build_ptr_to_int(start, i64_type, "tape_cur")
+                .unwrap();
+            let offset = self.builder.build_int_sub(cur, base, "offset").unwrap();
+            let remaining = self
+                .builder
+                .build_int_sub(i64_type.const_int(self.cells, false), offset, "remaining")
+                .unwrap();
+            let memchr = self.module.get_function("memchr").unwrap();
+            let found = self
+                .builder
+                .build_call(
+                    memchr,
+                    &[
+                        start.into(),
+                        self.ctx.i32_type().const_zero().into(),
+                        remaining.into(),
+                    ],
+                    "scan",
+                )
+                .unwrap()
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_pointer_value();
+            // Without a zero cell left on the tape memchr returns null. Leave
+            // the pointer just past the end instead, where the plain loop
+            // would have walked off the tape.
+            let end = unsafe {
+                self.builder.build_in_bounds_gep(
+                    self.tape,
+                    &[i64_type.const_int(self.cells, false)],
+                    "tape_end",
+                )
+            }
+            .unwrap();
+            let missing = self.builder.build_is_null(found, "not_found").unwrap();
+            let ptr = self
+                .builder
+                .build_select(missing, end, found, "scan_ptr")
+                .unwrap();
+            self.set_ptr(ptr.into_pointer_value());
+            return;
+        }
+
+        let pre_block = self.builder.get_insert_block().unwrap();
+        let func = pre_block.get_parent().unwrap();
+        let cond_block = self.ctx.append_basic_block(func, "scan_cond");
+        let step_block = self.ctx.append_basic_block(func, "scan_step");
+        let end_block = self.ctx.append_basic_block(func, "scan_end");
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+
+        self.builder.position_at_end(cond_block);
+        let phi = self
+            .builder
+            .build_phi(self.cell_ptr_type(), "scan_ptr")
+            .unwrap();
+        phi.add_incoming(&[(&start, pre_block)]);
+        let cur = phi.as_basic_value().into_pointer_value();
+        let val = self.builder.build_load(cur, "load_val").unwrap();
+        let comp = self.is_nonzero(val.into_int_value());
+        self.builder
+            .build_conditional_branch(comp, step_block, end_block)
+            .unwrap();
+
+        self.builder.position_at_end(step_block);
+        let next = self.offset_ptr(cur, count, dec);
+        self.count_step();
+        let step_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+        phi.add_incoming(&[(&next, step_end)]);
+
+        self.builder.position_at_end(end_block);
+        self.set_ptr(cur);
+    }
+
+    /// Prints `msg` and a newline to stderr and aborts. This ends the current
+    /// block, so the builder has to be moved on afterwards.
+    fn emit_abort(&self, msg: &str) {
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        let fputs = match self.module.get_function("fputs") {
+            Some(f) => f,
+            None => self.module.add_function(
+                "fputs",
+                self.ctx
+                    .i32_type()
+                    .fn_type(&[i8_ptr.into(), i8_ptr.into()], false),
+                None,
+            ),
+        };
+        let abort = self.module.get_function("abort").unwrap();
+        let text = self
+            .builder
+            .build_global_string_ptr(&format!("{}\n", msg), "abort_msg")
+            .unwrap();
+        let stream = self.std_stream(2);
+        self.builder
+            .build_call(fputs, &[text.as_pointer_value().into(), stream.into()], "")
+            .unwrap();
+        self.builder.build_call(abort, &[], "").unwrap();
+        self.builder.build_unreachable().unwrap();
+    }
+
+    /// Makes the Linux system call `nr` with three arguments, for
+    /// `syscalls` builds.
+    fn syscall(&self, nr: u64, args: [IntValue<'a>; 3]) -> IntValue<'a> {
+        let i64_type = self.ctx.i64_type();
+        let fn_type = i64_type.fn_type(
+            &[
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
+            ],
+            false,
+        );
+        let (asm, constraints) = if self.triple.starts_with("aarch64") {
+            ("svc #0", "={x0},{x8},{x0},{x1},{x2},~{memory}")
+        } else {
+            (
+                "syscall",
+                "={rax},{rax},{rdi},{rsi},{rdx},~{rcx},~{r11},~{memory}",
+            )
+        };
+        let asm = self.ctx.create_inline_asm(
+            fn_type,
+            asm.to_string(),
+            constraints.to_string(),
+            true,
+            false,
+            None,
+            false,
+        );
+        let [a, b, c] = args;
+        let args = [
+            i64_type.const_int(nr, false).into(),
+            a.into(),
+            b.into(),
+            c.into(),
+        ];
+        self.builder
+            .build_indirect_call(fn_type, asm, &args, "syscall")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value()
+    }
+
+    /// Gives a `syscalls` build what the C runtime would have: bodies made
+    /// of system calls for `putchar`, `getchar` and, where the program uses
+    /// them, `read` and `write`, a `memset` for LLVM to lower the memset
+    /// intrinsic to, and an `_start` that runs main and exits with its
+    /// result.
+    fn define_runtime(&mut self) {
+        // Numbers for read, write and exit_group.
+        let (read_nr, write_nr, exit_nr) = if self.triple.starts_with("aarch64") {
+            (63, 64, 94)
+        } else {
+            (0, 1, 231)
+        };
+        self.builder.unset_current_debug_location();
+        let i8_type = self.ctx.i8_type();
+        let i32_type = self.ctx.i32_type();
+        let i64_type = self.ctx.i64_type();
+        let ptr_arg = |ptr: PointerValue<'a>| {
+            self.builder
+                .build_ptr_to_int(ptr, i64_type, "ptr_arg")
+                .unwrap()
+        };
+
+        // The byte goes through a stack slot, as a system call only takes a
+        // buffer.
+        let putchar = self.module.get_function("putchar").unwrap();
+        let entry = self.ctx.append_basic_block(putchar, "entry");
+        self.builder.position_at_end(entry);
+        let c = putchar.get_nth_param(0).unwrap().into_int_value();
+        let byte = self.builder.build_int_truncate(c, i8_type, "byte").unwrap();
+        let slot = self.builder.build_alloca(i8_type, "slot").unwrap();
+        self.builder.build_store(slot, byte).unwrap();
+        let fd = i64_type.const_int(1, false);
+        self.syscall(write_nr, [fd, ptr_arg(slot), self.i64_one]);
+        self.builder.build_return(Some(&byte)).unwrap();
+
+        // As with `input_fd`, the slot starts at -1, so EOF or a failed read
+        // leaves what getchar would return.
+        let getchar = self.module.get_function("getchar").unwrap();
+        let entry = self.ctx.append_basic_block(getchar, "entry");
+        self.builder.position_at_end(entry);
+        let slot = self.builder.build_alloca(i8_type, "slot").unwrap();
+        self.builder
+            .build_store(slot, i8_type.const_all_ones())
+            .unwrap();
+        self.syscall(read_nr, [self.i64_zero, ptr_arg(slot), self.i64_one]);
+        let byte = self.builder.build_load(slot, "byte").unwrap();
+        self.builder.build_return(Some(&byte)).unwrap();
+
+        for (name, nr) in [("read", read_nr), ("write", write_nr)] {
+            let Some(f) = self.module.get_function(name) else {
+                continue;
+            };
+            let entry = self.ctx.append_basic_block(f, "entry");
+            self.builder.position_at_end(entry);
+            let fd = f.get_nth_param(0).unwrap().into_int_value();
+            let fd = self.builder.build_int_s_extend(fd, i64_type, "fd").unwrap();
+            let buf = f.get_nth_param(1).unwrap().into_pointer_value();
+            let len = f.get_nth_param(2).unwrap().into_int_value();
+            let result = self.syscall(nr, [fd, ptr_arg(buf), len]);
+            self.builder.build_return(Some(&result)).unwrap();
+        }
+        for name in ["putchar", "getchar", "read", "write"] {
+            if let Some(f) = self.module.get_function(name) {
+                f.set_linkage(Linkage::Internal);
+            }
+        }
+
+        // Big memsets, like clearing a long run of cells, are lowered to a
+        // call, which has to find a definition.
+        let i8_ptr = i8_type.ptr_type(AddressSpace::default());
+        let memset = self.module.add_function(
+            "memset",
+            i8_ptr.fn_type(&[i8_ptr.into(), i32_type.into(), i64_type.into()], false),
+            None,
+        );
+        let entry = self.ctx.append_basic_block(memset, "entry");
+        let cond_block = self.ctx.append_basic_block(memset, "cond");
+        let body_block = self.ctx.append_basic_block(memset, "body");
+        let done_block = self.ctx.append_basic_block(memset, "done");
+        self.builder.position_at_end(entry);
+        let dest = memset.get_nth_param(0).unwrap().into_pointer_value();
+        let val = memset.get_nth_param(1).unwrap().into_int_value();
+        let len = memset.get_nth_param(2).unwrap().into_int_value();
+        let byte = self
+            .builder
+            .build_int_truncate(val, i8_type, "byte")
+            .unwrap();
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+        self.builder.position_at_end(cond_block);
+        let i = self.builder.build_phi(i64_type, "i").unwrap();
+        let index = i.as_basic_value().into_int_value();
+        let done = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::EQ, index, len, "done")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(done, done_block, body_block)
+            .unwrap();
+        self.builder.position_at_end(body_block);
+        let ptr = unsafe { self.builder.build_gep(dest, &[index], "ptr").unwrap() };
+        self.builder.build_store(ptr, byte).unwrap();
+        let next = self
+            .builder
+            .build_int_add(index, self.i64_one, "next")
+            .unwrap();
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+        i.add_incoming(&[(&self.i64_zero, entry), (&next, body_block)]);
+        self.builder.position_at_end(done_block);
+        self.builder.build_return(Some(&dest)).unwrap();
+
+        let start =
+            self.module
+                .add_function("_start", self.ctx.void_type().fn_type(&[], false), None);
+        let noreturn = Attribute::get_named_enum_kind_id("noreturn");
+        start.add_attribute(
+            AttributeLoc::Function,
+            self.ctx.create_enum_attribute(noreturn, 0),
+        );
+        // The kernel enters with the stack 16 byte aligned rather than as a
+        // call would leave it, so x86-64 has to realign it.
+        if !self.triple.starts_with("aarch64") {
+            start.add_attribute(
+                AttributeLoc::Function,
+                self.ctx.create_string_attribute("stackrealign", ""),
+            );
+        }
+        let entry = self.ctx.append_basic_block(start, "entry");
+        self.builder.position_at_end(entry);
+        let main = self.module.get_function("main").unwrap();
+        let code = self
+            .builder
+            .build_call(main, &[], "code")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let code = self
+            .builder
+            .build_int_s_extend(code, i64_type, "code")
+            .unwrap();
+        self.syscall(exit_nr, [code, self.i64_zero, self.i64_zero]);
+        self.builder.build_unreachable().unwrap();
+
+        // Without this LLVM could turn loops back into calls to C library
+        // functions, memset's own loop into a call to memset included.
+        let no_builtins = self.ctx.create_string_attribute("no-builtins", "");
+        for f in self.module.get_functions() {
+            if f.count_basic_blocks() > 0 {
+                f.add_attribute(AttributeLoc::Function, no_builtins);
+            }
+        }
+    }
+
+    /// The `bf_step_limit` helper, which reports that the step limit was hit
+    /// and exits with code 3. Emitted into the module on first use.
+    fn step_limit_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("bf_step_limit") {
+            return f;
+        }
+        let i32_type = self.ctx.i32_type();
+        let fprintf = self.fprintf_function();
+        let exit = match self.module.get_function("exit") {
+            Some(f) => f,
+            None => self.module.add_function(
+                "exit",
+                self.ctx.void_type().fn_type(&[i32_type.into()], false),
+                None,
+            ),
+        };
+        let f = self.module.add_function(
+            "bf_step_limit",
+            self.ctx.void_type().fn_type(&[], false),
+            Some(Linkage::Internal),
+        );
+
+        let resume = self.builder.get_insert_block().unwrap();
+        self.builder.unset_current_debug_location();
+        let entry = self.ctx.append_basic_block(f, "entry");
+        self.builder.position_at_end(entry);
+        let stream = self.std_stream(2);
+        let msg = self
+            .builder
+            .build_global_string_ptr("step limit exceeded\n", "step_limit_msg")
+            .unwrap();
+        self.builder
+            .build_call(fprintf, &[stream.into(), msg.as_pointer_value().into()], "")
+            .unwrap();
+        self.builder
+            .build_call(exit, &[i32_type.const_int(3, false).into()], "")
+            .unwrap();
+        self.builder.build_unreachable().unwrap();
+        self.builder.position_at_end(resume);
+        f
+    }
+
+    /// Counts a jump back to a loop condition, bailing out through
+    /// `bf_step_limit` once there have been more than `max_steps`. Only back
+    /// edges are counted, since any program that runs forever has to take
+    /// one, and nothing is emitted without a limit.
+    fn count_step(&self) {
+        if self.max_steps == 0 {
+            return;
+        }
+        let i64_type = self.ctx.i64_type();
+        let steps = self.steps_global();
+        let limit = self.step_limit_function();
+        self.debug_location();
+        let count = self
+            .builder
+            .build_load(steps, "steps")
+            .unwrap()
+            .into_int_value();
+        let count = self
+            .builder
+            .build_int_add(count, self.i64_one, "steps")
+            .unwrap();
+        self.builder.build_store(steps, count).unwrap();
+        let exceeded = self
+            .builder
+            .build_int_compare(
+                inkwell::IntPredicate::UGT,
+                count,
+                i64_type.const_int(self.max_steps, false),
+                "exceeded",
+            )
+            .unwrap();
+        let func = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+        let limit_block = self.ctx.append_basic_block(func, "step_limit");
+        let ok_block = self.ctx.append_basic_block(func, "step_ok");
+        self.builder
+            .build_conditional_branch(exceeded, limit_block, ok_block)
+            .unwrap();
+        self.builder.position_at_end(limit_block);
+        self.builder.build_call(limit, &[], "").unwrap();
+        self.builder.build_unreachable().unwrap();
+        self.builder.position_at_end(ok_block);
+    }
+
+    /// Attaches a DWARF subprogram to `func`, returning its scope. `None`
+    /// unless building with debug info.
+    fn debug_subprogram(
+        &self,
+        func: FunctionValue<'a>,
+        name: &str,
+    ) -> Option<(DIScope<'a>, DIFile<'a>)> {
+        let debug = self.debug.as_ref()?;
+        let file = debug.file(self.span.file);
+        let line = self.span.line;
+        let ty = debug
+            .builder
+            .create_subroutine_type(file, None, &[], DIFlags::PUBLIC);
+        let subprogram = debug.builder.create_function(
+            debug.unit.as_debug_info_scope(),
+            name,
+            None,
+            file,
+            line,
+            ty,
+            false,
+            true,
+            line,
+            DIFlags::PUBLIC,
+            false,
+        );
+        func.set_subprogram(subprogram);
+        Some((subprogram.as_debug_info_scope(), file))
+    }
+
+    /// Moves `span` to the source of op `i`. Ops without one keep the
+    /// location of the op before them.
+    fn set_span(&mut self, i: usize) {
+        self.span = self.spans.get(i).copied().unwrap_or(self.span);
+    }
+
+    /// Number of loop iterations run so far, for `max_steps`.
+    fn steps_global(&self) -> PointerValue<'a> {
+        let i64_type = self.ctx.i64_type();
+        match self.module.get_global("steps") {
+            Some(global) => global,
+            None => {
+                let global = self.module.add_global(i64_type, None, "steps");
+                global.set_initializer(&self.i64_zero);
+                global.set_linkage(Linkage::Private);
+                global
+            }
+        }
+        .as_pointer_value()
+    }
+
+    /// Points the debug location of the instructions that follow at `span`,
+    /// in the function currently being generated. Ops from another file
+    /// than the function's, like an included one, go in a lexical block
+    /// for that file.
+    fn debug_location(&self) {
+        let scope = self.frames.last().unwrap().scope;
+        let (Some(debug), Some((scope, file))) = (&self.debug, scope) else {
+            return;
+        };
+        let Span { line, col, .. } = self.span;
+        let span_file = debug.file(self.span.file);
+        let scope = if span_file == file {
+            scope
+        } else {
+            debug
+                .builder
+                .create_lexical_block(scope, span_file, line, col)
+                .as_debug_info_scope()
+        };
+        let location = debug
+            .builder
+            .create_debug_location(self.ctx, line, col, scope, None);
+        self.builder.set_current_debug_location(location);
+    }
+
+    /// Where each op came from, and the names of the files spans point
+    /// into, for debug info and tracing.
+    pub fn set_spans(&mut self, spans: Vec<Span>, files: &[String]) {
+        if let Some(debug) = &mut self.debug {
+            let di_files = files
+                .iter()
+                .map(|path| {
+                    let (file, dir) = split_path(path);
+                    debug.builder.create_file(&file, &dir)
+                })
+                .collect();
+            debug.files = di_files;
+        }
+        let multi_file = spans.iter().any(|span| span.file != spans[0].file);
+        self.trace_files = if multi_file {
+            files.to_vec()
+        } else {
+            Vec::new()
+        };
+        self.spans = spans;
+    }
+
+    /// `fprintf`, declared on first use.
+    fn fprintf_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("fprintf") {
+            return f;
+        }
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        self.module.add_function(
+            "fprintf",
+            self.ctx
+                .i32_type()
+                .fn_type(&[i8_ptr.into(), i8_ptr.into()], true),
+            None,
+        )
+    }
+
+    /// Index of the pointer's cell on the tape, as an i64.
+    fn cur_index(&self) -> IntValue<'a> {
+        self.index_of(self.cur_ptr())
+    }
+
+    /// Index of the cell `cur` points at, as an i64.
+    fn index_of(&self, cur: PointerValue<'a>) -> IntValue<'a> {
+        let i64_type = self.ctx.i64_type();
+        let base = self.tape_base();
+        let cur_int = self
+            .builder
+            .build_ptr_to_int(cur, i64_type, "cur_int")
+            .unwrap();
+        let base_int = self
+            .builder
+            .build_ptr_to_int(base, i64_type, "base_int")
+            .unwrap();
+        let bytes = self
+            .builder
+            .build_int_sub(cur_int, base_int, "offset")
+            .unwrap();
+        self.builder
+            .build_int_exact_signed_div(
+                bytes,
+                i64_type.const_int(self.cell_type.get_bit_width() as u64 / 8, false),
+                "index",
+            )
+            .unwrap()
+    }
+
+    /// The `bf_trace` helper, which prints a source location, an op, the
+    /// pointer's cell index and the cell value to stderr. Emitted into the
+    /// module on first use, so traced programs need no extra runtime.
+    fn trace_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("bf_trace") {
+            return f;
+        }
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        let i32_type = self.ctx.i32_type();
+        let i64_type = self.ctx.i64_type();
+        let fprintf = self.fprintf_function();
+        let f = self.module.add_function(
+            "bf_trace",
+            self.ctx.void_type().fn_type(
+                &[
+                    i8_ptr.into(),
+                    i32_type.into(),
+                    i32_type.into(),
+                    i8_ptr.into(),
+                    i64_type.into(),
+                    i64_type.into(),
+                ],
+                false,
+            ),
+            Some(Linkage::Internal),
+        );
+
+        let resume = self.builder.get_insert_block().unwrap();
+        // The helper has no debug info of its own.
+        self.builder.unset_current_debug_location();
+        let entry = self.ctx.append_basic_block(f, "entry");
+        self.builder.position_at_end(entry);
+        let stream = self.std_stream(2);
+        let format = self
+            .builder
+            .build_global_string_ptr("%s%u:%u %s ptr=%lld cell=%llu\n", "trace_format")
+            .unwrap();
+        let mut args = vec![stream.into(), format.as_pointer_value().into()];
+        args.extend((0..6).map(|i| BasicMetadataValueEnum::from(f.get_nth_param(i).unwrap())));
+        self.builder.build_call(fprintf, &args, "trace").unwrap();
+        self.builder.build_return(None).unwrap();
+        self.builder.position_at_end(resume);
+        f
+    }
+
+    /// The `file:` to start trace lines for the current op with, empty if
+    /// the program is all one file.
+    fn trace_file(&self) -> PointerValue<'a> {
+        let (name, prefix) = match self.trace_files.get(self.span.file) {
+            Some(file) => (
+                format!("trace_file{}", self.span.file),
+                format!("{}:", file),
+            ),
+            None => ("trace_file".to_string(), String::new()),
+        };
+        let global = match self.module.get_global(&name) {
+            Some(global) => global,
+            None => self
+                .builder
+                .build_global_string_ptr(&prefix, &name)
+                .unwrap(),
+        };
+        // Looked up again, the global is the array rather than a pointer to
+        // its first byte.
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        global.as_pointer_value().const_cast(i8_ptr)
+    }
+
+    /// Emits a call printing the state after `op` to stderr.
+    fn trace_op(&mut self, op: &str) {
+        let i64_type = self.ctx.i64_type();
+        let trace = self.trace_function();
+        self.debug_location();
+        let index = self.cur_index();
+        let val = self
+            .builder
+            .build_load(self.cur_ptr(), "load_val")
+            .unwrap()
+            .into_int_value();
+        let val = self
+            .builder
+            .build_int_z_extend(val, i64_type, "trace_val")
+            .unwrap();
+        let name = self
+            .builder
+            .build_global_string_ptr(op, "trace_op")
+            .unwrap();
+        let file = self.trace_file();
+        let i32_type = self.ctx.i32_type();
+        self.builder
+            .build_call(
+                trace,
+                &[
+                    file.into(),
+                    i32_type.const_int(self.span.line as u64, false).into(),
+                    i32_type.const_int(self.span.col as u64, false).into(),
+                    name.as_pointer_value().into(),
+                    index.into(),
+                    val.into(),
+                ],
+                "",
+            )
+            .unwrap();
+    }
+
+    /// The `bf_dump_tape` helper, which prints the pointer's index and a
+    /// hex dump of up to 16 cells around it to stderr, with a caret under
+    /// the pointer's cell. Emitted into the module on first use.
+    fn dump_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("bf_dump_tape") {
+            return f;
+        }
+        let i64_type = self.ctx.i64_type();
+        let dump_cells = self.dump_cells_function();
+        let f = self.module.add_function(
+            "bf_dump_tape",
+            self.ctx
+                .void_type()
+                .fn_type(&[self.cell_ptr_type().into(), i64_type.into()], false),
+            Some(Linkage::Internal),
+        );
+
+        let resume = self.builder.get_insert_block().unwrap();
+        self.builder.unset_current_debug_location();
+        let entry = self.ctx.append_basic_block(f, "entry");
+        self.builder.position_at_end(entry);
+        let base = f.get_nth_param(0).unwrap().into_pointer_value();
+        let index = f.get_nth_param(1).unwrap().into_int_value();
+        // Show 8 cells before the pointer and 7 after, clamped to the tape.
+        let before = i64_type.const_int(8, false);
+        let has_before = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::SGT, index, before, "has_before")
+            .unwrap();
+        let shifted = self
+            .builder
+            .build_int_sub(index, before, "shifted")
+            .unwrap();
+        let start = self
+            .builder
+            .build_select(has_before, shifted, self.i64_zero, "start")
+            .unwrap()
+            .into_int_value();
+        let window_end = self
+            .builder
+            .build_int_add(start, i64_type.const_int(16, false), "window_end")
+            .unwrap();
+        let cells = i64_type.const_int(self.cells, false);
+        let fits = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::ULT, window_end, cells, "fits")
+            .unwrap();
+        let end = self
+            .builder
+            .build_select(fits, window_end, cells, "end")
+            .unwrap()
+            .into_int_value();
+        self.builder
+            .build_call(
+                dump_cells,
+                &[base.into(), index.into(), start.into(), end.into()],
+                "",
+            )
+            .unwrap();
+        self.builder.build_return(None).unwrap();
+        self.builder.position_at_end(resume);
+        f
+    }
+
+    /// The `bf_dump_cells` helper behind `bf_dump_tape` and `print_cells`,
+    /// which prints the pointer's index and cells `start..end` in hex to
+    /// stderr, with a caret under the pointer's cell. Emitted into the
+    /// module on first use.
+    fn dump_cells_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("bf_dump_cells") {
+            return f;
+        }
+        let i32_type = self.ctx.i32_type();
+        let i64_type = self.ctx.i64_type();
+        let fprintf = self.fprintf_function();
+        let f = self.module.add_function(
+            "bf_dump_cells",
+            self.ctx.void_type().fn_type(
+                &[
+                    self.cell_ptr_type().into(),
+                    i64_type.into(),
+                    i64_type.into(),
+                    i64_type.into(),
+                ],
+                false,
+            ),
+            Some(Linkage::Internal),
+        );
+
+        let resume = self.builder.get_insert_block().unwrap();
+        self.builder.unset_current_debug_location();
+        let entry = self.ctx.append_basic_block(f, "entry");
+        let cond_block = self.ctx.append_basic_block(f, "cond");
+        let body_block = self.ctx.append_basic_block(f, "body");
+        let done_block = self.ctx.append_basic_block(f, "done");
+        self.builder.position_at_end(entry);
+        let base = f.get_nth_param(0).unwrap().into_pointer_value();
+        let index = f.get_nth_param(1).unwrap().into_int_value();
+        let start = f.get_nth_param(2).unwrap().into_int_value();
+        let end = f.get_nth_param(3).unwrap().into_int_value();
+        let stream = self.std_stream(2);
+        let header = self
+            .builder
+            .build_global_string_ptr("ptr=%lld\n", "dump_header")
+            .unwrap();
+        self.builder
+            .build_call(
+                fprintf,
+                &[
+                    stream.into(),
+                    header.as_pointer_value().into(),
+                    index.into(),
+                ],
+                "",
+            )
+            .unwrap();
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+
+        self.builder.position_at_end(cond_block);
+        let i = self.builder.build_phi(i64_type, "i").unwrap();
+        i.add_incoming(&[(&start, entry)]);
+        let i_val = i.as_basic_value().into_int_value();
+        let more = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::ULT, i_val, end, "more")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(more, body_block, done_block)
+            .unwrap();
+
+        // Every cell is printed as wide as its largest value.
+        let digits = i32_type.const_int(self.cell_type.get_bit_width() as u64 / 4, false);
+        self.builder.position_at_end(body_block);
+        let cell_ptr = unsafe { self.builder.build_gep(base, &[i_val], "cell").unwrap() };
+        let val = self
+            .builder
+            .build_load(cell_ptr, "load_val")
+            .unwrap()
+            .into_int_value();
+        let val = self
+            .builder
+            .build_int_z_extend(val, i64_type, "dump_val")
+            .unwrap();
+        let stream = self.std_stream(2);
+        let cell_format = self
+            .builder
+            .build_global_string_ptr("%0*llx ", "dump_cell")
+            .unwrap();
+        self.builder
+            .build_call(
+                fprintf,
+                &[
+                    stream.into(),
+                    cell_format.as_pointer_value().into(),
+                    digits.into(),
+                    val.into(),
+                ],
+                "",
+            )
+            .unwrap();
+        let next = self
+            .builder
+            .build_int_add(i_val, self.i64_one, "next")
+            .unwrap();
+        i.add_incoming(&[(&next, body_block)]);
+        self.builder.build_unconditional_branch(cond_block).unwrap();
+
+        // Each cell takes its digits plus a space, so the caret is indented
+        // by that much per cell before the pointer.
+        self.builder.position_at_end(done_block);
+        let skipped = self.builder.build_int_sub(index, start, "skipped").unwrap();
+        let skipped = self
+            .builder
+            .build_int_truncate(skipped, i32_type, "skipped")
+            .unwrap();
+        let indent = self
+            .builder
+            .build_int_mul(
+                skipped,
+                i32_type.const_int(self.cell_type.get_bit_width() as u64 / 4 + 1, false),
+                "indent",
+            )
+            .unwrap();
+        let caret = self
+            .builder
+            .build_global_string_ptr("\n%*s^\n", "dump_caret")
+            .unwrap();
+        let empty = self.builder.build_global_string_ptr("", "empty").unwrap();
+        let stream = self.std_stream(2);
+        self.builder
+            .build_call(
+                fprintf,
+                &[
+                    stream.into(),
+                    caret.as_pointer_value().into(),
+                    indent.into(),
+                    empty.as_pointer_value().into(),
+                ],
+                "",
+            )
+            .unwrap();
+        self.builder.build_return(None).unwrap();
+        self.builder.position_at_end(resume);
+        f
+    }
+
+    /// Emits a call dumping the first `count` cells, or as many as there
+    /// are, to stderr.
+    fn print_cells(&mut self, count: u64) {
+        let dump_cells = self.dump_cells_function();
+        self.debug_location();
+        let i64_type = self.ctx.i64_type();
+        let index = self.cur_index();
+        let base = self.tape_base();
+        let end = i64_type.const_int(count.min(self.cells), false);
+        self.builder
+            .build_call(
+                dump_cells,
+                &[base.into(), index.into(), self.i64_zero.into(), end.into()],
+                "",
+            )
+            .unwrap();
+    }
+
+    /// Emits a call dumping the tape around the pointer to stderr.
+    fn dump_tape(&mut self) {
+        let dump = self.dump_function();
+        self.debug_location();
+        let index = self.cur_index();
+        let base = self.tape_base();
+        self.builder
+            .build_call(dump, &[base.into(), index.into()], "")
+            .unwrap();
+    }
+
+    /// Declares the function for a proc, so it can be called before its
+    /// definition is reached.
+    fn declare_proc(&mut self, name: &str) {
+        // Prefixed so a proc can't clash with main or the libc functions, and
+        // internal unless building a library, since nothing else calls it.
+        let linkage = if self.shared {
+            Linkage::External
+        } else {
+            Linkage::Internal
+        };
+        let f = self.module.add_function(
+            &proc_symbol(name),
+            self.ctx
+                .void_type()
+                .fn_type(&[self.cell_ptr_type().into()], false),
+            Some(linkage),
+        );
+        self.procs.insert(name.to_string(), f);
+    }
+
+    fn proc_define(&mut self, name: String) {
+        let f = self.procs[&name];
+        let ptr = f.get_first_param().unwrap();
+        let resume = self.builder.get_insert_block().unwrap();
+
+        let entry = self.ctx.append_basic_block(f, "entry");
+        self.builder.position_at_end(entry);
+
+        let scope = self.debug_subprogram(f, &name);
+        self.frames.push(Frame {
+            ptr: ptr.into_pointer_value(),
+            offset: 0,
+            loops: Vec::new(),
+            proc: Some((name, resume)),
+            scope,
+            out_buf: None,
+        });
+    }
+
+    fn proc_end(&mut self) {
+        let (_, resume) = self.frames.pop().unwrap().proc.unwrap();
+        self.builder.build_return(None).unwrap();
+        self.builder.position_at_end(resume);
+    }
+
+    fn proc_call(&mut self, name: &str) {
+        let f = self.procs[name];
+        let ptr = self.cur_ptr();
+        // The proc may grow the tape and move it, so the pointer is kept as
+        // an index across the call.
+        let index = self.grow.then(|| self.index_of(ptr));
+        self.builder
+            .build_call(f, &[BasicMetadataValueEnum::PointerValue(ptr)], name)
+            .unwrap();
+        if let Some(index) = index {
+            let base = self.tape_base();
+            let ptr = unsafe { self.builder.build_gep(base, &[index], "rebased").unwrap() };
+            self.set_ptr(ptr);
+        }
+    }
+
+    /// Capacity of a growing tape, in cells.
+    fn tape_cap(&self) -> PointerValue<'a> {
+        let i64_type = self.ctx.i64_type();
+        let global = match self.module.get_global("tape_cap") {
+            Some(global) => global,
+            None => {
+                let global = self.module.add_global(i64_type, None, "tape_cap");
+                global.set_initializer(&i64_type.const_int(self.cells, false));
+                global.set_linkage(Linkage::Private);
+                global
+            }
+        };
+        global.as_pointer_value()
+    }
+
+    /// The `bf_grow_tape` helper, which doubles the tape's capacity until
+    /// the given index fits, zeroes the new cells and returns the new base.
+    /// Emitted into the module on first use.
+    fn grow_function(&self) -> FunctionValue<'a> {
+        if let Some(f) = self.module.get_function("bf_grow_tape") {
+            return f;
+        }
+        let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
+        let i64_type = self.ctx.i64_type();
+        let cell_size = i64_type.const_int(self.cell_type.get_bit_width() as u64 / 8, false);
+        let f = self.module.add_function(
+            "bf_grow_tape",
+            self.cell_ptr_type().fn_type(&[i64_type.into()], false),
+            Some(Linkage::Internal),
+        );
+
+        let resume = self.builder.get_insert_block().unwrap();
+        self.builder.unset_current_debug_location();
+        let entry = self.ctx.append_basic_block(f, "entry");
+        let double_block = self.ctx.append_basic_block(f, "double");
+        let alloc_block = self.ctx.append_basic_block(f, "alloc");
+        let fail_block = self.ctx.append_basic_block(f, "alloc_failed");
+        let ok_block = self.ctx.append_basic_block(f, "alloc_ok");
+        self.builder.position_at_end(entry);
+        let index = f.get_nth_param(0).unwrap().into_int_value();
+        let cap = self
+            .builder
+            .build_load(self.tape_cap(), "cap")
+            .unwrap()
+            .into_int_value();
+        self.builder
+            .build_unconditional_branch(double_block)
+            .unwrap();
+
+        self.builder.position_at_end(double_block);
+        let new_cap = self.builder.build_phi(i64_type, "new_cap").unwrap();
+        let doubled = self
+            .builder
+            .build_int_mul(
+                new_cap.as_basic_value().into_int_value(),
+                i64_type.const_int(2, false),
+                "doubled",
+            )
+            .unwrap();
+        new_cap.add_incoming(&[(&cap, entry), (&doubled, double_block)]);
+        let fits = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::UGT, doubled, index, "fits")
+            .unwrap();
+        self.builder
+            .build_conditional_branch(fits, alloc_block, double_block)
+            .unwrap();
+
+        self.builder.position_at_end(alloc_block);
+        let base = self
+            .builder
+            .build_load(self.tape_global, "tape_base")
+            .unwrap()
+            .into_pointer_value();
+        let base = self
+            .builder
+            .build_pointer_cast(base, i8_ptr, "tape_bytes")
+            .unwrap();
+        let bytes = self
+            .builder
+            .build_int_mul(doubled, cell_size, "bytes")
+            .unwrap();
+        let realloc = self.module.get_function("realloc").unwrap();
+        let grown = self
+            .builder
+            .build_call(realloc, &[base.into(), bytes.into()], "grown")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+        let failed = self.builder.build_is_null(grown, "failed").unwrap();
+        self.builder
+            .build_conditional_branch(failed, fail_block, ok_block)
+            .unwrap();
+
+        self.builder.position_at_end(fail_block);
+        self.emit_abort("tape allocation failed");
+
+        self.builder.position_at_end(ok_block);
+        let old_bytes = self
+            .builder
+            .build_int_mul(cap, cell_size, "old_bytes")
+            .unwrap();
+        let fresh = unsafe {
+            self.builder
+                .build_gep(grown, &[old_bytes], "fresh")
+                .unwrap()
+        };
+        let fresh_bytes = self
+            .builder
+            .build_int_sub(bytes, old_bytes, "fresh_bytes")
+            .unwrap();
+        self.builder
+            .build_memset(fresh, 1, self.ctx.i8_type().const_zero(), fresh_bytes)
+            .unwrap();
+        let tape = self
+            .builder
+            .build_pointer_cast(grown, self.cell_ptr_type(), "tape")
+            .unwrap();
+        self.builder.build_store(self.tape_global, tape).unwrap();
+        self.builder.build_store(self.tape_cap(), doubled).unwrap();
+        self.builder.build_return(Some(&tape)).unwrap();
+        self.builder.position_at_end(resume);
+        f
+    }
+
+    /// With `grow`, makes sure `ptr` is on the tape, growing it if not, and
+    /// returns where that cell is afterwards.
+    fn ensure_capacity(&self, ptr: PointerValue<'a>) -> PointerValue<'a> {
+        let grow = self.grow_function();
+        self.debug_location();
+        let index = self.index_of(ptr);
+        let cap = self
+            .builder
+            .build_load(self.tape_cap(), "cap")
+            .unwrap()
+            .into_int_value();
+        let outside = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::UGE, index, cap, "outside")
+            .unwrap();
+        let pre_block = self.builder.get_insert_block().unwrap();
+        let func = pre_block.get_parent().unwrap();
+        let grow_block = self.ctx.append_basic_block(func, "grow");
+        let cont_block = self.ctx.append_basic_block(func, "grown");
+        self.builder
+            .build_conditional_branch(outside, grow_block, cont_block)
+            .unwrap();
+
+        self.builder.position_at_end(grow_block);
+        let base = self
+            .builder
+            .build_call(grow, &[index.into()], "new_base")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+        let moved = unsafe { self.builder.build_gep(base, &[index], "moved").unwrap() };
+        self.builder.build_unconditional_branch(cont_block).unwrap();
+
+        self.builder.position_at_end(cont_block);
+        let phi = self
+            .builder
+            .build_phi(self.cell_ptr_type(), "grown_ptr")
+            .unwrap();
+        phi.add_incoming(&[(&ptr, pre_block), (&moved, grow_block)]);
+        phi.as_basic_value().into_pointer_value()
+    }
+
+    pub fn new(
+        ctx: &'a Context,
+        ast: Vec<Op>,
+        input: Option<Vec<u8>>,
+        options: &CompileOptions,
+    ) -> Self {
+        let builder = ctx.create_builder();
+        let module = ctx.create_module("main");
+        let i8_type = ctx.i8_type();
+        let i32_type = ctx.i32_type();
+        let i8_ptr = i8_type.ptr_type(AddressSpace::default());
+        let i64_type = ctx.i64_type();
+        let cell_type = ctx.custom_width_int_type(options.cell_bits);
+        let triple = options.target_triple();
+        let wasm = triple.starts_with("wasm32");
+        let size_type = if wasm { i32_type } else { i64_type };
+        let _putchar =
+            module.add_function("putchar", i8_type.fn_type(&[i32_type.into()], false), None);
+        let _getchar = module.add_function("getchar", i8_type.fn_type(&[], false), None);
+        let _fwrite = module.add_function(
+            "fwrite",
+            size_type.fn_type(
+                &[
+                    i8_ptr.into(),
+                    size_type.into(),
+                    size_type.into(),
+                    i8_ptr.into(),
+                ],
+                false,
+            ),
+            None,
+        );
+        let calloc = module.add_function(
+            "calloc",
+            i8_ptr.fn_type(&[i64_type.into(), i64_type.into()], false),
+            None,
+        );
+        let _memchr = module.add_function(
+            "memchr",
+            i8_ptr.fn_type(&[i8_ptr.into(), i32_type.into(), i64_type.into()], false),
+            None,
+        );
+        let _realloc = module.add_function(
+            "realloc",
+            i8_ptr.fn_type(&[i8_ptr.into(), i64_type.into()], false),
+            None,
+        );
+        let _abort = module.add_function("abort", ctx.void_type().fn_type(&[], false), None);
+        let fn_type = i32_type.fn_type(&[], false);
+        // A library has no entry point, but top level code still needs a
+        // function to go in, so it gets one nobody can see.
+        // wasi-libc's start code calls main by the name clang gives an
+        // `int main(void)`.
+        let func = if options.shared {
+            module.add_function("bf_main", fn_type, Some(Linkage::Internal))
+        } else if let Some(name) = &options.entry_symbol {
+            module.add_function(name, fn_type, None)
+        } else if wasm && triple.contains("wasi") {
+            module.add_function("__main_void", fn_type, None)
+        } else {
+            module.add_function("main", fn_type, None)
+        };
+        let block = ctx.append_basic_block(func, "entry");
+        builder.position_at_end(block);
+
+        // WebAssembly and freestanding builds have no heap without a C
+        // runtime, so the tape is a zeroed global array there instead.
+        let storage = match options.tape {
+            TapeStorage::Heap if wasm || options.freestanding => TapeStorage::Global,
+            storage => storage,
+        };
+        let (block, calloc_block) = match storage {
+            TapeStorage::Global => {
+                let tape_type = cell_type.array_type(options.cells as u32);
+                let cells = module.add_global(tape_type, None, "tape_cells");
+                cells.set_initializer(&tape_type.const_zero());
+                cells.set_linkage(Linkage::Private);
+                (cells.as_pointer_value(), None)
+            }
+            TapeStorage::Stack => {
+                let cells = builder
+                    .build_array_alloca(
+                        cell_type,
+                        i64_type.const_int(options.cells, false),
+                        "tape_cells",
+                    )
+                    .unwrap();
+                let cell_bytes = options.cell_bits / 8;
+                let size = i64_type.const_int(options.cells * cell_bytes as u64, false);
+                builder
+                    .build_memset(cells, cell_bytes, i8_type.const_zero(), size)
+                    .unwrap();
+                (cells, None)
+            }
+            TapeStorage::Heap => {
+                let args = (
+                    i64_type.const_int(options.cells, false),
+                    i64_type.const_int(options.cell_bits as u64 / 8, false),
+                );
+                let block = builder
+                    .build_call(calloc, &[args.0.into(), args.1.into()], "block")
+                    .unwrap()
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_pointer_value();
+                (block, Some(block))
+            }
+        };
+
+        let tape = builder
+            .build_pointer_cast(block, cell_type.ptr_type(AddressSpace::default()), "tape")
+            .unwrap();
+        let cell_ptr = cell_type.ptr_type(AddressSpace::default());
+        let tape_global = module.add_global(cell_ptr, None, "tape");
+        tape_global.set_initializer(&cell_ptr.const_null());
+        tape_global.set_linkage(Linkage::Private);
+        builder
+            .build_store(tape_global.as_pointer_value(), tape)
+            .unwrap();
+
+        let start = start_ptr(ctx, &builder, tape, options.start_cell);
+
+        let input = match (input, options.input_fd) {
+            (Some(data), _) => {
+                let data_val = ctx.const_string(&data, false);
+                let data_global = module.add_global(data_val.get_type(), None, "input_data");
+                data_global.set_initializer(&data_val);
+                data_global.set_constant(true);
+                data_global.set_linkage(Linkage::Private);
+                let pos_global = module.add_global(i64_type, None, "input_pos");
+                pos_global.set_initializer(&i64_type.const_zero());
+                pos_global.set_linkage(Linkage::Private);
+                InputSource::Embedded {
+                    data: data_global.as_pointer_value(),
+                    pos: pos_global.as_pointer_value(),
+                    len: data.len() as u64,
+                }
+            }
+            (None, Some(fd)) => InputSource::Fd(fd),
+            (None, None) => InputSource::Stdin,
+        };
+        let debug = options.debug_source.as_deref().map(|path| {
+            let (file, dir) = split_path(path);
+            // Only the name, so the object doesn't depend on where it was
+            // built from. The directory is recorded as given on the command
+            // line.
+            module.set_source_file_name(&file);
+            module.add_basic_value_flag(
+                "Debug Info Version",
+                FlagBehavior::Warning,
+                i32_type.const_int(3, false),
+            );
+            let (builder, unit) = module.create_debug_info_builder(
+                true,
+                DWARFSourceLanguage::C,
+                &file,
+                &dir,
+                "bf",
+                false,
+                "",
+                0,
+                "",
+                DWARFEmissionKind::Full,
+                0,
+                false,
+                false,
+                "",
+                "",
+            );
+            DebugInfo {
+                builder,
+                unit,
+                files: Vec::new(),
+            }
+        });
+        let mut cdg = Self {
+            ctx,
+            builder,
+            frames: vec![Frame {
+                ptr: start,
+                offset: 0,
+                loops: Vec::new(),
+                proc: None,
+                scope: None,
+                out_buf: None,
+            }],
+            cell_type,
+            cell_zero: cell_type.const_zero(),
+            i64_zero: i64_type.const_zero(),
+            i64_one: i64_type.const_int(1, false),
+            signed_cells: options.signed_cells,
+            tape,
+            tape_global: tape_global.as_pointer_value(),
+            cells: options.cells,
+            start_cell: options.start_cell,
+            wrap_pointer: options.wrap_pointer,
+            exit_cell: options.exit_cell,
+            shared: options.shared,
+            static_link: options.static_link,
+            link_args: options.link_args.clone(),
+            output_fd: options.output_fd,
+            output_encoding: options.output_encoding,
+            flush_on_input: options.flush_on_input,
+            triple,
+            input,
+            module,
+            entered_loops: optimizer::entered_loops(&ast, options.cell_bits, !options.reusable),
+            ast,
+            procs: HashMap::new(),
+            debug,
+            spans: Vec::new(),
+            span: Span {
+                file: 0,
+                line: 1,
+                col: 1,
+            },
+            trace_files: Vec::new(),
+            trace: options.trace,
+            loop_hints: options.loop_hints,
+            max_steps: options.max_steps,
+            print_cells: options.print_cells,
+            grow: options.grow,
+            syscalls: options.freestanding,
+        };
+        let name = options.entry_symbol.as_deref().unwrap_or("main");
+        cdg.frames[0].scope = cdg.debug_subprogram(func, name);
+
+        // A huge --cells or memory pressure gets a message instead of a
+        // segfault on the first tape access.
+        if let Some(calloc_block) = calloc_block {
+            let failed = cdg
+                .builder
+                .build_is_null(calloc_block, "alloc_failed")
+                .unwrap();
+            let fail_block = ctx.append_basic_block(func, "alloc_failed");
+            let ok_block = ctx.append_basic_block(func, "alloc_ok");
+            cdg.builder
+                .build_conditional_branch(failed, fail_block, ok_block)
+                .unwrap();
+            cdg.builder.position_at_end(fail_block);
+            cdg.emit_abort("tape allocation failed");
+            cdg.builder.position_at_end(ok_block);
+        }
+        if options.reusable {
+            cdg.split_body();
+        }
+        cdg
+    }
+
+    /// Where the pointer starts on `tape`.
+    fn start_ptr(&self, tape: PointerValue<'a>) -> PointerValue<'a> {
+        start_ptr(self.ctx, &self.builder, tape, self.start_cell)
+    }
+
+    /// Whether the target is 32 bit WebAssembly.
+    fn wasm(&self) -> bool {
+        self.triple.starts_with("wasm32")
+    }
+
+    /// Whether there is no C runtime at all, as on wasm32-unknown-unknown,
+    /// leaving only `putchar`, `getchar`, `read` and `write`, imported from
+    /// the host there and made of system calls in `syscalls` builds.
+    fn freestanding(&self) -> bool {
+        self.syscalls || (self.wasm() && !self.triple.contains("wasi"))
+    }
+
+    /// The C runtime's `size_t`.
+    fn size_type(&self) -> IntType<'a> {
+        if self.wasm() {
+            self.ctx.i32_type()
+        } else {
+            self.ctx.i64_type()
+        }
+    }
+
+    /// Moves the program into `bf_run(cell *tape)`, returning the exit code,
+    /// and leaves main to allocate a tape and call it. Everything the body
+    /// reads from globals is reset on entry, so it can be run again.
+    fn split_body(&mut self) {
+        let i32_type = self.ctx.i32_type();
+        let run = self.module.add_function(
+            "bf_run",
+            i32_type.fn_type(&[self.cell_ptr_type().into()], false),
+            None,
+        );
+        self.debug_location();
+        let code = self
+            .builder
+            .build_call(run, &[self.tape.into()], "code")
+            .unwrap()
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        self.builder.build_return(Some(&code)).unwrap();
+
+        let entry = self.ctx.append_basic_block(run, "entry");
+        self.builder.position_at_end(entry);
+        self.frames[0].scope = self.debug_subprogram(run, "bf_run");
+        self.debug_location();
+        let tape = run.get_nth_param(0).unwrap().into_pointer_value();
+        self.builder.build_store(self.tape_global, tape).unwrap();
+        self.tape = tape;
+        self.frames[0].ptr = self.start_ptr(tape);
+        if let InputSource::Embedded { pos, .. } = self.input {
+            self.builder.build_store(pos, self.i64_zero).unwrap();
+        }
+        if self.max_steps > 0 {
+            self.builder
+                .build_store(self.steps_global(), self.i64_zero)
+                .unwrap();
+        }
+    }
+
+    /// Generates code for the whole op stream. Op streams from the lexer
+    /// are always well formed, but one built by hand could end inside a
+    /// proc body, which would leave main's code in the proc.
+    pub fn run(&mut self) -> Result<(), CompileError> {
+        let names: Vec<String> = self
+            .ast
+            .iter()
+            .filter_map(|op| match op {
+                Op::ProcDef(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        for name in names {
+            self.declare_proc(&name);
+        }
+        for (i, op) in self.ast.clone().drain(..).enumerate() {
+            self.set_span(i);
+            self.debug_location();
+            // Definitions and their end markers don't run where they appear.
+            let traced = (self.trace && !matches!(op, Op::ProcDef(_) | Op::ProcEnd))
+                .then(|| format!("{:?}", op));
+            match op {
+                Op::PointerInc(v) => {
+                    self.ptr_manipulate(v, false);
+                }
+                Op::PointerDec(v) => {
+                    self.ptr_manipulate(v, true);
+                }
+                Op::ValueInc(v) => {
+                    self.val_manipulate(v, false);
+                }
+                Op::ValueDec(v) => {
+                    self.val_manipulate(v, true);
+                }
+                Op::Output(1) => {
+                    self.out();
+                }
+                Op::Output(v) => {
+                    self.out_buffered(v);
+                }
+                Op::Input => {
+                    self.input();
+                }
+                Op::LLoop if self.entered_loops[i] => {
+                    self.do_loop_start();
+                }
+                Op::LLoop => {
+                    self.loop_start();
+                }
+                Op::RLoop => {
+                    self.loop_end();
+                }
+                Op::ScanInc(v) => {
+                    self.scan(v, false);
+                }
+                Op::ScanDec(v) => {
+                    self.scan(v, true);
+                }
+                Op::Set(v) => {
+                    self.set(v);
+                }
+                Op::Print(bytes) => {
+                    self.print(&bytes);
+                }
+                Op::Clear(v) => {
+                    self.clear(v);
+                }
+                Op::ProcDef(name) => {
+                    self.proc_define(name);
+                }
+                Op::ProcCall(name) => {
+                    self.proc_call(&name);
+                }
+                Op::ProcEnd => {
+                    self.proc_end();
+                }
+                Op::DumpTape => {
+                    self.dump_tape();
+                }
+            }
+            if let Some(op) = traced {
+                self.trace_op(&op);
+            }
+        }
+        if let Some((name, _)) = &self.frames.last().unwrap().proc {
+            return Err(CompileError::UnterminatedProc { name: name.clone() });
+        }
+        // The last op may have ended a proc, leaving its scope behind.
+        self.debug_location();
+        if self.print_cells > 0 {
+            self.print_cells(self.print_cells);
+        }
+        let i32_type = self.ctx.i32_type();
+        let code = if self.exit_cell {
+            let val = self
+                .builder
+                .build_load(self.cur_ptr(), "load_val")
+                .unwrap()
+                .into_int_value();
+            match val.get_type().get_bit_width() {
+                32 => val,
+                _ => self
+                    .builder
+                    .build_int_z_extend(val, i32_type, "exit_code")
+                    .unwrap(),
+            }
+        } else {
+            i32_type.const_zero()
+        };
+        let _ret = self.builder.build_return(Some(&code));
+        if self.syscalls {
+            self.define_runtime();
+        }
+        if let Some(debug) = &self.debug {
+            debug.builder.finalize();
+        }
+        Ok(())
+    }
+
+    /// The generated module, for running extra passes over before emission.
+    pub fn module(&self) -> &Module<'a> {
+        &self.module
+    }
+
+    pub fn into_module(self) -> Module<'a> {
+        self.module
+    }
+
+    /// C header declaring every proc, for calling a `shared` build.
+    pub fn header(&self) -> String {
+        let cell = match self.cell_type.get_bit_width() {
+            8 => "unsigned char",
+            16 => "uint16_t",
+            _ => "uint32_t",
+        };
+        let mut names: Vec<&String> = self.procs.keys().collect();
+        names.sort();
+        let mut header = String::from("#ifndef BF_MAIN_H\n#define BF_MAIN_H\n\n");
+        header.push_str("#include <stdint.h>\n\n");
+        header.push_str(&format!("#define BF_TAPE_SIZE {}\n\n", self.cells));
+        for name in names {
+            header.push_str(&format!("void {}({} *tape);\n", proc_symbol(name), cell));
+        }
+        header.push_str("\n#endif\n");
+        header
+    }
+
+    /// Textual LLVM IR of the generated module.
+    pub fn ir(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    /// Runs LLVM's verifier over the module, so broken IR is reported
+    /// instead of being handed to the backend.
+    pub fn verify(&self) -> Result<(), CompileError> {
+        self.module
+            .verify()
+            .map_err(|e| CompileError::Verify(e.to_string()))
+    }
+
+    fn target_machine(&self, options: &CompileOptions) -> Result<TargetMachine, CompileError> {
+        Target::initialize_all(&InitializationConfig::default());
+        let target_triple = TargetTriple::create(&self.triple);
+        let target =
+            Target::from_triple(&target_triple).map_err(|e| CompileError::Target(e.to_string()))?;
+        let (cpu, features) = options.target_cpu()?;
+        target
+            .create_target_machine(
+                &target_triple,
+                &cpu,
+                &features,
+                options.opt_level,
+                // Nothing gets relocated at load time in a static program.
+                if options.static_link {
+                    RelocMode::Static
+                } else {
+                    options.reloc_mode
+                },
+                options.code_model,
+            )
+            .ok_or_else(|| {
+                CompileError::Target(format!(
+                    "no target machine for {} with cpu '{}'",
+                    target_triple, cpu
+                ))
+            })
+    }
+
+    /// Emits the module as an object file into memory.
+    pub fn object(&self, options: &CompileOptions) -> Result<Vec<u8>, CompileError> {
+        self.verify()?;
+        let buffer = self
+            .target_machine(options)?
+            .write_to_memory_buffer(&self.module, FileType::Object)
+            .map_err(|e| CompileError::Emit(e.to_string()))?;
+        Ok(buffer.as_slice().to_vec())
+    }
+
+    /// Writes the module as an object file to `path`.
+    pub fn write_object(&self, options: &CompileOptions, path: &str) -> Result<(), CompileError> {
+        self.verify()?;
+        let target_machine = self.target_machine(options)?;
+        let file_type = FileType::Object;
+        target_machine
+            .write_to_file(&self.module, file_type, Path::new(path))
+            .map_err(|e| CompileError::Emit(e.to_string()))
+    }
+
+    /// Links the object written by `write_object` into a program or, for a
+    /// `shared` build, a library.
+    pub fn link(&self, path: &str) -> Result<(), CompileError> {
+        run_linker(self.link_command(path))
+    }
+
+    /// Linker invocation turning the object at `path` into `main.exe` (or
+    /// `main` outside Windows, `main.wasm` for WebAssembly), or with `shared`
+    /// into a library exporting every proc, followed by the extra
+    /// `link_args`.
+    fn link_command(&self, path: &str) -> Command {
+        let windows = self.triple.contains("windows");
+        let mut command = self.platform_link_command(path, windows);
+        for arg in &self.link_args {
+            command.arg(arg.to_arg(windows));
+        }
+        command
+    }
+
+    fn platform_link_command(&self, path: &str, windows: bool) -> Command {
+        if self.syscalls {
+            // `define_runtime` left nothing for a C runtime to provide. Code
+            // and data share a page, which keeps small programs under a
+            // kilobyte once stripped.
+            let mut command = Command::new("ld");
+            command
+                .arg("-nostdlib")
+                .arg("-static")
+                .arg("-z")
+                .arg("noseparate-code");
+            if self.debug.is_none() {
+                command.arg("-s");
+            }
+            command.arg(path).arg("-o").arg("main");
+            return command;
+        }
+        if self.freestanding() {
+            // Whatever is left undefined becomes an import from the host's
+            // `env` module.
+            let mut command = Command::new("wasm-ld");
+            command
+                .arg(path)
+                .arg("-o")
+                .arg("main.wasm")
+                .arg("--no-entry")
+                .arg("--export=main")
+                .arg("--allow-undefined");
+            return command;
+        }
+        if self.wasm() {
+            // clang knows where the WASI sysroot is and what it needs.
+            let mut command = Command::new("clang");
+            command
+                .arg(format!("--target={}", self.triple))
+                .arg(path)
+                .arg("-o")
+                .arg("main.wasm");
+            return command;
+        }
+        if !self.shared && windows {
+            let mut command = Command::new("link");
+            // /Brepro leaves the timestamp out, so the same object always
+            // links to the same bytes.
+            command
+                .arg(path)
+                .arg("/entry:main")
+                .arg("/out:main.exe")
+                .arg("/Brepro");
+            // The static CRT comes in three parts, where the DLL one only
+            // needs its import library.
+            if self.static_link {
+                command
+                    .arg("libucrt.lib")
+                    .arg("libvcruntime.lib")
+                    .arg("libcmt.lib");
+            } else {
+                command.arg("ucrt.lib");
+            }
+            // fprintf is only an inline function in the UCRT headers.
+            if self.module.get_function("fprintf").is_some() {
+                command.arg("legacy_stdio_definitions.lib");
+            }
+            return command;
+        }
+        if !self.shared {
+            let mut command = Command::new("cc");
+            command.arg(path).arg("-o").arg("main");
+            if self.static_link {
+                command.arg("-static");
+            }
+            return command;
+        }
+        if windows {
+            let mut command = Command::new("link");
+            command
+                .arg(path)
+                .arg("/dll")
+                .arg("/noentry")
+                .arg("/Brepro")
+                .arg("/out:main.dll")
+                .arg("ucrt.lib");
+            let mut names: Vec<&String> = self.procs.keys().collect();
+            names.sort();
+            for name in names {
+                command.arg(format!("/export:{}", proc_symbol(name)));
+            }
+            return command;
+        }
+        let mut command = Command::new("cc");
+        if self.triple.contains("apple") {
+            command.arg("-dynamiclib").arg("-o").arg("libmain.dylib");
+        } else {
+            command.arg("-shared").arg("-o").arg("libmain.so");
+        }
+        command.arg(path);
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate;
+
+    /// Arguments of the command linking `source` built with `opts`.
+    fn link_args(source: &str, opts: &CompileOptions) -> Vec<String> {
+        let ctx = Context::create();
+        let cdg = generate(&ctx, source, opts).unwrap();
+        let command = cdg.link_command("main.obj");
+        command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn msvc_links_fprintf_when_used() {
+        let msvc = CompileOptions {
+            target: Some("x86_64-pc-windows-msvc".to_string()),
+            ..Default::default()
+        };
+        let legacy = "legacy_stdio_definitions.lib".to_string();
+        assert!(!link_args(",[.-]", &msvc).contains(&legacy));
+        let step_limit = CompileOptions {
+            max_steps: 10,
+            ..msvc.clone()
+        };
+        assert!(link_args(",[.-]", &step_limit).contains(&legacy));
+        let print_cells = CompileOptions {
+            print_cells: 4,
+            ..msvc
+        };
+        assert!(link_args("+", &print_cells).contains(&legacy));
+    }
+}
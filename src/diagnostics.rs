@@ -0,0 +1,267 @@
+use std::fmt::Write;
+
+use crate::lexer::{ParseError, Position, PROC_CHARS};
+use crate::CompileError;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// When to color diagnostics, for `--color`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only when stderr is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether to color what goes to stderr.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => stderr_is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn stderr_is_terminal() -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    // SAFETY: isatty only looks at the descriptor, which needn't be open.
+    unsafe { isatty(2) == 1 }
+}
+
+/// Windows consoles don't always understand escape codes, so `auto` leaves
+/// them out there.
+#[cfg(not(unix))]
+fn stderr_is_terminal() -> bool {
+    false
+}
+
+/// Wraps `text` in `code` if coloring.
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", code, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The parse error in `error` and the file it is in, `path` for a plain
+/// parse error.
+fn located<'e>(error: &'e CompileError, path: &'e str) -> Option<(&'e ParseError, &'e str)> {
+    match error {
+        CompileError::Parse(e) => Some((e, path)),
+        CompileError::ParseFile { path, error, .. } => Some((error, path)),
+        _ => None,
+    }
+}
+
+/// A hint for a mistake that is easy to make, given the character the
+/// error points at if the source is known.
+fn hint(error: &ParseError, at: Option<char>) -> Option<String> {
+    Some(match error {
+        ParseError::UnmatchedBracket { .. } if at == Some(']') => {
+            "this ']' has no '[' to close; did you forget one, or end a proc before it?".to_string()
+        }
+        ParseError::UnmatchedBracket { .. } if at == Some('[') => {
+            "add a ']' to end this loop, in the same file and proc body".to_string()
+        }
+        ParseError::UndefinedProc { name, .. } => {
+            format!("define it with @{}{{ ... }}", name)
+        }
+        ParseError::DuplicateProc { .. } => {
+            "a proc can only be defined once; give one of them another name".to_string()
+        }
+        ParseError::UnterminatedProc { name, .. }
+            if name.chars().count() == 1 && PROC_CHARS.contains(name.as_str()) =>
+        {
+            format!("end it with another '{}'", name)
+        }
+        ParseError::UnterminatedProc { .. } => "end it with '}'".to_string(),
+        ParseError::IllegalChar { ch, .. } if ch.is_ascii_alphanumeric() => {
+            "letters and digits are reserved in BF>>, even in comments".to_string()
+        }
+        ParseError::IllegalChar { .. } => {
+            "--strict makes characters that aren't code an error".to_string()
+        }
+        ParseError::EmptyLoop { .. } => "to clear a cell, use [-]".to_string(),
+        _ => return None,
+    })
+}
+
+/// Writes the source line at `pos` with a caret under its column, and a
+/// gutter with the line number, returning the character under the caret.
+/// `None` if the source has no such line.
+fn snippet(
+    out: &mut String,
+    source: &str,
+    path: &str,
+    pos: Position,
+    color: bool,
+) -> Option<Option<char>> {
+    // Positions are counted after any byte order mark, as the lexer drops it.
+    let source = source.strip_prefix('\u{FEFF}').unwrap_or(source);
+    let line = source.lines().nth(pos.line.checked_sub(1)?)?;
+    let number = pos.line.to_string();
+    let gutter = " ".repeat(number.len());
+    let bar = paint("|", BLUE, color);
+    // Tabs are kept, so the caret lines up however wide they are shown.
+    let pad: String = line
+        .chars()
+        .take(pos.col - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let _ = writeln!(
+        out,
+        "{}{} {}:{}",
+        gutter,
+        paint("-->", BLUE, color),
+        path,
+        pos
+    );
+    let _ = writeln!(out, "{} {}", gutter, bar);
+    let _ = writeln!(out, "{} {} {}", paint(&number, BLUE, color), bar, line);
+    let _ = writeln!(out, "{} {} {}{}", gutter, bar, pad, paint("^", RED, color));
+    Some(line.chars().nth(pos.col - 1))
+}
+
+/// Renders `error` for a terminal: the message, then for parse errors the
+/// offending source line with a caret under the column and, for common
+/// mistakes, a hint. `path` is the file a plain `CompileError::Parse` is
+/// in, and `read` gets the source of a file, or `None` to leave the
+/// snippet out. Several errors are rendered one after the other, followed
+/// by how many there were.
+pub fn render(
+    error: &CompileError,
+    path: &str,
+    read: impl Fn(&str) -> Option<String>,
+    color: bool,
+) -> String {
+    let CompileError::Errors(errors) = error else {
+        return render_one(error, path, &read, color);
+    };
+    let mut out = errors
+        .iter()
+        .map(|error| render_one(error, path, &read, color))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let _ = writeln!(out, "\n{} errors emitted", errors.len());
+    out
+}
+
+fn render_one(
+    error: &CompileError,
+    path: &str,
+    read: &dyn Fn(&str) -> Option<String>,
+    color: bool,
+) -> String {
+    let mut out = format!("{} {}\n", paint("error:", RED, color), error);
+    let Some((parse, path)) = located(error, path) else {
+        return out;
+    };
+    let at = read(path)
+        .and_then(|source| snippet(&mut out, &source, path, parse.pos(), color))
+        .flatten();
+    if let Some(hint) = hint(parse, at) {
+        let gutter = " ".repeat(parse.pos().line.to_string().len());
+        let _ = writeln!(out, "{} {} hint: {}", gutter, paint("=", BLUE, color), hint);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse;
+
+    /// `source` rendered as `main.bf`, with color off.
+    fn rendered(source: &str) -> String {
+        let error = CompileError::Parse(parse(source).unwrap_err());
+        render(&error, "main.bf", |_| Some(source.to_string()), false)
+    }
+
+    #[test]
+    fn unmatched_bracket() {
+        assert_eq!(
+            rendered("+\n+.]"),
+            "\
+error: parse error: unmatched bracket at 2:3
+ --> main.bf:2:3
+  |
+2 | +.]
+  |   ^
+  = hint: this ']' has no '[' to close; did you forget one, or end a proc before it?
+"
+        );
+    }
+
+    #[test]
+    fn caret_follows_tabs() {
+        let out = rendered("\t+a");
+        assert!(out.contains("1 | \t+a\n  | \t ^\n"), "{}", out);
+        assert!(
+            out.contains("hint: letters and digits are reserved"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn without_source() {
+        let error = CompileError::Parse(parse("@p;").unwrap_err());
+        assert_eq!(
+            render(&error, "main.bf", |_| None, false),
+            "error: parse error: proc 'p' is never defined, called at 1:1\n  = hint: define it with @p{ ... }\n"
+        );
+        let error = CompileError::Verify("bad".to_string());
+        assert_eq!(
+            render(&error, "main.bf", |_| None, false),
+            "error: generated invalid IR: bad\n"
+        );
+    }
+
+    #[test]
+    fn several_errors() {
+        let errors = CompileError::Errors(vec![
+            CompileError::Jit("one".to_string()),
+            CompileError::Jit("two".to_string()),
+        ]);
+        assert_eq!(
+            render(&errors, "main.bf", |_| None, false),
+            "error: can't run in the JIT: one\n\nerror: can't run in the JIT: two\n\n2 errors emitted\n"
+        );
+    }
+
+    #[test]
+    fn color() {
+        let error = CompileError::Parse(parse("]").unwrap_err());
+        let out = render(&error, "main.bf", |_| Some("]".to_string()), true);
+        assert!(out.starts_with("\x1b[1;31merror:\x1b[0m "), "{:?}", out);
+        assert!(out.contains("\x1b[1;31m^\x1b[0m"), "{:?}", out);
+        assert!(!rendered("]").contains('\x1b'));
+    }
+
+    #[test]
+    fn byte_order_mark_and_crlf() {
+        let out = rendered("\u{FEFF}+]\r\n");
+        assert!(out.contains("1 | +]\n  |  ^\n"), "{:?}", out);
+        let out = rendered("+\r\n+]\r\n");
+        assert!(out.contains("2 | +]\n  |  ^\n"), "{:?}", out);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::lexer::{ParseError, Position};
+
+/// The eight BF commands, in the order `--map` lists them.
+const COMMANDS: [char; 8] = ['>', '<', '+', '-', '.', ',', '[', ']'];
+
+/// Turns the source of some language into plain BF for the lexer, so the
+/// rest of the pipeline doesn't need to know about dialects.
+pub trait Frontend {
+    fn translate(&self, source: &str) -> Result<String, ParseError>;
+}
+
+/// BF itself, passed through as is.
+#[derive(Clone, Copy, Debug)]
+pub struct Brainfuck;
+
+impl Frontend for Brainfuck {
+    fn translate(&self, source: &str) -> Result<String, ParseError> {
+        Ok(source.to_string())
+    }
+}
+
+/// Ook! and its relatives, where each command is a pair of `word.`,
+/// `word?` or `word!` tokens. Anything between tokens is a comment.
+#[derive(Clone, Copy, Debug)]
+pub struct Ook {
+    word: &'static str,
+}
+
+impl Ook {
+    pub const OOK: Ook = Ook { word: "Ook" };
+    pub const BLUB: Ook = Ook { word: "Blub" };
+}
+
+impl Frontend for Ook {
+    fn translate(&self, source: &str) -> Result<String, ParseError> {
+        // Each token's punctuation and where the token starts.
+        let tokens: Vec<(char, usize)> = source
+            .match_indices(self.word)
+            .filter_map(|(i, _)| {
+                let mark = source[i + self.word.len()..].chars().next()?;
+                matches!(mark, '.' | '?' | '!').then_some((mark, i))
+            })
+            .collect();
+        let mut out = String::new();
+        for pair in tokens.chunks(2) {
+            let pos = Position::after(source[..pair[0].1].chars());
+            let command = match pair {
+                [('.', _), ('?', _)] => '>',
+                [('?', _), ('.', _)] => '<',
+                [('.', _), ('.', _)] => '+',
+                [('!', _), ('!', _)] => '-',
+                [('!', _), ('.', _)] => '.',
+                [('.', _), ('!', _)] => ',',
+                [('!', _), ('?', _)] => '[',
+                [('?', _), ('!', _)] => ']',
+                _ => {
+                    let token = pair
+                        .iter()
+                        .map(|(mark, _)| format!("{}{}", self.word, mark))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    return Err(ParseError::InvalidToken { token, pos });
+                }
+            };
+            out.push(command);
+        }
+        Ok(out)
+    }
+}
+
+/// A dialect that renames each BF command to another single character.
+/// Everything else is a comment.
+#[derive(Clone, Debug)]
+pub struct Substitution {
+    map: HashMap<char, char>,
+}
+
+impl Substitution {
+    /// Parses a `--map` spec, `><+-.,[]=` followed by the eight characters
+    /// that stand for those commands, in that order. `None` if it isn't one.
+    pub fn new(spec: &str) -> Option<Self> {
+        let (commands, chars) = spec.split_at(spec.find('=')?);
+        let chars: Vec<char> = chars[1..].chars().collect();
+        if commands.chars().ne(COMMANDS) || chars.len() != 8 {
+            return None;
+        }
+        let map: HashMap<char, char> = chars.into_iter().zip(COMMANDS).collect();
+        // Two commands sharing a character would be ambiguous.
+        (map.len() == 8).then_some(Substitution { map })
+    }
+}
+
+impl Frontend for Substitution {
+    fn translate(&self, source: &str) -> Result<String, ParseError> {
+        Ok(source.chars().filter_map(|c| self.map.get(&c)).collect())
+    }
+}
+
+/// Which frontend reads the source.
+#[derive(Clone, Debug)]
+pub enum Dialect {
+    Brainfuck,
+    Ook(Ook),
+    Map(Substitution),
+}
+
+impl Dialect {
+    /// Picks the dialect by file extension, BF for anything unknown.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("ook") => Dialect::Ook(Ook::OOK),
+            Some("blub") => Dialect::Ook(Ook::BLUB),
+            _ => Dialect::Brainfuck,
+        }
+    }
+
+    /// Looks a dialect up by its `--dialect` name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bf" => Some(Dialect::Brainfuck),
+            "ook" => Some(Dialect::Ook(Ook::OOK)),
+            "blub" => Some(Dialect::Ook(Ook::BLUB)),
+            _ => None,
+        }
+    }
+
+    pub fn frontend(&self) -> &dyn Frontend {
+        match self {
+            Dialect::Brainfuck => &Brainfuck,
+            Dialect::Ook(ook) => ook,
+            Dialect::Map(map) => map,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ook() {
+        let source =
+            "Ook. Ook. Ook! Ook? Ook. Ook?\nOok. Ook. Ook? Ook. Ook! Ook! Ook? Ook! Ook! Ook.";
+        assert_eq!(Ook::OOK.translate(source), Ok("+[>+<-].".to_string()));
+        // Anything between tokens is a comment.
+        let blub = "Blub. hello Blub? -- Blub.\tBlub.";
+        assert_eq!(Ook::BLUB.translate(blub), Ok(">+".to_string()));
+    }
+
+    #[test]
+    fn ook_invalid_tokens() {
+        assert_eq!(
+            Ook::OOK.translate("Ook. Ook.\n  Ook? Ook?"),
+            Err(ParseError::InvalidToken {
+                token: "Ook? Ook?".to_string(),
+                pos: Position { line: 2, col: 3 },
+            })
+        );
+        assert_eq!(
+            Ook::OOK.translate("Ook. Ook. Ook!"),
+            Err(ParseError::InvalidToken {
+                token: "Ook!".to_string(),
+                pos: Position { line: 1, col: 11 },
+            })
+        );
+    }
+
+    #[test]
+    fn substitution() {
+        let map = Substitution::new("><+-.,[]=ABCDEFGH").unwrap();
+        assert_eq!(map.translate("CGAC BDH E!"), Ok("+[>+<-].".to_string()));
+        // Wrong commands, too few characters, or a character used twice.
+        assert!(Substitution::new("<>+-.,[]=ABCDEFGH").is_none());
+        assert!(Substitution::new("><+-.,[]=ABCDEFG").is_none());
+        assert!(Substitution::new("><+-.,[]=ABCDEFGA").is_none());
+        assert!(Substitution::new("ABCDEFGH").is_none());
+    }
+
+    #[test]
+    fn picks_dialect() {
+        assert!(matches!(Dialect::from_path("a/b.ook"), Dialect::Ook(ook) if ook.word == "Ook"));
+        assert!(matches!(Dialect::from_path("b.blub"), Dialect::Ook(ook) if ook.word == "Blub"));
+        assert!(matches!(Dialect::from_path("b.bf"), Dialect::Brainfuck));
+        assert!(matches!(Dialect::from_path("ook"), Dialect::Brainfuck));
+        assert!(matches!(Dialect::from_name("blub"), Some(Dialect::Ook(_))));
+        assert!(Dialect::from_name("ook!").is_none());
+        let source = "+[-]@p{.}";
+        assert_eq!(
+            Dialect::Brainfuck.frontend().translate(source),
+            Ok(source.to_string())
+        );
+    }
+}
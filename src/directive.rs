@@ -0,0 +1,168 @@
+use crate::{CompileOptions, OutputEncoding, TapeStorage};
+
+/// The settings on a `#!` line at the top of `source`, like
+/// `#!cells=30000 cell-size=16`, or `None` without one. A shebang, like
+/// `#!/usr/bin/env bf`, has none either. The lexer skips the line.
+pub fn read(source: &str) -> Option<&str> {
+    let source = source.strip_prefix('\u{FEFF}').unwrap_or(source);
+    let line = source.strip_prefix("#!")?.lines().next().unwrap_or("");
+    (!line.trim_start().starts_with('/')).then_some(line)
+}
+
+/// Applies the settings in `line`, separated by spaces, to `options`. Each
+/// is the name of a command line flag without the `--` and its value after
+/// a `=`, or just the name for a flag that takes none, and does what the
+/// flag would. Settings for which `given` is true, because the flag was
+/// passed too, are left out. Returns a warning for each setting that isn't
+/// known or has a bad value, which are ignored.
+pub fn apply(
+    line: &str,
+    options: &mut CompileOptions,
+    given: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for setting in line.split_whitespace() {
+        let (key, value) = match setting.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (setting, None),
+        };
+        if given(key) {
+            continue;
+        }
+        if let Err(expected) = set(options, key, value) {
+            warnings.push(match expected {
+                Some(expected) => format!("'{}' takes {}, ignoring '{}'", key, expected, setting),
+                None => format!("unknown setting '{}' ignored", key),
+            });
+        }
+    }
+    warnings
+}
+
+/// Sets `key` to `value`, or returns what it takes instead, `None` for a
+/// key that isn't a setting.
+fn set(
+    options: &mut CompileOptions,
+    key: &str,
+    value: Option<&str>,
+) -> Result<(), Option<&'static str>> {
+    let number = |expected| value.and_then(|v| v.parse().ok()).ok_or(Some(expected));
+    let flag = match value {
+        None | Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(_) => Err(Some("no value, true or false")),
+    };
+    match key {
+        "cells" => match number("a positive number")? {
+            0 => return Err(Some("a positive number")),
+            cells => options.cells = cells,
+        },
+        "cell-size" => {
+            options.cell_bits = match value {
+                Some("8") => 8,
+                Some("16") => 16,
+                Some("32") => 32,
+                _ => return Err(Some("8, 16 or 32")),
+            }
+        }
+        "start-cell" => options.start_cell = number("a cell index")?,
+        "print-cells" => options.print_cells = number("a number of cells")?,
+        "max-steps" => options.max_steps = number("a number")?,
+        "output" => {
+            options.output_encoding = match value {
+                Some("byte") => OutputEncoding::Byte,
+                Some("utf8") => OutputEncoding::Utf8,
+                _ => return Err(Some("byte or utf8")),
+            }
+        }
+        "tape" => {
+            options.tape = match value {
+                Some("heap") => TapeStorage::Heap,
+                Some("stack") => TapeStorage::Stack,
+                Some("global") => TapeStorage::Global,
+                _ => return Err(Some("heap, stack or global")),
+            }
+        }
+        "signed-cells" => options.signed_cells = flag?,
+        "wrap-pointer" => options.wrap_pointer = flag?,
+        "grow" => options.grow = flag?,
+        "exit-cell" => options.exit_cell = flag?,
+        "strict" => options.strict = flag?,
+        "debug-ops" => options.debug_ops = flag?,
+        "loop-hints" => options.loop_hints = flag?,
+        "flush-on-input" => options.flush_on_input = flag?,
+        _ => return Err(None),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_settings_line() {
+        assert_eq!(read("#!cells=30000 grow\n+."), Some("cells=30000 grow"));
+        assert_eq!(read("\u{FEFF}#!cell-size=16"), Some("cell-size=16"));
+        assert_eq!(read("#!/usr/bin/env bf\n+."), None);
+        assert_eq!(read("+.\n#!cells=10"), None);
+        // The lexer skips the line, letters and all.
+        let ops = crate::lexer::parse("#!cells=30000 grow\n+.").unwrap();
+        assert_eq!(
+            ops,
+            [crate::ops::Op::ValueInc(1), crate::ops::Op::Output(1)]
+        );
+    }
+
+    #[test]
+    fn applies_settings() {
+        let mut options = CompileOptions::default();
+        let warnings = apply(
+            "cells=30000 cell-size=16 grow output=utf8 tape=global signed-cells=false",
+            &mut options,
+            |_| false,
+        );
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(options.cells, 30000);
+        assert_eq!(options.cell_bits, 16);
+        assert!(options.grow);
+        assert!(!options.signed_cells);
+        assert_eq!(options.output_encoding, OutputEncoding::Utf8);
+        assert_eq!(options.tape, TapeStorage::Global);
+    }
+
+    #[test]
+    fn flags_win() {
+        let mut options = CompileOptions {
+            cells: 5,
+            ..Default::default()
+        };
+        let warnings = apply("cells=30000 exit-cell", &mut options, |key| key == "cells");
+        assert!(warnings.is_empty());
+        assert_eq!(options.cells, 5);
+        assert!(options.exit_cell);
+    }
+
+    #[test]
+    fn bad_settings_are_ignored() {
+        let mut options = CompileOptions::default();
+        let warnings = apply(
+            "eof=zero cells=0 cell-size=12 grow=yes strict",
+            &mut options,
+            |_| false,
+        );
+        assert_eq!(
+            warnings,
+            [
+                "unknown setting 'eof' ignored",
+                "'cells' takes a positive number, ignoring 'cells=0'",
+                "'cell-size' takes 8, 16 or 32, ignoring 'cell-size=12'",
+                "'grow' takes no value, true or false, ignoring 'grow=yes'",
+            ]
+        );
+        assert_eq!(options.cells, CompileOptions::default().cells);
+        assert_eq!(options.cell_bits, 8);
+        assert!(!options.grow);
+        assert!(options.strict);
+    }
+}
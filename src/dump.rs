@@ -0,0 +1,489 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::lexer::Position;
+use crate::ops::Op;
+
+/// Names the format, so other JSON fails loudly rather than as no ops.
+const FORMAT: &str = "bf-ops";
+/// Bumped whenever a dump from an older compiler would mean something
+/// else, like when an op changes meaning.
+pub const VERSION: u64 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum DumpError {
+    /// The text isn't JSON, or not of the expected shape.
+    #[error("bad op dump: expected {expected} at {pos}")]
+    Syntax {
+        expected: &'static str,
+        pos: Position,
+    },
+    /// JSON, but not an op dump.
+    #[error("not an op dump, no \"format\": \"{}\"", FORMAT)]
+    NotADump,
+    /// A dump in another version of the format.
+    #[error("op dump is version {0}, but this compiler reads version {}", VERSION)]
+    Version(u64),
+    /// An op this compiler doesn't know, or one with the wrong argument.
+    #[error("bad op '{name}' at op {op}")]
+    UnknownOp { name: String, op: usize },
+    /// The ops don't form a program: a bracket or proc that isn't matched,
+    /// or a proc that is defined twice or never.
+    #[error("{reason} at op {op}")]
+    Invalid { reason: String, op: usize },
+}
+
+/// Writes `ops` as JSON, one op a line, each as serde would write an
+/// externally tagged enum: `"Input"`, `{"ValueInc":3}`, `{"Print":[104,105]}`.
+pub fn to_json(ops: &[Op]) -> String {
+    let mut out = format!(
+        "{{\"format\":\"{}\",\"version\":{},\"ops\":[",
+        FORMAT, VERSION
+    );
+    for (i, op) in ops.iter().enumerate() {
+        out.push_str(if i == 0 { "\n  " } else { ",\n  " });
+        let (name, value) = match op {
+            Op::PointerInc(v) => ("PointerInc", v.to_string()),
+            Op::PointerDec(v) => ("PointerDec", v.to_string()),
+            Op::ValueInc(v) => ("ValueInc", v.to_string()),
+            Op::ValueDec(v) => ("ValueDec", v.to_string()),
+            Op::Output(v) => ("Output", v.to_string()),
+            Op::ScanInc(v) => ("ScanInc", v.to_string()),
+            Op::ScanDec(v) => ("ScanDec", v.to_string()),
+            Op::Set(v) => ("Set", v.to_string()),
+            Op::Clear(v) => ("Clear", v.to_string()),
+            Op::Print(bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(u8::to_string).collect();
+                ("Print", format!("[{}]", bytes.join(",")))
+            }
+            Op::ProcDef(name) => ("ProcDef", quote(name)),
+            Op::ProcCall(name) => ("ProcCall", quote(name)),
+            Op::Input => ("Input", String::new()),
+            Op::LLoop => ("LLoop", String::new()),
+            Op::RLoop => ("RLoop", String::new()),
+            Op::ProcEnd => ("ProcEnd", String::new()),
+            Op::DumpTape => ("DumpTape", String::new()),
+        };
+        if value.is_empty() {
+            out.push_str(&quote(name));
+        } else {
+            let _ = write!(out, "{{{}:{}}}", quote(name), value);
+        }
+    }
+    out.push_str("\n]}\n");
+    out
+}
+
+/// `s` as a JSON string.
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON value. Numbers are only ever counts and bytes here, so anything
+/// but a non-negative integer is rejected.
+enum Json {
+    /// `null`, `true` or `false`, which no op takes.
+    Literal,
+    Int(u64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct Parser<'s> {
+    text: &'s str,
+    ptr: usize,
+}
+
+impl<'s> Parser<'s> {
+    fn error(&self, expected: &'static str) -> DumpError {
+        DumpError::Syntax {
+            expected,
+            pos: Position::after(self.text[..self.ptr].chars()),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.ptr..];
+        self.ptr += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.ptr..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.ptr += c.len_utf8();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char, expected: &'static str) -> Result<(), DumpError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(expected))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, DumpError> {
+        self.skip_whitespace();
+        let rest = &self.text[self.ptr..];
+        for word in ["null", "true", "false"] {
+            if rest.starts_with(word) {
+                self.ptr += word.len();
+                return Ok(Json::Literal);
+            }
+        }
+        match self.peek() {
+            Some('"') => self.string().map(Json::Str),
+            Some('[') => {
+                self.ptr += 1;
+                let mut items = Vec::new();
+                if !self.eat(']') {
+                    loop {
+                        items.push(self.value()?);
+                        if self.eat(']') {
+                            break;
+                        }
+                        self.expect(',', "',' or ']'")?;
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some('{') => {
+                self.ptr += 1;
+                let mut fields = Vec::new();
+                if !self.eat('}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(':', "':'")?;
+                        fields.push((key, self.value()?));
+                        if self.eat('}') {
+                            break;
+                        }
+                        self.expect(',', "',' or '}'")?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some('0'..='9') => {
+                let digits =
+                    rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                let value = rest[..digits]
+                    .parse()
+                    .map_err(|_| self.error("a smaller number"))?;
+                self.ptr += digits;
+                if matches!(self.peek(), Some('.' | 'e' | 'E')) {
+                    return Err(self.error("a whole number"));
+                }
+                Ok(Json::Int(value))
+            }
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, DumpError> {
+        if self.peek() != Some('"') {
+            return Err(self.error("a string"));
+        }
+        self.ptr += 1;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("'\"'"))?;
+            self.ptr += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escaped = self.peek().ok_or_else(|| self.error("an escape"))?;
+                    self.ptr += escaped.len_utf8();
+                    out.push(match escaped {
+                        '"' | '\\' | '/' => escaped,
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => {
+                            let hex = self.text.get(self.ptr..self.ptr + 4);
+                            let code = hex
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("four hex digits"))?;
+                            self.ptr += 4;
+                            code
+                        }
+                        _ => return Err(self.error("an escape")),
+                    });
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+/// Reads back ops written by `to_json`, or by another tool in the same
+/// format. The ops aren't checked; see `validate`.
+pub fn from_json(text: &str) -> Result<Vec<Op>, DumpError> {
+    let mut parser = Parser { text, ptr: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.ptr < text.len() {
+        return Err(parser.error("the end"));
+    }
+    let Json::Object(fields) = value else {
+        return Err(DumpError::NotADump);
+    };
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, v)| v);
+    if !matches!(field("format"), Some(Json::Str(format)) if format == FORMAT) {
+        return Err(DumpError::NotADump);
+    }
+    match field("version") {
+        Some(Json::Int(VERSION)) => {}
+        Some(Json::Int(version)) => return Err(DumpError::Version(*version)),
+        _ => return Err(DumpError::NotADump),
+    }
+    let Some(Json::Array(ops)) = field("ops") else {
+        return Err(DumpError::NotADump);
+    };
+    ops.iter()
+        .enumerate()
+        .map(|(i, op)| decode(op, i))
+        .collect()
+}
+
+/// The op `value` stands for, the `i`th in the dump.
+fn decode(value: &Json, i: usize) -> Result<Op, DumpError> {
+    let unknown = |name: &str| DumpError::UnknownOp {
+        name: name.to_string(),
+        op: i,
+    };
+    let (name, arg) = match value {
+        Json::Str(name) => (name.as_str(), None),
+        Json::Object(fields) if fields.len() == 1 => (fields[0].0.as_str(), Some(&fields[0].1)),
+        _ => return Err(unknown("?")),
+    };
+    let count = |op: fn(usize) -> Op| match arg {
+        Some(Json::Int(v)) => usize::try_from(*v).map(op).map_err(|_| unknown(name)),
+        _ => Err(unknown(name)),
+    };
+    let text = |op: fn(String) -> Op| match arg {
+        Some(Json::Str(s)) => Ok(op(s.clone())),
+        _ => Err(unknown(name)),
+    };
+    match (name, arg) {
+        ("Input", None) => Ok(Op::Input),
+        ("LLoop", None) => Ok(Op::LLoop),
+        ("RLoop", None) => Ok(Op::RLoop),
+        ("ProcEnd", None) => Ok(Op::ProcEnd),
+        ("DumpTape", None) => Ok(Op::DumpTape),
+        ("PointerInc", _) => count(Op::PointerInc),
+        ("PointerDec", _) => count(Op::PointerDec),
+        ("ValueInc", _) => count(Op::ValueInc),
+        ("ValueDec", _) => count(Op::ValueDec),
+        ("Output", _) => count(Op::Output),
+        ("ScanInc", _) => count(Op::ScanInc),
+        ("ScanDec", _) => count(Op::ScanDec),
+        ("Clear", _) => count(Op::Clear),
+        ("Set", Some(Json::Int(v))) => u8::try_from(*v).map(Op::Set).map_err(|_| unknown(name)),
+        ("Print", Some(Json::Array(bytes))) => bytes
+            .iter()
+            .map(|b| match b {
+                Json::Int(b) => u8::try_from(*b).ok(),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .map(Op::Print)
+            .ok_or_else(|| unknown(name)),
+        ("ProcDef", _) => text(Op::ProcDef),
+        ("ProcCall", _) => text(Op::ProcCall),
+        _ => Err(unknown(name)),
+    }
+}
+
+/// Checks what the lexer would have for ops that didn't come from it:
+/// brackets match within each proc body, every proc body is closed, and
+/// every proc called is defined exactly once.
+pub fn validate(ops: &[Op]) -> Result<(), DumpError> {
+    let invalid = |reason: String, op: usize| Err(DumpError::Invalid { reason, op });
+    let mut defined = HashSet::new();
+    // Open brackets, and how many were open when each proc body started.
+    let mut open = Vec::new();
+    let mut floors = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        let floor = floors.last().map_or(0, |(floor, _)| *floor);
+        match op {
+            Op::LLoop => open.push(i),
+            Op::RLoop if open.len() == floor => {
+                return invalid("unmatched RLoop".to_string(), i);
+            }
+            Op::RLoop => {
+                open.pop();
+            }
+            Op::ProcDef(name) => {
+                if !defined.insert(name) {
+                    return invalid(format!("proc '{}' defined again", name), i);
+                }
+                floors.push((open.len(), i));
+            }
+            Op::ProcEnd if floors.is_empty() => {
+                return invalid("ProcEnd outside a proc".to_string(), i);
+            }
+            Op::ProcEnd if open.len() > floor => {
+                return invalid("unmatched LLoop".to_string(), open[open.len() - 1]);
+            }
+            Op::ProcEnd => {
+                floors.pop();
+            }
+            _ => {}
+        }
+    }
+    if let Some(&(_, def)) = floors.last() {
+        return invalid("proc never closed".to_string(), def);
+    }
+    if let Some(&start) = open.last() {
+        return invalid("unmatched LLoop".to_string(), start);
+    }
+    match ops
+        .iter()
+        .enumerate()
+        .find(|(_, op)| matches!(op, Op::ProcCall(name) if !defined.contains(name)))
+    {
+        Some((i, Op::ProcCall(name))) => invalid(format!("proc '{}' is never defined", name), i),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Op::*;
+
+    #[test]
+    fn round_trip() {
+        let ops = vec![
+            PointerInc(3),
+            PointerDec(1),
+            ValueInc(255),
+            ValueDec(1000),
+            Output(2),
+            Input,
+            ScanInc(1),
+            ScanDec(4),
+            Set(0),
+            Set(255),
+            Clear(7),
+            Print(vec![]),
+            Print(vec![0, 104, 255]),
+            ProcDef("p".to_string()),
+            LLoop,
+            DumpTape,
+            RLoop,
+            ProcEnd,
+            ProcCall("p".to_string()),
+            ProcDef("q\"\\\n\u{e9}".to_string()),
+            ProcEnd,
+        ];
+        let json = to_json(&ops);
+        assert_eq!(from_json(&json), Ok(ops));
+        assert_eq!(from_json(&to_json(&[])), Ok(vec![]));
+    }
+
+    #[test]
+    fn format() {
+        let json = to_json(&[ValueInc(2), Input, Print(vec![104, 105])]);
+        assert_eq!(
+            json,
+            "{\"format\":\"bf-ops\",\"version\":1,\"ops\":[\n  {\"ValueInc\":2},\n  \"Input\",\n  {\"Print\":[104,105]}\n]}\n"
+        );
+    }
+
+    #[test]
+    fn other_json_is_rejected() {
+        assert_eq!(from_json("[]"), Err(DumpError::NotADump));
+        assert_eq!(
+            from_json(r#"{"format":"other","version":1,"ops":[]}"#),
+            Err(DumpError::NotADump)
+        );
+        assert_eq!(
+            from_json(r#"{"format":"bf-ops","version":2,"ops":[]}"#),
+            Err(DumpError::Version(2))
+        );
+        assert_eq!(
+            from_json(r#"{"format":"bf-ops","version":1,"ops":["Input",{"Set":256}]}"#),
+            Err(DumpError::UnknownOp {
+                name: "Set".to_string(),
+                op: 1,
+            })
+        );
+        assert_eq!(
+            from_json(r#"{"format":"bf-ops","version":1,"ops":[{"Jump":1}]}"#),
+            Err(DumpError::UnknownOp {
+                name: "Jump".to_string(),
+                op: 0,
+            })
+        );
+        assert_eq!(
+            from_json("{\"format\":\n  \"bf-ops\" \"version\"}"),
+            Err(DumpError::Syntax {
+                expected: "',' or '}'",
+                pos: Position { line: 2, col: 12 },
+            })
+        );
+    }
+
+    #[test]
+    fn validates_programs() {
+        let invalid = |ops: &[Op]| match validate(ops) {
+            Err(DumpError::Invalid { reason, op }) => (reason, op),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            validate(&[ProcCall("p".into()), ProcDef("p".into()), ProcEnd]),
+            Ok(())
+        );
+        assert_eq!(invalid(&[Input, RLoop]), ("unmatched RLoop".to_string(), 1));
+        assert_eq!(
+            invalid(&[LLoop, ProcDef("p".into()), RLoop, ProcEnd, RLoop]),
+            ("unmatched RLoop".to_string(), 2)
+        );
+        assert_eq!(
+            invalid(&[ProcEnd]),
+            ("ProcEnd outside a proc".to_string(), 0)
+        );
+        assert_eq!(
+            invalid(&[ProcDef("p".into()), ProcEnd, ProcDef("p".into()), ProcEnd]),
+            ("proc 'p' defined again".to_string(), 2)
+        );
+        assert_eq!(
+            invalid(&[Input, ProcDef("p".into())]),
+            ("proc never closed".to_string(), 1)
+        );
+        assert_eq!(
+            invalid(&[LLoop, LLoop, RLoop]),
+            ("unmatched LLoop".to_string(), 0)
+        );
+        assert_eq!(
+            invalid(&[Input, ProcCall("q".into())]),
+            ("proc 'q' is never defined".to_string(), 1)
+        );
+    }
+}
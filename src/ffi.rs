@@ -0,0 +1,164 @@
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use inkwell::OptimizationLevel;
+
+use crate::{compile_to_object, CompileError, CompileOptions};
+
+/// A null or non-UTF-8 argument.
+pub const BF_ERROR_ARGUMENT: i32 = -1;
+/// The source doesn't parse.
+pub const BF_ERROR_PARSE: i32 = -2;
+/// Anything else that can go wrong compiling, like an unknown target.
+pub const BF_ERROR_COMPILE: i32 = -3;
+/// The compiler panicked, which is a bug.
+pub const BF_ERROR_INTERNAL: i32 = -4;
+
+/// The options C callers can set, with the same meaning as the fields of
+/// `CompileOptions` they are named after. Flags are 0 or 1.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BfOptions {
+    pub cells: u64,
+    pub cell_bits: u32,
+    /// 0 to 3, as for `-O`.
+    pub opt_level: u32,
+    pub wrap_pointer: c_int,
+    pub signed_cells: c_int,
+    pub exit_cell: c_int,
+}
+
+impl BfOptions {
+    fn to_options(self) -> Option<CompileOptions> {
+        if self.cells == 0 || !matches!(self.cell_bits, 8 | 16 | 32) {
+            return None;
+        }
+        Some(CompileOptions {
+            cells: self.cells,
+            cell_bits: self.cell_bits,
+            opt_level: match self.opt_level {
+                0 => OptimizationLevel::None,
+                1 => OptimizationLevel::Less,
+                2 => OptimizationLevel::Default,
+                3 => OptimizationLevel::Aggressive,
+                _ => return None,
+            },
+            wrap_pointer: self.wrap_pointer != 0,
+            signed_cells: self.signed_cells != 0,
+            exit_cell: self.exit_cell != 0,
+            ..CompileOptions::default()
+        })
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Remembers `message` for `bf_last_error_message` and returns `code`.
+fn fail(code: i32, message: String) -> i32 {
+    // A message with a NUL in it is cut short there rather than lost.
+    let message = message.split('\0').next().unwrap_or_default();
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    code
+}
+
+/// Fills `opts` with the defaults `bf` uses without flags.
+///
+/// # Safety
+///
+/// `opts` has to point to a writable `BfOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_default_options(opts: *mut BfOptions) {
+    let defaults = CompileOptions::default();
+    if let Some(opts) = opts.as_mut() {
+        *opts = BfOptions {
+            cells: defaults.cells,
+            cell_bits: defaults.cell_bits,
+            opt_level: match defaults.opt_level {
+                OptimizationLevel::None => 0,
+                OptimizationLevel::Less => 1,
+                OptimizationLevel::Default => 2,
+                OptimizationLevel::Aggressive => 3,
+            },
+            wrap_pointer: 0,
+            signed_cells: 0,
+            exit_cell: 0,
+        };
+    }
+}
+
+/// Compiles the NUL-terminated BF program `src` to an object file for the
+/// host, as `compile_to_object` does, with `opts` or the defaults if it is
+/// null. On success stores a buffer to be freed with `bf_free_buffer` in
+/// `out_buf` and its length in `out_len`, and returns 0. Otherwise returns
+/// one of the negative `BF_ERROR_` codes, with the message in
+/// `bf_last_error_message`.
+///
+/// # Safety
+///
+/// `src` has to be a NUL-terminated string, `opts` null or a valid
+/// `BfOptions`, and `out_buf` and `out_len` writable.
+#[no_mangle]
+pub unsafe extern "C" fn bf_compile_to_object(
+    src: *const c_char,
+    opts: *const BfOptions,
+    out_buf: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    if src.is_null() || out_buf.is_null() || out_len.is_null() {
+        return fail(BF_ERROR_ARGUMENT, "null argument".to_string());
+    }
+    let Ok(source) = CStr::from_ptr(src).to_str() else {
+        return fail(BF_ERROR_ARGUMENT, "source isn't UTF-8".to_string());
+    };
+    let options = match opts.as_ref() {
+        None => CompileOptions::default(),
+        Some(opts) => match opts.to_options() {
+            Some(options) => options,
+            None => return fail(BF_ERROR_ARGUMENT, format!("bad options: {:?}", opts)),
+        },
+    };
+    let compiled = panic::catch_unwind(AssertUnwindSafe(|| compile_to_object(source, &options)));
+    match compiled {
+        Ok(Ok(object)) => {
+            let object = object.into_boxed_slice();
+            *out_len = object.len();
+            *out_buf = Box::into_raw(object) as *mut u8;
+            0
+        }
+        Ok(Err(e)) => {
+            let code = match e {
+                CompileError::Parse(_) | CompileError::Errors(_) => BF_ERROR_PARSE,
+                CompileError::Options(_) => BF_ERROR_ARGUMENT,
+                _ => BF_ERROR_COMPILE,
+            };
+            fail(code, e.to_string())
+        }
+        Err(_) => fail(BF_ERROR_INTERNAL, "the compiler panicked".to_string()),
+    }
+}
+
+/// Frees a buffer returned by `bf_compile_to_object`. Null is ignored.
+///
+/// # Safety
+///
+/// `buf` and `len` have to be what `bf_compile_to_object` returned, and
+/// the buffer can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bf_free_buffer(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// The message of the last error on this thread, or null if there hasn't
+/// been one. It stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn bf_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
@@ -0,0 +1,314 @@
+use crate::lexer::PROC_CHARS;
+use crate::ops::Op;
+
+/// Column that lines are wrapped at.
+const WIDTH: usize = 80;
+
+/// Spaces per level of nesting.
+const INDENT: usize = 4;
+
+struct Formatter {
+    out: String,
+    line: String,
+    indent: usize,
+    minify: bool,
+}
+
+impl Formatter {
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.out.push_str(&" ".repeat(self.indent * INDENT));
+            self.out.push_str(&self.line);
+            self.out.push('\n');
+            self.line.clear();
+        }
+    }
+
+    /// Appends `token`, which is never split across lines, wrapping first if
+    /// it wouldn't fit.
+    fn push(&mut self, token: &str) {
+        // `@` followed by a name would lex as a named proc, not the `@`
+        // proc followed by another op.
+        if self.line.ends_with('@') && token.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            self.line.push(' ');
+        }
+        if !self.minify
+            && !self.line.is_empty()
+            && self.indent * INDENT + self.line.chars().count() + token.chars().count() > WIDTH
+        {
+            self.flush();
+        }
+        self.line.push_str(token);
+    }
+
+    /// Appends `count` copies of `ch`. The lexer folds a run up to the first
+    /// other character, so runs are never split across lines, and one right
+    /// after a run of the same character is kept apart with a space.
+    fn push_run(&mut self, ch: char, count: usize) {
+        if self.line.ends_with(ch) {
+            self.line.push(' ');
+        }
+        self.push(&ch.to_string().repeat(count));
+    }
+
+    /// Appends the `+` or `-` run that takes a cell from `from` to `to`.
+    fn push_step(&mut self, from: u8, to: u8) {
+        if to > from {
+            self.push_run('+', (to - from) as usize);
+        } else if to < from {
+            self.push_run('-', (from - to) as usize);
+        }
+    }
+
+    /// Puts `token` on a line of its own and indents what follows.
+    fn open(&mut self, token: &str) {
+        if self.minify {
+            self.push(token);
+            return;
+        }
+        self.flush();
+        self.push(token);
+        self.flush();
+        self.indent += 1;
+    }
+
+    /// Ends an indented block with `token` on a line of its own.
+    fn close(&mut self, token: &str) {
+        if self.minify {
+            self.push(token);
+            return;
+        }
+        self.flush();
+        self.indent -= 1;
+        self.push(token);
+        self.flush();
+    }
+}
+
+/// Whether `name` is written as a single character proc rather than with
+/// `@name{ }`.
+fn is_char_proc(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!((chars.next(), chars.next()), (Some(c), None) if PROC_CHARS.contains(c))
+}
+
+/// Writes `ops` back out as source, in a canonical layout: straight line
+/// code fills lines up to 80 columns, breaking between ops, loops with no
+/// loops or procs inside stay on the line, and other loops and proc bodies
+/// get their brackets on lines of their own and are indented a level. With
+/// `minify` everything goes on one line with no whitespace at all.
+///
+/// Lexing the result gives back `ops`. Ops that only the optimizer makes
+/// are written as the BF they stand for, which lexes to different ops that
+/// do the same.
+pub fn format(ops: &[Op], minify: bool) -> String {
+    let mut f = Formatter {
+        out: String::new(),
+        line: String::new(),
+        indent: 0,
+        minify,
+    };
+    // Whether each open loop is laid out as a block.
+    let mut loops = Vec::new();
+    // How each open proc is closed.
+    let mut procs = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::PointerInc(v) => f.push_run('>', *v),
+            Op::PointerDec(v) => f.push_run('<', *v),
+            Op::ValueInc(v) => f.push_run('+', *v),
+            Op::ValueDec(v) => f.push_run('-', *v),
+            Op::Output(v) => f.push_run('.', *v),
+            Op::Input => f.push(","),
+            Op::LLoop => {
+                let mut depth = 0;
+                let block = ops[i..]
+                    .iter()
+                    .take_while(|op| {
+                        match op {
+                            Op::LLoop => depth += 1,
+                            Op::RLoop => depth -= 1,
+                            _ => {}
+                        }
+                        depth > 0
+                    })
+                    .skip(1)
+                    .any(|op| matches!(op, Op::LLoop | Op::ProcDef(_)));
+                if block {
+                    f.open("[");
+                } else {
+                    f.push("[");
+                }
+                loops.push(block);
+            }
+            Op::RLoop => {
+                if loops.pop().unwrap_or(false) {
+                    f.close("]");
+                } else {
+                    f.push("]");
+                }
+            }
+            Op::ScanInc(v) => f.push(&format!("[{}]", ">".repeat(*v))),
+            Op::ScanDec(v) => f.push(&format!("[{}]", "<".repeat(*v))),
+            Op::Set(v) => {
+                f.push("[-]");
+                f.push_run('+', *v as usize);
+            }
+            Op::Clear(v) => {
+                for cell in 0..*v {
+                    if cell > 0 {
+                        f.push(">");
+                    }
+                    f.push("[-]");
+                }
+                f.push_run('<', v.saturating_sub(1));
+            }
+            // Only partial evaluation makes these, at the start where the
+            // cell is still zero, so each byte is the step from the one
+            // before, ending back at zero.
+            Op::Print(bytes) => {
+                let mut cell = 0;
+                for &byte in bytes {
+                    f.push_step(cell, byte);
+                    f.push(".");
+                    cell = byte;
+                }
+                f.push_step(cell, 0);
+            }
+            Op::ProcDef(name) if is_char_proc(name) => {
+                f.open(name);
+                procs.push(name.clone());
+            }
+            Op::ProcDef(name) => {
+                f.open(&format!("@{}{{", name));
+                procs.push("}".to_string());
+            }
+            Op::ProcCall(name) if is_char_proc(name) => f.push(name),
+            Op::ProcCall(name) => f.push(&format!("@{};", name)),
+            Op::ProcEnd => {
+                let close = procs.pop().unwrap_or_else(|| "}".to_string());
+                f.close(&close);
+            }
+            Op::DumpTape => f.push("#"),
+        }
+    }
+    f.flush();
+    f.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::parse;
+
+    const PROGRAMS: [&str; 6] = [
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/procs.bf"),
+        include_str!("../examples/print_char_proc.bf"),
+        include_str!("../examples/what_is_your_name.bf"),
+        "+[>++[>+++[-]<-]<-]@name{,[.,]}@name;$+$$@$$@@@name;",
+        "\n  + +\t--\r\n..  ",
+    ];
+
+    #[test]
+    fn round_trip() {
+        for source in PROGRAMS {
+            let ops = parse(source).unwrap();
+            for minify in [false, true] {
+                let formatted = format(&ops, minify);
+                assert_eq!(parse(&formatted).unwrap(), ops, "{}", formatted);
+            }
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let ops = parse("++[>+<-]>[[-]>]@p{.}").unwrap();
+        assert_eq!(
+            format(&ops, false),
+            "++[>+<-]>\n[\n    [-]>\n]\n@p{\n    .\n}\n"
+        );
+        assert_eq!(format(&ops, true), "++[>+<-]>[[-]>]@p{.}\n");
+    }
+
+    #[test]
+    fn wraps_at_80_columns() {
+        let ops = parse(&"+>".repeat(100)).unwrap();
+        let formatted = format(&ops, false);
+        assert!(formatted.lines().all(|line| line.len() <= WIDTH));
+        assert_eq!(formatted.lines().count(), 3);
+    }
+
+    #[test]
+    fn runs_stay_apart() {
+        let ops = [Op::ValueInc(2), Op::ValueInc(3), Op::Output(1)];
+        let formatted = format(&ops, true);
+        assert_eq!(formatted, "++ +++.\n");
+        assert_eq!(parse(&formatted).unwrap(), ops);
+    }
+
+    /// A random program of up to `len` ops, with balanced brackets.
+    fn random_program(seed: &mut u64, len: usize) -> String {
+        let mut next = |n: u64| {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 33) % n
+        };
+        let mut out = String::new();
+        let mut open = 0;
+        for _ in 0..next(len as u64) {
+            match next(10) {
+                8 => {
+                    out.push('[');
+                    open += 1;
+                }
+                9 if open > 0 => {
+                    out.push(']');
+                    open -= 1;
+                }
+                i => out.push(b"+-<>+-><.,"[i as usize] as char),
+            }
+        }
+        out.push_str(&"]".repeat(open));
+        out
+    }
+
+    #[test]
+    fn emitted_bf_runs_the_same() {
+        use crate::interpreter::{interpret, Limits, Tape};
+        use crate::optimizer::optimize;
+        use crate::CompileOptions;
+
+        let mut seed = 1;
+        let mut finished = 0;
+        for i in 0..3000 {
+            let source = match PROGRAMS.get(i) {
+                Some(source) => source.to_string(),
+                None => random_program(&mut seed, 60),
+            };
+            for cell_bits in [8, 16] {
+                let options = CompileOptions {
+                    cell_bits,
+                    ..Default::default()
+                };
+                let run = |ops: &[Op], max_steps| {
+                    let limits = Limits {
+                        max_steps: Some(max_steps),
+                    };
+                    interpret(ops, b"in\xff", Tape::of(&options), limits)
+                };
+                let ops = parse(&source).unwrap();
+                let Ok(expected) = run(&ops, 10_000) else {
+                    continue;
+                };
+                // Expanded Set and Print ops can take more steps.
+                let emitted = format(&optimize(ops, &options), true);
+                let output = run(&parse(&emitted).unwrap(), 1_000_000);
+                assert_eq!(output, Ok(expected), "{} became {}", source, emitted);
+                finished += 1;
+            }
+        }
+        assert!(finished > 1000, "only {} programs finished", finished);
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use thiserror::Error;
+
+use crate::ops::Op;
+use crate::{CompileOptions, OutputEncoding};
+
+/// Number of cells on the interpreter's tape.
+pub const TAPE_CELLS: usize = 30_000;
+
+/// Shape of the tape, matching the compiled program's when made with
+/// `Tape::of`.
+#[derive(Clone, Copy, Debug)]
+pub struct Tape {
+    /// Cells the tape starts with.
+    pub cells: usize,
+    /// Cell the pointer starts on.
+    pub start_cell: usize,
+    /// 8, 16 or 32.
+    pub cell_bits: u32,
+    /// Input bytes and output cells are sign-extended.
+    pub signed_cells: bool,
+    /// Moving off either end comes back in at the other.
+    pub wrap_pointer: bool,
+    /// Moving off the right end doubles the tape.
+    pub grow: bool,
+}
+
+impl Default for Tape {
+    /// `TAPE_CELLS` byte cells.
+    fn default() -> Self {
+        Tape {
+            cells: TAPE_CELLS,
+            start_cell: 0,
+            cell_bits: 8,
+            signed_cells: false,
+            wrap_pointer: false,
+            grow: false,
+        }
+    }
+}
+
+impl Tape {
+    /// The tape a program compiled with `options` runs on.
+    pub fn of(options: &CompileOptions) -> Self {
+        Tape {
+            cells: options.cells as usize,
+            start_cell: options.start_cell as usize,
+            cell_bits: options.cell_bits,
+            signed_cells: options.signed_cells,
+            wrap_pointer: options.wrap_pointer,
+            grow: options.grow,
+        }
+    }
+
+    fn mask(&self) -> u32 {
+        match self.cell_bits {
+            32 => u32::MAX,
+            bits => (1 << bits) - 1,
+        }
+    }
+
+    /// The cell value `,` stores for byte `b`.
+    fn read_byte(&self, b: u8) -> u32 {
+        if self.signed_cells {
+            b as i8 as u32 & self.mask()
+        } else {
+            b as u32
+        }
+    }
+
+    /// The output value of byte `b` from a `Print`, which holds byte cell
+    /// values whatever the width of the tape.
+    fn print_byte(&self, b: u8) -> u32 {
+        if self.signed_cells {
+            b as i8 as u32
+        } else {
+            b as u32
+        }
+    }
+
+    /// `v` widened to 32 bits for output, as the compiled program does.
+    fn widen(&self, v: u32) -> u32 {
+        let shift = 32 - self.cell_bits;
+        if self.signed_cells {
+            ((v << shift) as i32 >> shift) as u32
+        } else {
+            v
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Maximum number of ops to execute, `None` for no limit.
+    pub max_steps: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum InterpError {
+    #[error("step limit exceeded")]
+    StepLimitExceeded,
+    #[error("pointer left the tape at op {op}")]
+    PointerOutOfBounds { op: usize },
+    #[error("unmatched bracket at op {op}")]
+    UnmatchedBracket { op: usize },
+    #[error("proc '{name}' is never closed")]
+    UnterminatedProc { name: String },
+    #[error("proc '{name}' is not defined")]
+    UndefinedProc { name: String },
+}
+
+struct Interpreter<'a> {
+    ops: &'a [Op],
+    jumps: Vec<usize>,
+    shape: Tape,
+    tape: Vec<u32>,
+    input: &'a mut dyn Read,
+    output: Vec<u32>,
+    steps: u64,
+    limits: Limits,
+    procs: HashMap<String, (usize, usize)>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn step(&mut self) -> Result<(), InterpError> {
+        self.steps += 1;
+        match self.limits.max_steps {
+            Some(max) if self.steps > max => Err(InterpError::StepLimitExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn move_ptr(
+        &mut self,
+        ptr: usize,
+        count: usize,
+        dec: bool,
+        op: usize,
+    ) -> Result<usize, InterpError> {
+        let len = self.tape.len();
+        if self.shape.wrap_pointer {
+            let step = count % len;
+            return Ok(if dec {
+                (ptr + len - step) % len
+            } else {
+                (ptr + step) % len
+            });
+        }
+        if dec {
+            return ptr
+                .checked_sub(count)
+                .ok_or(InterpError::PointerOutOfBounds { op });
+        }
+        let new = ptr
+            .checked_add(count)
+            .ok_or(InterpError::PointerOutOfBounds { op })?;
+        if new >= len && self.shape.grow {
+            let mut cap = len;
+            while cap <= new {
+                cap *= 2;
+            }
+            self.tape.resize(cap, 0);
+        }
+        if new >= self.tape.len() {
+            return Err(InterpError::PointerOutOfBounds { op });
+        }
+        Ok(new)
+    }
+
+    /// Executes `ops[start..end]` with the data pointer at `ptr`, returning
+    /// where the pointer ends up. Proc calls are kept on a stack of their
+    /// own rather than Rust's, so deep recursion runs into the step limit
+    /// instead of overflowing.
+    fn exec(&mut self, start: usize, mut end: usize, mut ptr: usize) -> Result<usize, InterpError> {
+        // For each call being run: where it was made from, the end of the
+        // code it was made in, and the caller's pointer.
+        let mut calls: Vec<(usize, usize, usize)> = Vec::new();
+        let mut i = start;
+        loop {
+            if i >= end {
+                let Some((call, caller_end, caller_ptr)) = calls.pop() else {
+                    break;
+                };
+                i = call + 1;
+                end = caller_end;
+                ptr = caller_ptr;
+                continue;
+            }
+            self.step()?;
+            match &self.ops[i] {
+                Op::PointerInc(v) => ptr = self.move_ptr(ptr, *v, false, i)?,
+                Op::PointerDec(v) => ptr = self.move_ptr(ptr, *v, true, i)?,
+                Op::ValueInc(v) => {
+                    self.tape[ptr] = self.tape[ptr].wrapping_add(*v as u32) & self.shape.mask()
+                }
+                Op::ValueDec(v) => {
+                    self.tape[ptr] = self.tape[ptr].wrapping_sub(*v as u32) & self.shape.mask()
+                }
+                Op::Output(v) => {
+                    let val = self.shape.widen(self.tape[ptr]);
+                    self.output.resize(self.output.len() + v, val);
+                }
+                Op::Input => {
+                    // EOF, or an error, reads as -1, same as the compiled
+                    // getchar call.
+                    let mut byte = [0xFF];
+                    if !matches!(self.input.read(&mut byte), Ok(1)) {
+                        byte[0] = 0xFF;
+                    }
+                    self.tape[ptr] = self.shape.read_byte(byte[0]);
+                }
+                Op::LLoop => {
+                    if self.tape[ptr] == 0 {
+                        i = self.jumps[i];
+                    }
+                }
+                Op::RLoop => {
+                    if self.tape[ptr] != 0 {
+                        i = self.jumps[i];
+                    }
+                }
+                Op::ScanInc(v) | Op::ScanDec(v) => {
+                    let dec = matches!(self.ops[i], Op::ScanDec(_));
+                    while self.tape[ptr] != 0 {
+                        self.step()?;
+                        ptr = self.move_ptr(ptr, *v, dec, i)?;
+                    }
+                }
+                Op::Set(v) => self.tape[ptr] = *v as u32,
+                Op::Print(bytes) => {
+                    let bytes = bytes.iter().map(|b| self.shape.print_byte(*b));
+                    self.output.extend(bytes);
+                }
+                Op::Clear(v) => {
+                    let end = ptr
+                        .checked_add(*v)
+                        .filter(|end| *end <= self.tape.len())
+                        .ok_or(InterpError::PointerOutOfBounds { op: i })?;
+                    self.tape[ptr..end].fill(0);
+                }
+                // Definitions were all found up front, so just skip the body.
+                Op::ProcDef(name) => i = self.procs[name].1,
+                Op::ProcCall(name) => {
+                    let &(body_start, body_end) = self
+                        .procs
+                        .get(name)
+                        .ok_or_else(|| InterpError::UndefinedProc { name: name.clone() })?;
+                    // Procs get their own copy of the pointer, so the caller's
+                    // position is untouched.
+                    calls.push((i, end, ptr));
+                    i = body_start;
+                    end = body_end;
+                    continue;
+                }
+                // Bodies are run up to their end marker, never through it.
+                Op::ProcEnd => {}
+                Op::DumpTape => eprintln!("{}", dump_tape(&self.tape, ptr, self.shape.cell_bits)),
+            }
+            i += 1;
+        }
+        Ok(ptr)
+    }
+}
+
+/// The cells around `ptr`, in the same format as the compiled `#`.
+pub fn dump_tape(tape: &[u32], ptr: usize, cell_bits: u32) -> String {
+    let digits = cell_bits as usize / 4;
+    let start = ptr.saturating_sub(8);
+    let end = (start + 16).min(tape.len());
+    let cells: String = tape[start..end]
+        .iter()
+        .map(|cell| format!("{:0digits$x} ", cell, digits = digits))
+        .collect();
+    format!(
+        "ptr={}\n{}\n{:indent$}^",
+        ptr,
+        cells,
+        "",
+        indent = (ptr - start) * (digits + 1)
+    )
+}
+
+/// Turns the values written by `.` into bytes the way the compiled program
+/// does: the low byte of each, or with `Utf8` each as a UTF-8 code point,
+/// with U+FFFD for values that aren't one.
+pub fn encode(output: &[u32], encoding: OutputEncoding) -> Vec<u8> {
+    match encoding {
+        OutputEncoding::Byte => output.iter().map(|v| *v as u8).collect(),
+        OutputEncoding::Utf8 => output
+            .iter()
+            .map(|v| char::from_u32(*v).unwrap_or('\u{FFFD}'))
+            .collect::<String>()
+            .into_bytes(),
+    }
+}
+
+/// Finds the body of every proc, as the range between its `ProcDef` and
+/// `ProcEnd`, so procs can be called before their definition.
+fn find_procs(ops: &[Op]) -> Result<HashMap<String, (usize, usize)>, InterpError> {
+    let mut procs = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        let Op::ProcDef(name) = op else {
+            continue;
+        };
+        let mut depth = 0;
+        let close = ops[i..]
+            .iter()
+            .position(|op| {
+                match op {
+                    Op::ProcDef(_) => depth += 1,
+                    Op::ProcEnd => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            })
+            .ok_or_else(|| InterpError::UnterminatedProc { name: name.clone() })?;
+        procs.insert(name.clone(), (i + 1, i + close));
+    }
+    Ok(procs)
+}
+
+fn match_brackets(ops: &[Op]) -> Result<Vec<usize>, InterpError> {
+    let mut jumps = vec![0; ops.len()];
+    let mut stack = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::LLoop => stack.push(i),
+            Op::RLoop => {
+                let start = stack.pop().ok_or(InterpError::UnmatchedBracket { op: i })?;
+                jumps[start] = i;
+                jumps[i] = start;
+            }
+            _ => {}
+        }
+    }
+    match stack.pop() {
+        Some(op) => Err(InterpError::UnmatchedBracket { op }),
+        None => Ok(jumps),
+    }
+}
+
+/// Straightforward reference implementation of the op stream, used to check
+/// the compiler's output against. Runs on a zeroed tape shaped like `tape`
+/// and returns the value of every cell the program wrote, for `encode`.
+pub fn interpret(
+    ops: &[Op],
+    input: &[u8],
+    tape: Tape,
+    limits: Limits,
+) -> Result<Vec<u32>, InterpError> {
+    let mut input = input;
+    let mut interpreter = Interpreter {
+        ops,
+        jumps: match_brackets(ops)?,
+        shape: tape,
+        tape: vec![0; tape.cells],
+        input: &mut input,
+        output: Vec::new(),
+        steps: 0,
+        limits,
+        procs: find_procs(ops)?,
+    };
+    interpreter.exec(0, ops.len(), tape.start_cell)?;
+    Ok(interpreter.output)
+}
+
+/// Interpreter state kept between runs, for the REPL. Each run executes
+/// only the ops it is given, on the tape and pointer the previous runs
+/// left behind, and can call procs they defined.
+pub struct Session {
+    ops: Vec<Op>,
+    shape: Tape,
+    tape: Vec<u32>,
+    ptr: usize,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new(Tape::default())
+    }
+}
+
+impl Session {
+    /// A session on a zeroed tape shaped like `shape`.
+    pub fn new(shape: Tape) -> Self {
+        Session {
+            ops: Vec::new(),
+            shape,
+            tape: vec![0; shape.cells],
+            ptr: shape.start_cell,
+        }
+    }
+
+    pub fn shape(&self) -> Tape {
+        self.shape
+    }
+
+    pub fn tape(&self) -> &[u32] {
+        &self.tape
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// Runs `ops` after everything run so far, with `,` reading from
+    /// `input`, returning what they wrote. If they fail, cells they changed
+    /// stay changed, but the pointer stays put and any procs they defined
+    /// are forgotten.
+    pub fn run(
+        &mut self,
+        ops: Vec<Op>,
+        input: &mut dyn Read,
+        limits: Limits,
+    ) -> Result<Vec<u32>, InterpError> {
+        let start = self.ops.len();
+        self.ops.extend(ops);
+        let result = self.exec_from(start, input, limits);
+        if result.is_err() {
+            self.ops.truncate(start);
+        }
+        result
+    }
+
+    fn exec_from(
+        &mut self,
+        start: usize,
+        input: &mut dyn Read,
+        limits: Limits,
+    ) -> Result<Vec<u32>, InterpError> {
+        let jumps = match_brackets(&self.ops)?;
+        let procs = find_procs(&self.ops)?;
+        let mut interpreter = Interpreter {
+            ops: &self.ops,
+            jumps,
+            shape: self.shape,
+            tape: std::mem::take(&mut self.tape),
+            input,
+            output: Vec::new(),
+            steps: 0,
+            limits,
+            procs,
+        };
+        let result = interpreter.exec(start, self.ops.len(), self.ptr);
+        self.tape = interpreter.tape;
+        self.ptr = result?;
+        Ok(interpreter.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Op::*;
+
+    fn run(ops: &[Op], cell_bits: u32) -> Vec<u32> {
+        let tape = Tape {
+            cell_bits,
+            ..Tape::default()
+        };
+        interpret(ops, b"", tape, Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn cells_wrap() {
+        for (bits, max) in [(8, 0xFF), (16, 0xFFFF), (32, u32::MAX)] {
+            // Below zero to the largest value, and from there up to zero.
+            let ops = [ValueDec(1), Output(1), ValueInc(1), Output(1)];
+            assert_eq!(run(&ops, bits), [max, 0], "{} bit cells", bits);
+        }
+    }
+
+    #[test]
+    fn large_steps_wrap() {
+        let ops = [ValueInc(300), Output(1), ValueDec(301), Output(1)];
+        assert_eq!(run(&ops, 8), [44, 255]);
+        assert_eq!(run(&ops, 16), [300, 0xFFFF]);
+    }
+
+    #[test]
+    fn procs_keep_the_callers_pointer() {
+        let p = || "p".to_string();
+        let ops = [
+            ProcDef(p()),
+            PointerInc(1),
+            ValueInc(1),
+            ProcEnd,
+            ProcCall(p()),
+            ProcCall(p()),
+            Output(1),
+            PointerInc(1),
+            Output(1),
+        ];
+        assert_eq!(run(&ops, 8), [0, 2]);
+    }
+
+    #[test]
+    fn unbounded_recursion_hits_the_step_limit() {
+        let p = || "p".to_string();
+        let ops = [ProcDef(p()), ProcCall(p()), ProcEnd, ProcCall(p())];
+        let limits = Limits {
+            max_steps: Some(1_000_000),
+        };
+        let result = interpret(&ops, b"", Tape::default(), limits);
+        assert_eq!(result, Err(InterpError::StepLimitExceeded));
+    }
+
+    #[test]
+    fn session_keeps_its_shape() {
+        let shape = Tape {
+            cells: 4,
+            start_cell: 1,
+            cell_bits: 16,
+            ..Tape::default()
+        };
+        let mut session = Session::new(shape);
+        let mut input: &[u8] = b"a";
+        let output = session.run(vec![ValueDec(1), Output(1)], &mut input, Limits::default());
+        assert_eq!(output, Ok(vec![0xFFFF]));
+        // `,` reads on from where the last run left off, then hits EOF.
+        let ops = vec![PointerInc(1), Input, Output(1), Input, Output(1)];
+        let output = session.run(ops, &mut input, Limits::default());
+        assert_eq!(output, Ok(vec![b'a' as u32, 0xFF]));
+        assert_eq!(session.tape(), [0, 0xFFFF, 0xFF, 0]);
+        assert_eq!(session.ptr(), 2);
+    }
+}
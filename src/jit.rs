@@ -0,0 +1,162 @@
+use std::cell::RefCell;
+use std::io::{self, Cursor, Read, Write};
+use std::os::raw::{c_int, c_void};
+
+use inkwell::context::Context;
+use inkwell::targets::{InitializationConfig, Target};
+
+use crate::{generate, host_triple, CompileError, CompileOptions};
+
+/// What a program run by `run_jit` wrote to stdout, and the code it
+/// exited with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunResult {
+    pub output: Vec<u8>,
+    pub exit_code: i32,
+}
+
+/// Stdin and stdout of the program running on this thread.
+struct Io {
+    input: Box<dyn Read>,
+    /// What the program wrote, or `None` to write straight to the
+    /// process's stdout.
+    output: Option<Vec<u8>>,
+}
+
+thread_local! {
+    static IO: RefCell<Option<Io>> = const { RefCell::new(None) };
+}
+
+// Stand-ins for the C runtime's functions, which the program's calls are
+// mapped to.
+
+/// Returns -1 at the end of the input or on an error, like `getchar`, so
+/// EOF reads the same as in a linked program.
+extern "C" fn jit_getchar() -> c_int {
+    IO.with(|io| match io.borrow_mut().as_mut() {
+        Some(io) => {
+            // So that a prompt shows up before the program waits for input.
+            if io.output.is_none() {
+                let _ = io::stdout().flush();
+            }
+            let mut byte = [0];
+            match io.input.read_exact(&mut byte) {
+                Ok(()) => byte[0] as c_int,
+                Err(_) => -1,
+            }
+        }
+        None => -1,
+    })
+}
+
+fn write(bytes: &[u8]) {
+    IO.with(|io| match io.borrow_mut().as_mut() {
+        Some(Io {
+            output: Some(output),
+            ..
+        }) => output.extend_from_slice(bytes),
+        Some(Io { output: None, .. }) => {
+            let _ = io::stdout().write_all(bytes);
+        }
+        None => {}
+    });
+}
+
+extern "C" fn jit_putchar(c: c_int) -> c_int {
+    write(&[c as u8]);
+    c & 0xFF
+}
+
+/// Only ever called for stdout, so `stream` isn't looked at.
+extern "C" fn jit_fwrite(buf: *const u8, size: usize, count: usize, _stream: *mut c_void) -> usize {
+    write(unsafe { std::slice::from_raw_parts(buf, size * count) });
+    count
+}
+
+/// Why `opts` can't be run in the JIT, if they can't.
+fn unsupported(opts: &CompileOptions) -> Option<&'static str> {
+    if opts.target_triple() != host_triple() {
+        Some("only the host target can be run")
+    } else if opts.shared {
+        Some("a shared build has no entry point")
+    } else if opts.output_fd.is_some() || opts.input_fd.is_some() {
+        Some("--output-fd and --input-fd would bypass the captured stdin and stdout")
+    } else if opts.freestanding {
+        Some("a freestanding build makes its own system calls")
+    } else if opts.max_steps > 0 {
+        Some("the step limit exits the whole process")
+    } else {
+        None
+    }
+}
+
+/// Compiles `source` and runs it in this process, with `input` as its
+/// stdin and its stdout captured, so tests and embedders don't have to
+/// link and spawn a program. Input data after a `!`, or from
+/// `input_file`, is read instead of `input` as usual, and stderr, for
+/// `trace` and friends, is the process's own.
+///
+/// Options whose code could end the process or get around the capture,
+/// like `max_steps` and the file descriptor options, are an error; use
+/// the interpreter's `Limits` for programs that might not halt.
+pub fn run_jit(
+    source: &str,
+    input: &[u8],
+    opts: &CompileOptions,
+) -> Result<RunResult, CompileError> {
+    let io = Io {
+        input: Box::new(Cursor::new(input.to_vec())),
+        output: Some(Vec::new()),
+    };
+    let (io, exit_code) = execute(source, opts, io)?;
+    Ok(RunResult {
+        output: io.output.unwrap_or_default(),
+        exit_code,
+    })
+}
+
+/// Like `run_jit`, but the program reads the process's stdin and writes to
+/// its stdout as it goes, the way a linked one would, for `--jit`. Returns
+/// the code it exited with.
+pub fn run_jit_stdio(source: &str, opts: &CompileOptions) -> Result<i32, CompileError> {
+    let io = Io {
+        input: Box::new(io::stdin()),
+        output: None,
+    };
+    let (_, exit_code) = execute(source, opts, io)?;
+    let _ = io::stdout().flush();
+    Ok(exit_code)
+}
+
+/// Compiles and runs `source` with `io` as its stdin and stdout, handing
+/// `io` back afterwards.
+fn execute(source: &str, opts: &CompileOptions, io: Io) -> Result<(Io, i32), CompileError> {
+    if let Some(reason) = unsupported(opts) {
+        return Err(CompileError::Jit(reason.to_string()));
+    }
+    let ctx = Context::create();
+    let cdg = generate(&ctx, source, opts)?;
+    cdg.verify()?;
+    Target::initialize_native(&InitializationConfig::default()).map_err(CompileError::Jit)?;
+    let module = cdg.module();
+    let engine = module
+        .create_jit_execution_engine(opts.opt_level)
+        .map_err(|e| CompileError::Jit(e.to_string()))?;
+    let stand_ins = [
+        ("getchar", jit_getchar as *const () as usize),
+        ("putchar", jit_putchar as *const () as usize),
+        ("fwrite", jit_fwrite as *const () as usize),
+    ];
+    for (name, address) in stand_ins {
+        if let Some(f) = module.get_function(name) {
+            engine.add_global_mapping(&f, address);
+        }
+    }
+    let name = opts.entry_symbol.as_deref().unwrap_or("main");
+    let main = unsafe { engine.get_function::<unsafe extern "C" fn() -> i32>(name) }
+        .map_err(|e| CompileError::Jit(format!("can't find {}: {:?}", name, e)))?;
+    IO.with(|cell| cell.replace(Some(io)));
+    let exit_code = unsafe { main.call() };
+    let io = IO.with(|cell| cell.take()).unwrap();
+    Ok((io, exit_code))
+}
@@ -0,0 +1,1130 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::ops::Op;
+
+/// Characters that define and call single character procs. `@` followed by
+/// a letter starts a named proc instead, and with debug ops `#` dumps the
+/// tape.
+pub const PROC_CHARS: &str = "@#$%&*^~=|:;/\\";
+
+/// Line and column in the source, both counted from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Position {
+    /// Where the character following `before` is.
+    pub(crate) fn after(before: impl Iterator<Item = char>) -> Self {
+        let mut pos = Position { line: 1, col: 1 };
+        for c in before {
+            if c == '\n' {
+                pos.line += 1;
+                pos.col = 1;
+            } else {
+                pos.col += 1;
+            }
+        }
+        pos
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Where an op came from: the file, as an index into `Lexer::files`, and
+/// the line and column in it, counted from 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// An op with where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+}
+
+/// A parse error and where it is: the file, as an index into
+/// `Lexer::files`, and the files including that one with where each
+/// `%include` is, innermost first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocatedError {
+    pub error: ParseError,
+    pub file: usize,
+    pub included_from: Vec<(usize, Position)>,
+}
+
+/// Reads an included file, given its path relative to the working
+/// directory.
+pub type Loader = Box<dyn Fn(&Path) -> io::Result<String>>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum ParseError {
+    /// An ASCII letter or digit, which BF++ doesn't allow as a proc
+    /// identifier, or in strict mode any other character that is neither
+    /// an op nor in `PROC_CHARS`.
+    #[error("illegal character {ch:?} at {pos}")]
+    IllegalChar { ch: char, pos: Position },
+    #[error("unmatched bracket at {pos}")]
+    UnmatchedBracket { pos: Position },
+    #[error("proc '{name}' is never defined, called at {pos}")]
+    UndefinedProc { name: String, pos: Position },
+    /// `first` is where the proc was defined before, in `first_file` if
+    /// that was an earlier file than the one being lexed.
+    #[error(
+        "proc '{name}' defined again at {pos}, first at {}{first}",
+        file_prefix(first_file)
+    )]
+    DuplicateProc {
+        name: String,
+        pos: Position,
+        first: Position,
+        first_file: Option<String>,
+    },
+    /// `pos` is where the definition starts.
+    #[error("proc '{name}' opened at {pos} is never closed")]
+    UnterminatedProc { name: String, pos: Position },
+    /// A `@name` not followed by `{` or `;`.
+    #[error("expected '{{' or ';' after proc '{name}' at {pos}")]
+    MissingDelimiter { name: String, pos: Position },
+    /// `[]`, which never ends once entered. Only an error in strict mode.
+    #[error("empty loop at {pos} never ends once entered")]
+    EmptyLoop { pos: Position },
+    /// Something a dialect frontend has no command for.
+    #[error("invalid token '{token}' at {pos}")]
+    InvalidToken { token: String, pos: Position },
+    /// A `%include` not followed by a path in double quotes.
+    #[error("expected a quoted path after %include at {pos}")]
+    MalformedInclude { pos: Position },
+    /// An included file that couldn't be read, or that can't be read at
+    /// all because the lexer has no `Loader`.
+    #[error("can't include {path} at {pos}: {reason}")]
+    IncludeFailed {
+        path: String,
+        pos: Position,
+        reason: String,
+    },
+    /// A file including itself, directly or through other files.
+    #[error("{path} includes itself, through the %include at {pos}")]
+    IncludeCycle { path: String, pos: Position },
+}
+
+/// `file:` for a `DuplicateProc` whose first definition is in another
+/// file, nothing otherwise.
+fn file_prefix(file: &Option<String>) -> String {
+    file.as_ref()
+        .map(|file| format!("{}:", file))
+        .unwrap_or_default()
+}
+
+impl ParseError {
+    /// Where in the source the error is.
+    pub fn pos(&self) -> Position {
+        match self {
+            ParseError::IllegalChar { pos, .. }
+            | ParseError::UnmatchedBracket { pos }
+            | ParseError::UndefinedProc { pos, .. }
+            | ParseError::DuplicateProc { pos, .. }
+            | ParseError::UnterminatedProc { pos, .. }
+            | ParseError::MissingDelimiter { pos, .. }
+            | ParseError::EmptyLoop { pos }
+            | ParseError::InvalidToken { pos, .. }
+            | ParseError::MalformedInclude { pos }
+            | ParseError::IncludeFailed { pos, .. }
+            | ParseError::IncludeCycle { pos, .. } => *pos,
+        }
+    }
+}
+
+/// What `get_op` found next.
+enum Lexed {
+    Op(Spanned<Op>),
+    /// A `%include` of `path`, at `at`.
+    Include {
+        path: String,
+        at: Position,
+    },
+}
+
+/// The state of a file whose lexing is paused while a file it includes is
+/// lexed.
+struct Including {
+    buffer: String,
+    ptr: usize,
+    line: u32,
+    col: u32,
+    file: usize,
+    /// Where the `%include` is.
+    at: Position,
+    open_procs: Vec<(String, bool, usize)>,
+    forward_calls: Vec<(String, usize)>,
+    open: Vec<usize>,
+    floors: Vec<usize>,
+}
+
+/// Lexes ops one at a time as an `Iterator`, checking brackets and procs
+/// as it goes, or all at once with `run`.
+pub struct Lexer {
+    buffer: String,
+    /// Byte offset of the next character in `buffer`.
+    ptr: usize,
+    /// Everything after a `!` separator, fed to the program as its stdin.
+    input: Option<String>,
+    /// Procs whose definitions have started, with the file and position of
+    /// the definition. A proc can be called from its own body, or from a
+    /// proc nested in it.
+    procs: HashMap<String, (usize, Position)>,
+    /// Name of every file lexed so far, for errors and spans. The buffer a
+    /// lexer is created with has no name.
+    files: Vec<String>,
+    /// Index of the file being lexed in `files`.
+    file: usize,
+    /// Files paused at a `%include`, outermost first.
+    including: Vec<Including>,
+    /// Reads included files. Without one `%include` is an error.
+    loader: Option<Loader>,
+    /// Procs being defined, innermost last, whether they use the named
+    /// `@name{ }` syntax and where they start.
+    open_procs: Vec<(String, bool, usize)>,
+    /// Named procs called before their definition, and where, checked once
+    /// the whole buffer has been lexed.
+    forward_calls: Vec<(String, usize)>,
+    /// Where each open bracket in the file is.
+    open: Vec<usize>,
+    /// How many brackets were open when each enclosing proc body started.
+    floors: Vec<usize>,
+    /// Whether the last op was a `[`, to spot empty loops.
+    after_lloop: bool,
+    /// Set at the end of the buffer, or once `max_errors` errors have been
+    /// found, until `next_file`.
+    done: bool,
+    /// Errors found so far, in the order they were found.
+    errors: Vec<LocatedError>,
+    /// How many errors to find before giving up. Lexing carries on past
+    /// the ones before that, to report them all in one go.
+    max_errors: usize,
+    /// How many of `errors` the `Iterator` has returned.
+    yielded: usize,
+    /// An op lexed along with errors, held back by the `Iterator` until
+    /// they have been returned.
+    ready: Option<Spanned<Op>>,
+    /// Line and column of the next character, both counted from 1.
+    line: u32,
+    col: u32,
+    /// Where each op lexed so far came from.
+    spans: Vec<Span>,
+    /// Lex `#` as a tape dump instead of a proc.
+    debug_ops: bool,
+    /// Reject stray characters instead of skipping them as comments.
+    strict: bool,
+    /// Where each `[]` starts, and in which file, reported as warnings
+    /// outside strict mode.
+    empty_loops: Vec<(usize, Position)>,
+}
+
+impl Lexer {
+    pub fn new(source: String) -> Self {
+        Self {
+            buffer: without_bom(source),
+            ptr: 0,
+            input: None,
+            procs: HashMap::new(),
+            files: vec![String::new()],
+            file: 0,
+            including: Vec::new(),
+            loader: None,
+            open_procs: Vec::new(),
+            forward_calls: Vec::new(),
+            open: Vec::new(),
+            floors: Vec::new(),
+            after_lloop: false,
+            done: false,
+            errors: Vec::new(),
+            max_errors: 1,
+            yielded: 0,
+            ready: None,
+            line: 1,
+            col: 1,
+            spans: Vec::new(),
+            debug_ops: false,
+            strict: false,
+            empty_loops: Vec::new(),
+        }
+    }
+
+    /// Makes `#` dump the tape around the pointer, as in many other BF
+    /// implementations, rather than define or call a proc.
+    pub fn set_debug_ops(&mut self, debug_ops: bool) {
+        self.debug_ops = debug_ops;
+    }
+
+    /// Makes characters that are neither ops nor proc characters an error,
+    /// rather than comments, to catch procs defined by a typo. Empty loops
+    /// become an error too.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Keeps lexing after an error until `max_errors` have been found,
+    /// rather than stopping at the first. Illegal characters are skipped,
+    /// and stray brackets and proc delimiters dropped, to carry on.
+    pub fn set_max_errors(&mut self, max_errors: usize) {
+        self.max_errors = max_errors.max(1);
+    }
+
+    /// Whether `c` is skipped as a comment: anything but an op, a proc
+    /// character or an ASCII letter or digit, unless in strict mode.
+    fn is_comment(&self, c: char) -> bool {
+        let special = match c {
+            '}' => matches!(self.open_procs.last(), Some((_, true, _))),
+            _ => "<>+-.,[]!".contains(c) || PROC_CHARS.contains(c) || c.is_ascii_alphanumeric(),
+        };
+        !self.strict && !special
+    }
+
+    /// Lets `%include "path"` pull in other files, read with `loader`.
+    /// Paths are relative to the including file.
+    pub fn set_loader(&mut self, loader: Loader) {
+        self.loader = Some(loader);
+    }
+
+    /// Moves on to the next file of a program split across several, named
+    /// `file` in errors pointing back at it. Procs defined in earlier files
+    /// can be called from it, while loops and proc bodies have to be closed
+    /// in the file they were opened in. Spans carry on from the earlier
+    /// files, one per op.
+    pub fn next_file(&mut self, file: &str, source: String) {
+        self.buffer = without_bom(source);
+        self.ptr = 0;
+        self.after_lloop = false;
+        self.done = false;
+        self.ready = None;
+        self.line = 1;
+        self.col = 1;
+        self.files.push(file.to_string());
+        self.file = self.files.len() - 1;
+    }
+
+    /// Name of every file lexed so far, indexed by `Span::file`.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Takes the errors found so far, with the files they are in.
+    pub fn take_errors(&mut self) -> Vec<LocatedError> {
+        self.yielded = 0;
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Records `error`, in the file being lexed. Once `max_errors` have
+    /// been found it is returned, to stop lexing.
+    fn report(&mut self, error: ParseError) -> Result<(), ParseError> {
+        let included_from = self
+            .including
+            .iter()
+            .rev()
+            .map(|outer| (outer.file, outer.at))
+            .collect();
+        self.errors.push(LocatedError {
+            error: error.clone(),
+            file: self.file,
+            included_from,
+        });
+        if self.errors.len() >= self.max_errors {
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Position of the next character, to remember a proc definition by.
+    fn here(&self) -> (usize, Position) {
+        let pos = Position {
+            line: self.line as usize,
+            col: self.col as usize,
+        };
+        (self.file, pos)
+    }
+
+    /// Line and column of the character at byte `index` in the buffer.
+    fn pos_at(&self, index: usize) -> Position {
+        Position::after(self.buffer[..index.min(self.buffer.len())].chars())
+    }
+
+    fn peek(&self) -> Option<char> {
+        match self.buffer.as_bytes().get(self.ptr) {
+            Some(&b) if b.is_ascii() => Some(b as char),
+            _ => self.buffer[self.ptr..].chars().next(),
+        }
+    }
+
+    /// The character after the next one.
+    fn peek_second(&self) -> Option<char> {
+        self.buffer[self.ptr..].chars().nth(1)
+    }
+
+    fn eat(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.ptr += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    /// Eats a run of the op character `c`. Op characters are ASCII and on
+    /// one line, so this is a scan over the bytes.
+    fn eat_while_same(&mut self, c: char, op: fn(usize) -> Op) -> Op {
+        let count = self.buffer.as_bytes()[self.ptr..]
+            .iter()
+            .take_while(|&&b| b == c as u8)
+            .count();
+        self.ptr += count;
+        self.col += count as u32;
+        op(count)
+    }
+
+    /// Lexes `@name{`, which opens a definition, or `@name;`, which calls it.
+    /// A proc defined again still opens a body, which is lexed as usual.
+    fn named_proc(&mut self) -> Result<Option<Op>, ParseError> {
+        let pos = self.ptr;
+        let def = self.here();
+        self.eat();
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if !c.is_alphanumeric() && c != '_' {
+                break;
+            }
+            name.push(c);
+            self.eat();
+        }
+        match self.peek() {
+            Some('{') => {
+                if let Some(&(file, first)) = self.procs.get(&name) {
+                    self.report(ParseError::DuplicateProc {
+                        first,
+                        first_file: (file != self.file).then(|| self.files[file].clone()),
+                        pos: self.pos_at(pos),
+                        name: name.clone(),
+                    })?;
+                } else {
+                    self.procs.insert(name.clone(), def);
+                }
+                self.eat();
+                self.open_procs.push((name.clone(), true, pos));
+                Ok(Some(Op::ProcDef(name)))
+            }
+            Some(';') => {
+                if !self.procs.contains_key(&name) {
+                    self.forward_calls.push((name.clone(), pos));
+                }
+                self.eat();
+                Ok(Some(Op::ProcCall(name)))
+            }
+            _ => {
+                let pos = self.pos_at(self.ptr);
+                self.report(ParseError::MissingDelimiter { name, pos })?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// A single character proc is defined by its first occurrence, closed by
+    /// the second and called by any after that.
+    fn char_proc(&mut self, ch: char) -> Op {
+        let pos = self.ptr;
+        let def = self.here();
+        self.eat();
+        let name = ch.to_string();
+        if matches!(self.open_procs.last(), Some((n, false, _)) if *n == name) {
+            self.open_procs.pop();
+            Op::ProcEnd
+        } else if self.procs.contains_key(&name) {
+            Op::ProcCall(name)
+        } else {
+            self.procs.insert(name.clone(), def);
+            self.open_procs.push((name.clone(), false, pos));
+            Op::ProcDef(name)
+        }
+    }
+
+    /// Whether the `%` at `ptr` starts an include directive. A letter after
+    /// a proc character is an error otherwise, so this can't change the
+    /// meaning of a valid program.
+    fn at_include(&self) -> bool {
+        self.buffer[self.ptr + 1..].starts_with("include")
+    }
+
+    /// Lexes `%include "path"`, or skips over a malformed one.
+    fn include_directive(&mut self) -> Result<Option<Lexed>, ParseError> {
+        let (_, at) = self.here();
+        for _ in 0.."%include".len() {
+            self.eat();
+        }
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.eat();
+        }
+        if self.eat() != Some('"') {
+            self.report(ParseError::MalformedInclude { pos: at })?;
+            return Ok(None);
+        }
+        let mut path = String::new();
+        loop {
+            match self.eat() {
+                Some('"') => break,
+                Some('\n') | None => {
+                    self.report(ParseError::MalformedInclude { pos: at })?;
+                    return Ok(None);
+                }
+                Some(c) => path.push(c),
+            }
+        }
+        Ok(Some(Lexed::Include { path, at }))
+    }
+
+    /// Skips a `#!` line at the top of the file, a shebang or settings for
+    /// the compiler (see `directive::read`), which would otherwise be lexed
+    /// as a proc and the start of the input data.
+    fn skip_directive(&mut self) {
+        if self.ptr == 0 && self.buffer.starts_with("#!") {
+            self.ptr = self.buffer.find('\n').unwrap_or(self.buffer.len());
+        }
+    }
+
+    fn get_op(&mut self) -> Result<Option<Lexed>, ParseError> {
+        self.skip_directive();
+        loop {
+            while let Some(c) = self.peek() {
+                if !matches!(c, '\n' | '\r' | ' ' | '\t') && !self.is_comment(c) {
+                    break;
+                }
+                self.eat();
+            }
+            let c = if let Some(c) = self.peek() {
+                c
+            } else {
+                return Ok(None);
+            };
+            let span = Span {
+                file: self.file,
+                line: self.line,
+                col: self.col,
+            };
+            let op = match c {
+                '>' => self.eat_while_same(c, Op::PointerInc),
+                '<' => self.eat_while_same(c, Op::PointerDec),
+                '+' => self.eat_while_same(c, Op::ValueInc),
+                '-' => self.eat_while_same(c, Op::ValueDec),
+                '.' => self.eat_while_same(c, Op::Output),
+                ',' => {
+                    self.eat();
+                    Op::Input
+                }
+                '[' => {
+                    self.eat();
+                    Op::LLoop
+                }
+                ']' => {
+                    self.eat();
+                    Op::RLoop
+                }
+                '!' => {
+                    self.input = Some(self.buffer[self.ptr + 1..].to_string());
+                    self.ptr = self.buffer.len();
+                    return Ok(None);
+                }
+                '@' if matches!(self.peek_second(), Some(c) if c.is_alphabetic() || c == '_') => {
+                    match self.named_proc()? {
+                        Some(op) => op,
+                        None => continue,
+                    }
+                }
+                '}' if matches!(self.open_procs.last(), Some((_, true, _))) => {
+                    self.eat();
+                    self.open_procs.pop();
+                    Op::ProcEnd
+                }
+                '#' if self.debug_ops => {
+                    self.eat();
+                    Op::DumpTape
+                }
+                '%' if self.at_include() => match self.include_directive()? {
+                    Some(include) => return Ok(Some(include)),
+                    None => continue,
+                },
+                ch if PROC_CHARS.contains(ch) => self.char_proc(ch),
+                ch => {
+                    let pos = self.pos_at(self.ptr);
+                    self.report(ParseError::IllegalChar { ch, pos })?;
+                    // A word in a comment is one error, not one a letter.
+                    self.eat();
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric()) {
+                        self.eat();
+                    }
+                    continue;
+                }
+            };
+            return Ok(Some(Lexed::Op(Spanned { value: op, span })));
+        }
+    }
+
+    /// Takes where each op returned by `run` came from.
+    pub fn take_spans(&mut self) -> Vec<Span> {
+        std::mem::take(&mut self.spans)
+    }
+
+    /// Takes where each `[]` found by `run` starts, and the index of the
+    /// file it is in. They almost always hang, so callers should warn about
+    /// them.
+    pub fn take_empty_loops(&mut self) -> Vec<(usize, Position)> {
+        std::mem::take(&mut self.empty_loops)
+    }
+
+    /// Takes the data following a `!` separator, if the source had one.
+    pub fn take_input(&mut self) -> Option<String> {
+        self.input.take()
+    }
+
+    /// Lexes the whole buffer, and the files it includes, checking that
+    /// every bracket is matched within the same proc body and every proc is
+    /// closed. Returns the first error found; with `set_max_errors` the
+    /// rest are in `take_errors`.
+    pub fn run(&mut self) -> Result<Vec<Op>, ParseError> {
+        let first = self.errors.len();
+        let mut ops = Vec::new();
+        // The same as collecting the `Iterator`, without going through
+        // `next` for every op, which is measurably slower on big inputs.
+        while let Ok(Some(spanned)) = self.lex_next() {
+            self.spans.push(spanned.span);
+            ops.push(spanned.value);
+        }
+        self.done = true;
+        self.yielded = self.errors.len();
+        match self.errors.get(first) {
+            Some(located) => Err(located.error.clone()),
+            None => Ok(ops),
+        }
+    }
+
+    /// Lexes the next op, stepping into and out of included files, or
+    /// returns `None` at the end of the buffer. Errors are recorded, and
+    /// only returned to stop.
+    fn lex_next(&mut self) -> Result<Option<Spanned<Op>>, ParseError> {
+        loop {
+            match self.get_op()? {
+                Some(Lexed::Op(spanned)) => {
+                    self.check(&spanned.value)?;
+                    return Ok(Some(spanned));
+                }
+                Some(Lexed::Include { path, at }) => self.include(&path, at)?,
+                None => {
+                    self.end_file()?;
+                    match self.including.pop() {
+                        Some(outer) => self.resume(outer),
+                        None => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that `op`, just lexed, doesn't close a loop that isn't open
+    /// in the same proc body, or leave one open at the end of a proc. A
+    /// stray `]` is then left out of the bracket matching, and brackets
+    /// left open in a proc are closed with it.
+    fn check(&mut self, op: &Op) -> Result<(), ParseError> {
+        let floor = self.floors.last().copied().unwrap_or(0);
+        // Brackets are always a single byte, so the one just eaten is at
+        // `ptr - 1`.
+        match op {
+            Op::LLoop => self.open.push(self.ptr - 1),
+            Op::RLoop if self.open.len() == floor => {
+                let pos = self.pos_at(self.ptr - 1);
+                self.report(ParseError::UnmatchedBracket { pos })?;
+            }
+            Op::RLoop => {
+                let start = self.open.pop().unwrap();
+                if self.after_lloop {
+                    let pos = self.pos_at(start);
+                    if self.strict {
+                        self.report(ParseError::EmptyLoop { pos })?;
+                    } else {
+                        self.empty_loops.push((self.file, pos));
+                    }
+                }
+            }
+            Op::ProcDef(_) => self.floors.push(self.open.len()),
+            Op::ProcEnd => {
+                if self.open.len() > floor {
+                    let pos = self.pos_at(*self.open.last().unwrap());
+                    self.open.truncate(floor);
+                    self.report(ParseError::UnmatchedBracket { pos })?;
+                }
+                self.floors.pop();
+            }
+            _ => {}
+        }
+        self.after_lloop = matches!(op, Op::LLoop);
+        Ok(())
+    }
+
+    /// Checks that the file just lexed closed every loop and proc it
+    /// opened, and defined every proc it called.
+    fn end_file(&mut self) -> Result<(), ParseError> {
+        while let Some((name, _, pos)) = self.open_procs.pop() {
+            let pos = self.pos_at(pos);
+            self.report(ParseError::UnterminatedProc { name, pos })?;
+        }
+        for (name, pos) in std::mem::take(&mut self.forward_calls) {
+            if !self.procs.contains_key(&name) {
+                let pos = self.pos_at(pos);
+                self.report(ParseError::UndefinedProc { name, pos })?;
+            }
+        }
+        self.floors.clear();
+        for start in std::mem::take(&mut self.open) {
+            let pos = self.pos_at(start);
+            self.report(ParseError::UnmatchedBracket { pos })?;
+        }
+        Ok(())
+    }
+
+    /// Switches to the file at `path`, relative to the current one, to
+    /// carry on after the `%include` at `at` once it has been lexed. The
+    /// included file can call the procs defined so far, but has to close
+    /// its own loops and procs.
+    fn include(&mut self, path: &str, at: Position) -> Result<(), ParseError> {
+        let dir = Path::new(&self.files[self.file])
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let resolved = normalize(&dir.join(path));
+        let name = resolved.to_string_lossy().into_owned();
+        let open_files = std::iter::once(self.file).chain(self.including.iter().map(|f| f.file));
+        if open_files
+            .map(|file| normalize(Path::new(&self.files[file])))
+            .any(|file| file == resolved)
+        {
+            return self.report(ParseError::IncludeCycle {
+                path: name,
+                pos: at,
+            });
+        }
+        let source = match &self.loader {
+            Some(loader) => loader(&resolved).map_err(|e| e.to_string()),
+            None => Err("includes aren't supported here".to_string()),
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(reason) => {
+                return self.report(ParseError::IncludeFailed {
+                    path: name,
+                    pos: at,
+                    reason,
+                })
+            }
+        };
+
+        self.including.push(Including {
+            buffer: std::mem::replace(&mut self.buffer, without_bom(source)),
+            ptr: self.ptr,
+            line: self.line,
+            col: self.col,
+            file: self.file,
+            at,
+            open_procs: std::mem::take(&mut self.open_procs),
+            forward_calls: std::mem::take(&mut self.forward_calls),
+            open: std::mem::take(&mut self.open),
+            floors: std::mem::take(&mut self.floors),
+        });
+        self.files.push(name);
+        self.file = self.files.len() - 1;
+        self.ptr = 0;
+        self.line = 1;
+        self.col = 1;
+        Ok(())
+    }
+
+    /// Carries on lexing the file `outer` after its `%include`.
+    fn resume(&mut self, outer: Including) {
+        self.buffer = outer.buffer;
+        self.ptr = outer.ptr;
+        self.line = outer.line;
+        self.col = outer.col;
+        self.file = outer.file;
+        self.open_procs = outer.open_procs;
+        self.forward_calls = outer.forward_calls;
+        self.open = outer.open;
+        self.floors = outer.floors;
+    }
+}
+
+impl Iterator for Lexer {
+    type Item = Result<Spanned<Op>, ParseError>;
+
+    /// Lexes and checks the next op, returning errors as they are found.
+    /// At the end of the buffer, or after `max_errors` errors, this returns
+    /// `None` until `next_file` is called.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(located) = self.errors.get(self.yielded) {
+                self.yielded += 1;
+                return Some(Err(located.error.clone()));
+            }
+            if let Some(spanned) = self.ready.take() {
+                return Some(Ok(spanned));
+            }
+            if self.done {
+                return None;
+            }
+            match self.lex_next() {
+                Ok(Some(spanned)) => self.ready = Some(spanned),
+                Ok(None) | Err(_) => self.done = true,
+            }
+        }
+    }
+}
+
+/// `source` without the byte order mark editors on Windows like to start
+/// files with, which would otherwise count as a column on the first line.
+fn without_bom(mut source: String) -> String {
+    if source.starts_with('\u{FEFF}') {
+        source.drain(..'\u{FEFF}'.len_utf8());
+    }
+    source
+}
+
+/// `path` with `.` components dropped and `..` applied where it can be, so
+/// the same file reached two ways gets the same name.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(out.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                out.pop();
+            }
+            component => out.push(component),
+        }
+    }
+    out
+}
+
+/// Parses `source` into ops without panicking, whatever the input. Anything
+/// after a `!` separator is ignored; use a `Lexer` to get at it.
+pub fn parse(source: &str) -> Result<Vec<Op>, ParseError> {
+    Lexer::new(source.to_string()).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Op::*;
+
+    #[test]
+    fn call_before_definition() {
+        let ops = parse("@b;>@b;@b{+.}").unwrap();
+        let b = || "b".to_string();
+        assert_eq!(
+            ops,
+            [
+                ProcCall(b()),
+                PointerInc(1),
+                ProcCall(b()),
+                ProcDef(b()),
+                ValueInc(1),
+                Output(1),
+                ProcEnd
+            ]
+        );
+    }
+
+    #[test]
+    fn other_punctuation_is_a_comment() {
+        assert_eq!(parse("?+\0.\x7f? \"'"), Ok(vec![ValueInc(1), Output(1)]));
+    }
+
+    #[test]
+    fn strict_rejects_comments() {
+        let mut lexer = Lexer::new("+?".to_string());
+        lexer.set_strict(true);
+        assert_eq!(
+            lexer.run(),
+            Err(ParseError::IllegalChar {
+                ch: '?',
+                pos: Position { line: 1, col: 2 },
+            })
+        );
+    }
+
+    #[test]
+    fn letters_are_illegal() {
+        assert!(matches!(
+            parse("+a"),
+            Err(ParseError::IllegalChar { ch: 'a', .. })
+        ));
+    }
+
+    #[test]
+    fn call_to_undefined_proc() {
+        assert_eq!(
+            parse("+@nope;"),
+            Err(ParseError::UndefinedProc {
+                name: "nope".to_string(),
+                pos: Position { line: 1, col: 2 },
+            })
+        );
+    }
+
+    #[test]
+    fn duplicate_proc_message() {
+        let pos = |line, col| Position { line, col };
+        let mut error = ParseError::DuplicateProc {
+            name: "p".to_string(),
+            pos: pos(3, 1),
+            first: pos(1, 4),
+            first_file: None,
+        };
+        assert_eq!(
+            error.to_string(),
+            "proc 'p' defined again at 3:1, first at 1:4"
+        );
+        if let ParseError::DuplicateProc { first_file, .. } = &mut error {
+            *first_file = Some("lib.bf".to_string());
+        }
+        assert_eq!(
+            error.to_string(),
+            "proc 'p' defined again at 3:1, first at lib.bf:1:4"
+        );
+    }
+
+    /// A lexer that has lexed `lib.bf`, set up the way `lex_files` does.
+    fn after_lib(lib: &str) -> (Lexer, Result<Vec<Op>, ParseError>) {
+        let mut lexer = Lexer::new(String::new());
+        lexer.next_file("lib.bf", lib.to_string());
+        let ops = lexer.run();
+        (lexer, ops)
+    }
+
+    #[test]
+    fn procs_carry_over_files() {
+        let (mut lexer, lib) = after_lib("@up{+}");
+        assert!(lib.is_ok());
+        lexer.next_file("main.bf", "@up;.".to_string());
+        assert_eq!(lexer.run(), Ok(vec![ProcCall("up".to_string()), Output(1)]));
+        assert_eq!(lexer.files()[1..], ["lib.bf", "main.bf"]);
+    }
+
+    #[test]
+    fn duplicate_proc_in_later_file() {
+        let (mut lexer, _) = after_lib("\n@up{+}");
+        lexer.next_file("main.bf", "+@up{-}".to_string());
+        assert_eq!(
+            lexer.run(),
+            Err(ParseError::DuplicateProc {
+                name: "up".to_string(),
+                pos: Position { line: 1, col: 2 },
+                first: Position { line: 2, col: 1 },
+                first_file: Some("lib.bf".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn loops_close_in_their_file() {
+        let (mut lexer, lib) = after_lib("+[");
+        assert!(matches!(lib, Err(ParseError::UnmatchedBracket { .. })));
+        lexer.next_file("main.bf", "]".to_string());
+        assert!(matches!(
+            lexer.run(),
+            Err(ParseError::UnmatchedBracket { .. })
+        ));
+    }
+
+    /// A lexer for `main.bf`, reading includes from `files` instead of the
+    /// disk.
+    fn with_files(main: &str, files: &[(&str, &str)]) -> Lexer {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|&(path, source)| (PathBuf::from(path), source.to_string()))
+            .collect();
+        let mut lexer = Lexer::new(String::new());
+        lexer.set_loader(Box::new(move |path: &Path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        }));
+        lexer.next_file("main.bf", main.to_string());
+        lexer
+    }
+
+    #[test]
+    fn include_relative_to_including_file() {
+        let mut lexer = with_files(
+            "+%include \"lib/a.bf\"\n.",
+            &[("lib/a.bf", ">%include \"b.bf\""), ("lib/b.bf", "@p{-}@p;")],
+        );
+        assert_eq!(
+            lexer.run(),
+            Ok(vec![
+                ValueInc(1),
+                PointerInc(1),
+                ProcDef("p".to_string()),
+                ValueDec(1),
+                ProcEnd,
+                ProcCall("p".to_string()),
+                Output(1),
+            ])
+        );
+        assert_eq!(lexer.files()[1..], ["main.bf", "lib/a.bf", "lib/b.bf"]);
+    }
+
+    #[test]
+    fn include_cycle() {
+        let mut lexer = with_files("%include \"a.bf\"", &[("a.bf", "+\n%include \"main.bf\"")]);
+        assert_eq!(
+            lexer.run(),
+            Err(ParseError::IncludeCycle {
+                path: "main.bf".to_string(),
+                pos: Position { line: 2, col: 1 },
+            })
+        );
+        // The same file included twice in a row is not a cycle.
+        let mut lexer = with_files("%include \"a.bf\"%include \"a.bf\"", &[("a.bf", ".")]);
+        assert_eq!(lexer.run(), Ok(vec![Output(1), Output(1)]));
+    }
+
+    #[test]
+    fn include_stack() {
+        let mut lexer = with_files(
+            "\n%include \"a.bf\"",
+            &[("a.bf", "+%include \"b.bf\""), ("b.bf", "[")],
+        );
+        assert!(lexer.run().is_err());
+        let errors = lexer.take_errors();
+        assert_eq!(errors.len(), 1);
+        let names: Vec<_> = errors[0]
+            .included_from
+            .iter()
+            .map(|&(file, pos)| (lexer.files()[file].as_str(), pos.line))
+            .collect();
+        assert_eq!(lexer.files()[errors[0].file], "b.bf");
+        assert_eq!(names, [("a.bf", 1), ("main.bf", 2)]);
+    }
+
+    #[test]
+    fn bad_includes() {
+        let mut lexer = with_files("%include a.bf", &[]);
+        assert!(matches!(
+            lexer.run(),
+            Err(ParseError::MalformedInclude { .. })
+        ));
+        let mut lexer = with_files("+%include \"gone.bf\"", &[]);
+        assert!(matches!(
+            lexer.run(),
+            Err(ParseError::IncludeFailed { path, .. }) if path == "gone.bf"
+        ));
+        // Without a loader nothing can be included.
+        assert!(matches!(
+            parse("%include \"a.bf\""),
+            Err(ParseError::IncludeFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        assert_eq!(
+            parse("\u{FEFF}+]"),
+            Err(ParseError::UnmatchedBracket {
+                pos: Position { line: 1, col: 2 },
+            })
+        );
+    }
+
+    #[test]
+    fn non_ascii_is_a_comment() {
+        // Latin-1 read lossily is U+FFFD, which is no different.
+        assert_eq!(parse("+\u{e9}\u{FFFD}."), Ok(vec![ValueInc(1), Output(1)]));
+        let mut lexer = Lexer::new("+\u{e9}".to_string());
+        lexer.set_strict(true);
+        assert_eq!(
+            lexer.run(),
+            Err(ParseError::IllegalChar {
+                ch: '\u{e9}',
+                pos: Position { line: 1, col: 2 },
+            })
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        assert_eq!(
+            parse("+\r\n+\r\n  ["),
+            Err(ParseError::UnmatchedBracket {
+                pos: Position { line: 3, col: 3 },
+            })
+        );
+    }
+
+    /// Every error `source` has, up to `max_errors`, as messages.
+    fn errors(source: &str, max_errors: usize) -> Vec<String> {
+        let mut lexer = Lexer::new(source.to_string());
+        lexer.set_max_errors(max_errors);
+        assert!(lexer.run().is_err());
+        let errors = lexer.take_errors();
+        errors.iter().map(|e| e.error.to_string()).collect()
+    }
+
+    #[test]
+    fn several_errors() {
+        assert_eq!(
+            errors("a+]\n@p{[\n@q;x", 20),
+            [
+                "illegal character 'a' at 1:1",
+                "unmatched bracket at 1:3",
+                "illegal character 'x' at 3:4",
+                "proc 'p' opened at 2:1 is never closed",
+                "proc 'q' is never defined, called at 3:1",
+                "unmatched bracket at 2:4",
+            ]
+        );
+        assert_eq!(
+            errors("@p{}@p{}", 20),
+            ["proc 'p' defined again at 1:5, first at 1:1"]
+        );
+    }
+
+    #[test]
+    fn error_cap() {
+        assert_eq!(errors("]]]]]", 20).len(), 5);
+        assert_eq!(
+            errors("]]]]]", 3),
+            [
+                "unmatched bracket at 1:1",
+                "unmatched bracket at 1:2",
+                "unmatched bracket at 1:3",
+            ]
+        );
+        // One by default.
+        let mut lexer = Lexer::new("]]".to_string());
+        assert!(lexer.run().is_err());
+        assert_eq!(lexer.take_errors().len(), 1);
+    }
+}
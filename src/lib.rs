@@ -0,0 +1,820 @@
+pub mod c_backend;
+pub mod codegen;
+pub mod diagnostics;
+pub mod dialect;
+pub mod directive;
+pub mod dump;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod formatter;
+pub mod interpreter;
+pub mod jit;
+pub mod lexer;
+pub mod ops;
+pub mod optimizer;
+pub mod repl;
+pub mod stats;
+pub mod timings;
+pub mod watch;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use inkwell::context::Context;
+use inkwell::targets::{CodeModel, RelocMode, TargetMachine};
+use inkwell::OptimizationLevel;
+use thiserror::Error;
+
+use codegen::CodeGen;
+use dialect::Dialect;
+use dump::DumpError;
+use lexer::{Lexer, ParseError, Position};
+use ops::Op;
+use optimizer::Passes;
+use timings::{time, Timings};
+
+/// What `compile_file` produces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// An object file from LLVM, linked with the system linker.
+    Llvm,
+    /// Portable C source in `main.c`, built with the system `cc`.
+    C,
+}
+
+/// An extra argument for the linker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkArg {
+    /// Passed on as is.
+    Raw(String),
+    /// A directory to search for libraries, like `-L`.
+    SearchDir(String),
+    /// A library to link, like `-l`.
+    Lib(String),
+}
+
+impl LinkArg {
+    /// The argument as `cc`, or with `msvc` MSVC's `link`, spells it.
+    pub fn to_arg(&self, msvc: bool) -> String {
+        match (self, msvc) {
+            (LinkArg::Raw(arg), _) => arg.clone(),
+            (LinkArg::SearchDir(dir), true) => format!("/LIBPATH:{}", dir),
+            (LinkArg::SearchDir(dir), false) => format!("-L{}", dir),
+            (LinkArg::Lib(lib), true) => format!("{}.lib", lib),
+            (LinkArg::Lib(lib), false) => format!("-l{}", lib),
+        }
+    }
+}
+
+/// How `.` turns a cell into output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// The low byte of the cell, as is.
+    Byte,
+    /// The cell as a Unicode code point, encoded as UTF-8. Values that
+    /// aren't scalar values come out as U+FFFD.
+    Utf8,
+}
+
+/// Where the tape lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapeStorage {
+    /// Allocated with `calloc` when the program starts. WebAssembly gets
+    /// a global instead, as it may have no C runtime to allocate with.
+    Heap,
+    /// An array on main's stack, zeroed when the program starts. Saves
+    /// the allocation for small tapes, but a big one can overflow the
+    /// stack.
+    Stack,
+    /// A zeroed global array. It isn't zeroed again if the program's entry
+    /// point is called more than once, as with `entry_symbol`.
+    Global,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    pub backend: Backend,
+    /// Language the source is written in.
+    pub dialect: Dialect,
+    /// Number of cells on the tape.
+    pub cells: u64,
+    /// Where the tape is kept. Only `Heap` can grow, so `grow` needs it.
+    pub tape: TapeStorage,
+    /// Cell the pointer starts on, so programs can move left from the
+    /// start. Must be less than `cells`.
+    pub start_cell: u64,
+    /// Width of a tape cell in bits: 8, 16 or 32. Cell arithmetic wraps at
+    /// this width.
+    pub cell_bits: u32,
+    /// Treat cells as signed where it makes a difference: bytes read by
+    /// `,` are sign-extended, so EOF is -1 at any width, and cells output
+    /// as UTF-8 are sign-extended, so negative ones print as U+FFFD.
+    /// Otherwise bytes are zero-extended and EOF reads as 255.
+    pub signed_cells: bool,
+    /// Treat the tape as circular, so moving off one end enters the other.
+    pub wrap_pointer: bool,
+    /// Reallocate the tape at twice the size whenever the pointer moves past
+    /// its end, so `cells` is only where it starts. Not for use with
+    /// `wrap_pointer` or `shared`.
+    pub grow: bool,
+    /// Generate the program as `int bf_run(cell *tape)`, with a `main` that
+    /// only allocates a tape and calls it, so a host can run the program
+    /// on tapes of its own, as often as it likes. Not for use with `grow`
+    /// or `shared`.
+    pub reusable: bool,
+    /// Reject characters that are neither ops nor proc characters instead
+    /// of skipping them as comments.
+    pub strict: bool,
+    /// Parse errors to find before giving up. Lexing carries on past the
+    /// ones before that, so they are all reported in one go.
+    pub max_errors: usize,
+    /// Return the current cell from `main` instead of 0.
+    pub exit_cell: bool,
+    /// Build a shared library exporting every proc as
+    /// `void f(cell *tape)` instead of a program. The tape global used by
+    /// `wrap_pointer` is only set up by main, so don't combine the two.
+    pub shared: bool,
+    /// Stop after writing the object file.
+    pub no_link: bool,
+    /// Name to give `main`, `None` to keep it, so the program can be
+    /// linked into another as `int name(void)`. The object then has no
+    /// entry point of its own, so use it with `no_link`. Not for use with
+    /// `shared`.
+    pub entry_symbol: Option<String>,
+    /// Link the C runtime statically, so the program loads nothing at
+    /// runtime. Implies static relocations. Not for use with `shared`.
+    pub static_link: bool,
+    /// Leave out the C runtime altogether on Linux (x86-64 and aarch64):
+    /// I/O is done with system calls from the module itself, which gets
+    /// its own `_start` and is linked with `ld -nostdlib`. The tape is a
+    /// global. Not for use with `shared`, `entry_symbol`, `grow` or the
+    /// options that print to stderr, like `trace`.
+    pub freestanding: bool,
+    /// Extra linker arguments, passed after the object file in order.
+    pub link_args: Vec<LinkArg>,
+    /// Where `compile_file` writes the C header for a `shared` build,
+    /// `None` for `main.h` next to the object.
+    pub header: Option<String>,
+    /// File descriptor to write output to with `write`, instead of stdout.
+    pub output_fd: Option<i32>,
+    pub output_encoding: OutputEncoding,
+    /// File descriptor to read input from with `read`, instead of stdin.
+    pub input_fd: Option<i32>,
+    /// Flush stdout before every read, so a prompt printed just before it
+    /// shows up even when stdout isn't a terminal and libc buffers it.
+    pub flush_on_input: bool,
+    /// File whose bytes are baked into the program and read by `,` instead
+    /// of stdin. Takes precedence over `!` data and `input_fd`.
+    pub input_file: Option<String>,
+    /// Target triple to compile for, `None` for the host.
+    pub target: Option<String>,
+    /// CPU to tune and select instructions for, `native` for the host's.
+    pub cpu: String,
+    /// LLVM feature string like `+avx2,-sse4a`, added to the host's
+    /// features with `native`.
+    pub features: String,
+    pub opt_level: OptimizationLevel,
+    pub reloc_mode: RelocMode,
+    pub code_model: CodeModel,
+    /// Print notes about what the optimizer did to stderr.
+    pub verbose: bool,
+    /// Print the op stream before and after optimization to stderr.
+    pub dump_ast: bool,
+    /// Source file to emit DWARF debug info for, `None` for none.
+    pub debug_source: Option<String>,
+    /// Print the location, pointer and cell after every op to stderr at
+    /// runtime.
+    pub trace: bool,
+    /// Lex `#` as a tape dump instead of a proc.
+    pub debug_ops: bool,
+    /// Mark loop conditions as likely to stay in the loop.
+    pub loop_hints: bool,
+    /// Dump this many cells from the start of the tape to stderr when the
+    /// program ends, 0 for none.
+    pub print_cells: u64,
+    /// Exit with code 3 once loops have jumped back this many times in
+    /// total, 0 for no limit.
+    pub max_steps: u64,
+    /// Print what the op stream is made of before and after optimization,
+    /// and the op count after each pass, to stderr.
+    pub stats: bool,
+    /// Which BF level optimizations to run.
+    pub passes: Passes,
+    /// Print how long each phase of `compile_file` took to stderr.
+    pub timings: bool,
+}
+
+impl CompileOptions {
+    pub fn target_triple(&self) -> String {
+        match &self.target {
+            Some(target) => target.clone(),
+            None => host_triple(),
+        }
+    }
+
+    /// The CPU and feature string to hand LLVM, with `native` resolved to
+    /// the host's. That only makes sense when compiling for the host.
+    pub fn target_cpu(&self) -> Result<(String, String), CompileError> {
+        if self.cpu != "native" {
+            return Ok((self.cpu.clone(), self.features.clone()));
+        }
+        if let Some(target) = self.target.as_ref().filter(|t| **t != host_triple()) {
+            return Err(CompileError::Target(format!(
+                "--cpu native can't be used when cross compiling to {}",
+                target
+            )));
+        }
+        let cpu = TargetMachine::get_host_cpu_name().to_string();
+        let mut features = TargetMachine::get_host_cpu_features().to_string();
+        if !self.features.is_empty() {
+            features.push(',');
+            features.push_str(&self.features);
+        }
+        Ok((cpu, features))
+    }
+
+    /// Checks the tape settings the compiler can't work with, which the
+    /// command line rejects before they get here.
+    pub fn check_tape(&self) -> Result<(), CompileError> {
+        if self.start_cell >= self.cells {
+            return Err(CompileError::Options(format!(
+                "start_cell {} is off a tape of {} cells",
+                self.start_cell, self.cells
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn host_triple() -> String {
+    TargetMachine::get_default_triple()
+        .as_str()
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Llvm,
+            dialect: Dialect::Brainfuck,
+            cells: 1000,
+            tape: TapeStorage::Heap,
+            start_cell: 0,
+            cell_bits: 8,
+            signed_cells: false,
+            wrap_pointer: false,
+            grow: false,
+            reusable: false,
+            strict: false,
+            max_errors: 20,
+            exit_cell: false,
+            shared: false,
+            no_link: false,
+            entry_symbol: None,
+            static_link: false,
+            freestanding: false,
+            link_args: Vec::new(),
+            header: None,
+            output_fd: None,
+            output_encoding: OutputEncoding::Byte,
+            input_fd: None,
+            flush_on_input: false,
+            input_file: None,
+            target: None,
+            cpu: "generic".to_string(),
+            features: String::new(),
+            opt_level: OptimizationLevel::Aggressive,
+            reloc_mode: RelocMode::PIC,
+            code_model: CodeModel::Default,
+            verbose: false,
+            dump_ast: false,
+            debug_source: None,
+            trace: false,
+            debug_ops: false,
+            loop_hints: false,
+            max_steps: 0,
+            print_cells: 0,
+            stats: false,
+            passes: Passes::default(),
+            timings: false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CompileError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("parse error: {0}")]
+    Parse(#[from] ParseError),
+    /// A parse error in one file of a program split across several, or
+    /// in an included file, with the files including it and where,
+    /// innermost first.
+    #[error("parse error in {path}: {error}{}", included_from_lines(included_from))]
+    ParseFile {
+        path: String,
+        error: Box<ParseError>,
+        included_from: Vec<(String, Position)>,
+    },
+    /// The op stream ended inside a proc body.
+    #[error("proc '{name}' is never closed")]
+    UnterminatedProc { name: String },
+    /// The target machine couldn't be set up.
+    #[error("target error: {0}")]
+    Target(String),
+    /// The generated module failed LLVM's verifier.
+    #[error("generated invalid IR: {0}")]
+    Verify(String),
+    /// LLVM failed to emit the object file.
+    #[error("failed to emit object: {0}")]
+    Emit(String),
+    /// The linker, or `cc` for the C backend, exited with an error.
+    #[error("{linker} failed{}", link_output(output))]
+    Link {
+        linker: String,
+        /// What it printed, stdout and stderr together, since MSVC's
+        /// `link` reports errors on stdout.
+        output: String,
+    },
+    /// Several parse errors, at most `max_errors`, in the order they were
+    /// found. A single one is returned as is.
+    #[error("{}", join_errors(.0))]
+    Errors(Vec<CompileError>),
+    /// The options can't be compiled with, see `CompileOptions::check_tape`.
+    #[error("bad options: {0}")]
+    Options(String),
+    /// `run_jit` couldn't run the program in this process.
+    #[error("can't run in the JIT: {0}")]
+    Jit(String),
+    /// An op dump given with `--from-ops` couldn't be read, or its ops
+    /// don't form a program.
+    #[error(transparent)]
+    Ops(#[from] DumpError),
+}
+
+/// The `included from` lines under a `ParseFile` error.
+fn included_from_lines(included_from: &[(String, Position)]) -> String {
+    included_from
+        .iter()
+        .map(|(path, pos)| format!("\n    included from {}:{}", path, pos.line))
+        .collect()
+}
+
+/// What a failed linker printed, after a colon and on lines of its own.
+fn link_output(output: &str) -> String {
+    if output.is_empty() {
+        String::new()
+    } else {
+        format!(":\n{}", output)
+    }
+}
+
+/// Several errors, one per line.
+fn join_errors(errors: &[CompileError]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runs `command` to link, turning a failed exit into `CompileError::Link`.
+pub(crate) fn run_linker(mut command: Command) -> Result<(), CompileError> {
+    let output = command.output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Err(CompileError::Link {
+        linker: command.get_program().to_string_lossy().into_owned(),
+        output: text.trim_end().to_string(),
+    })
+}
+
+/// Lexes `files`, each a path and its source, as one program, returning the
+/// lexer too for the input data and spans. Included files are read from
+/// disk, and every file lexed is left in `loaded`, even when lexing fails. Errors and warnings in a single file read as they would from the
+/// lexer, while for a program split across several, or in included files,
+/// they name the file they are in. Lexing goes on after errors, through
+/// the later files too, until `max_errors` have been found.
+fn lex_sources(
+    files: &[(&str, &str)],
+    opts: &CompileOptions,
+    timings: &mut Timings,
+    loaded: &mut Vec<String>,
+) -> Result<(Vec<Op>, Lexer), CompileError> {
+    let mut lexer = Lexer::new(String::new());
+    lexer.set_debug_ops(opts.debug_ops);
+    lexer.set_strict(opts.strict);
+    lexer.set_loader(Box::new(|path: &Path| read_source(path)));
+    let mut ops = Vec::new();
+    let mut errors = Vec::new();
+    let single = files.len() == 1;
+    for &(path, source) in files {
+        let translated = time(&mut timings.translate, || {
+            opts.dialect.frontend().translate(source)
+        });
+        let source = match translated {
+            Ok(source) => source,
+            Err(error) if single => return Err(CompileError::Parse(error)),
+            Err(error) => {
+                errors.push(CompileError::ParseFile {
+                    path: path.to_string(),
+                    error: Box::new(error),
+                    included_from: Vec::new(),
+                });
+                continue;
+            }
+        };
+        lexer.next_file(path, source);
+        lexer.set_max_errors(opts.max_errors.saturating_sub(errors.len()));
+        let top = lexer.files().len() - 1;
+        if let Ok(lexed) = time(&mut timings.lex, || lexer.run()) {
+            ops.extend(lexed);
+        }
+        for located in lexer.take_errors() {
+            if single && located.included_from.is_empty() {
+                errors.push(CompileError::Parse(located.error));
+                continue;
+            }
+            let name = |file: usize| lexer.files()[file].clone();
+            errors.push(CompileError::ParseFile {
+                path: name(located.file),
+                error: Box::new(located.error),
+                included_from: located
+                    .included_from
+                    .into_iter()
+                    .map(|(file, pos)| (name(file), pos))
+                    .collect(),
+            });
+        }
+        for (file, pos) in lexer.take_empty_loops() {
+            let warning = ParseError::EmptyLoop { pos };
+            if single && file == top {
+                eprintln!("warning: {}", warning);
+            } else {
+                eprintln!("warning: {}: {}", lexer.files()[file], warning);
+            }
+        }
+        if errors.len() >= opts.max_errors {
+            break;
+        }
+    }
+    // The first file is the lexer's empty starting one.
+    *loaded = lexer.files()[1..].to_vec();
+    match errors.len() {
+        0 => Ok((ops, lexer)),
+        1 => Err(errors.pop().unwrap()),
+        _ => Err(CompileError::Errors(errors)),
+    }
+}
+
+/// Reads and lexes the files at `paths` as one program, without optimizing,
+/// returning the lexer too for the input data. Empty loops are warned about
+/// on stderr.
+pub fn lex_files<P: AsRef<str>>(
+    paths: &[P],
+    opts: &CompileOptions,
+) -> Result<(Vec<Op>, Lexer), CompileError> {
+    let sources = read_sources(paths)?;
+    let files = files(paths, &sources);
+    lex_sources(&files, opts, &mut Timings::default(), &mut Vec::new())
+}
+
+/// Reads the file at each of `paths`.
+fn read_sources<P: AsRef<str>>(paths: &[P]) -> std::io::Result<Vec<String>> {
+    paths
+        .iter()
+        .map(|path| read_source(path.as_ref()))
+        .collect()
+}
+
+/// Reads a source file. Only ASCII means anything to the lexer, so bytes
+/// that aren't valid UTF-8 are let through as U+FFFD, a comment like any
+/// other non-ASCII character, instead of failing the read.
+pub fn read_source<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    Ok(match String::from_utf8(fs::read(path)?) {
+        Ok(source) => source,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    })
+}
+
+/// Pairs each of `paths` with its source, for `lex_sources`.
+fn files<'s, P: AsRef<str>>(paths: &'s [P], sources: &'s [String]) -> Vec<(&'s str, &'s str)> {
+    paths
+        .iter()
+        .map(AsRef::as_ref)
+        .zip(sources.iter().map(String::as_str))
+        .collect()
+}
+
+/// Lexes and optimizes `files`, returning the lexer too for the input data
+/// and spans.
+fn lex(
+    files: &[(&str, &str)],
+    opts: &CompileOptions,
+    timings: &mut Timings,
+    loaded: &mut Vec<String>,
+) -> Result<(Vec<Op>, Lexer), CompileError> {
+    opts.check_tape()?;
+    let (ops, lexer) = lex_sources(files, opts, timings, loaded)?;
+    // Optimizing merges and drops ops, losing the line each came from, so
+    // debug and trace builds compile the ops as written.
+    let ast = if opts.debug_source.is_some() || opts.trace {
+        ops
+    } else {
+        time(&mut timings.optimize, || optimizer::optimize(ops, opts))
+    };
+    Ok((ast, lexer))
+}
+
+/// The input to bake into the program: the `input_file`, or failing that
+/// the data after a `!`.
+fn embedded_input(
+    lexer: &mut Lexer,
+    opts: &CompileOptions,
+) -> Result<Option<Vec<u8>>, CompileError> {
+    let data = lexer.take_input().map(String::into_bytes);
+    match &opts.input_file {
+        Some(path) => Ok(Some(fs::read(path)?)),
+        None => Ok(data),
+    }
+}
+
+/// Lexes, optimizes and generates code for `source`, leaving the module in
+/// the returned `CodeGen` so callers can transform it before emitting.
+pub fn generate<'a>(
+    ctx: &'a Context,
+    source: &str,
+    opts: &CompileOptions,
+) -> Result<CodeGen<'a>, CompileError> {
+    let files = [("", source)];
+    generate_timed(ctx, &files, opts, &mut Timings::default(), &mut Vec::new())
+}
+
+fn generate_timed<'a>(
+    ctx: &'a Context,
+    files: &[(&str, &str)],
+    opts: &CompileOptions,
+    timings: &mut Timings,
+    loaded: &mut Vec<String>,
+) -> Result<CodeGen<'a>, CompileError> {
+    let (ast, mut lexer) = lex(files, opts, timings, loaded)?;
+    let input = embedded_input(&mut lexer, opts)?;
+    time(&mut timings.codegen, || {
+        let mut cdg = CodeGen::new(ctx, ast, input, opts);
+        cdg.set_spans(lexer.take_spans(), lexer.files());
+        cdg.run()?;
+        Ok(cdg)
+    })
+}
+
+/// Compiles `source` to an object file in memory.
+pub fn compile_to_object(source: &str, opts: &CompileOptions) -> Result<Vec<u8>, CompileError> {
+    let ctx = Context::create();
+    generate(&ctx, source, opts)?.object(opts)
+}
+
+/// Compiles `source` to textual LLVM IR.
+pub fn compile_to_ir(source: &str, opts: &CompileOptions) -> Result<String, CompileError> {
+    let ctx = Context::create();
+    Ok(generate(&ctx, source, opts)?.ir())
+}
+
+/// Translates `source` to C, after the same optimizations as for LLVM.
+pub fn compile_to_c(source: &str, opts: &CompileOptions) -> Result<String, CompileError> {
+    let files = [("", source)];
+    compile_to_c_timed(&files, opts, &mut Timings::default(), &mut Vec::new())
+}
+
+fn compile_to_c_timed(
+    files: &[(&str, &str)],
+    opts: &CompileOptions,
+    timings: &mut Timings,
+    loaded: &mut Vec<String>,
+) -> Result<String, CompileError> {
+    let (ast, mut lexer) = lex(files, opts, timings, loaded)?;
+    let input = embedded_input(&mut lexer, opts)?;
+    Ok(time(&mut timings.codegen, || {
+        c_backend::translate_to_c(&ast, input.as_deref(), opts)
+    }))
+}
+
+/// Compiles the file at `path` to `main.o` and, unless `no_link` is set,
+/// links it. Shared builds also get a header declaring their procs. With
+/// the C backend it writes `main.c` and builds that with `cc` instead.
+/// Returns how long each phase took.
+pub fn compile_file(path: &str, opts: &CompileOptions) -> Result<Timings, CompileError> {
+    compile_files(&[path], opts)
+}
+
+/// Like `compile_file`, for a program split across `paths`, lexed in order
+/// so that each file can call the procs defined in the ones before it.
+pub fn compile_files<P: AsRef<str>>(
+    paths: &[P],
+    opts: &CompileOptions,
+) -> Result<Timings, CompileError> {
+    compile_files_loading(paths, opts, &mut Vec::new())
+}
+
+/// Like `compile_files`, also leaving the name of every file the program
+/// was lexed from in `loaded`, included ones too, for `--watch` to watch.
+/// The files are left there whether the build works or not, as long as
+/// `paths` could be read.
+pub fn compile_files_loading<P: AsRef<str>>(
+    paths: &[P],
+    opts: &CompileOptions,
+    loaded: &mut Vec<String>,
+) -> Result<Timings, CompileError> {
+    let mut timings = Timings::default();
+    let sources = read_sources(paths)?;
+    let files = files(paths, &sources);
+    if opts.backend == Backend::C {
+        let code = compile_to_c_timed(&files, opts, &mut timings, loaded)?;
+        build_c(&code, opts, &mut timings)?;
+        return Ok(timings);
+    }
+    let ctx = Context::create();
+    let cdg = generate_timed(&ctx, &files, opts, &mut timings, loaded)?;
+    build_object(&cdg, opts, &mut timings)?;
+    Ok(timings)
+}
+
+/// Like `compile_file`, for ops read from a dump rather than lexed. They
+/// are checked with `dump::validate` and compiled as they are, without
+/// optimizing, so a tool that rewrote them gets exactly what it wrote.
+pub fn compile_ops(ops: Vec<Op>, opts: &CompileOptions) -> Result<Timings, CompileError> {
+    opts.check_tape()?;
+    dump::validate(&ops)?;
+    let mut timings = Timings::default();
+    let input = match &opts.input_file {
+        Some(path) => Some(fs::read(path)?),
+        None => None,
+    };
+    if opts.backend == Backend::C {
+        let code = time(&mut timings.codegen, || {
+            c_backend::translate_to_c(&ops, input.as_deref(), opts)
+        });
+        build_c(&code, opts, &mut timings)?;
+        return Ok(timings);
+    }
+    let ctx = Context::create();
+    let cdg = time(&mut timings.codegen, || {
+        let mut cdg = CodeGen::new(&ctx, ops, input, opts);
+        cdg.run()?;
+        Ok::<_, CompileError>(cdg)
+    })?;
+    build_object(&cdg, opts, &mut timings)?;
+    Ok(timings)
+}
+
+/// Reads the op dump at `path`, as written by `--emit=ops`, and compiles
+/// it with `compile_ops`.
+pub fn compile_ops_file(path: &str, opts: &CompileOptions) -> Result<Timings, CompileError> {
+    let ops = dump::from_json(&read_source(path)?)?;
+    compile_ops(ops, opts)
+}
+
+/// Writes `code` to `main.c` and, unless `no_link` is set, builds it.
+fn build_c(code: &str, opts: &CompileOptions, timings: &mut Timings) -> Result<(), CompileError> {
+    fs::write("main.c", code)?;
+    if !opts.no_link {
+        let mut command = Command::new("cc");
+        command.args(["-O2", "-o", "main", "main.c"]);
+        command.args(opts.link_args.iter().map(|arg| arg.to_arg(false)));
+        time(&mut timings.link, || run_linker(command))?;
+    }
+    Ok(())
+}
+
+/// Writes `main.o`, links it unless `no_link` is set, and writes the
+/// header of a shared build.
+fn build_object(
+    cdg: &CodeGen,
+    opts: &CompileOptions,
+    timings: &mut Timings,
+) -> Result<(), CompileError> {
+    time(&mut timings.emit, || cdg.write_object(opts, "main.o"))?;
+    if !opts.no_link {
+        time(&mut timings.link, || cdg.link("main.o"))?;
+    }
+    if opts.shared {
+        let header = opts.header.as_deref().unwrap_or("main.h");
+        fs::write(header, cdg.header())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_arg_spelling() {
+        let args = [
+            LinkArg::Raw("-Wl,--as-needed".to_string()),
+            LinkArg::SearchDir("libs".to_string()),
+            LinkArg::Lib("m".to_string()),
+        ];
+        let cc: Vec<_> = args.iter().map(|arg| arg.to_arg(false)).collect();
+        let msvc: Vec<_> = args.iter().map(|arg| arg.to_arg(true)).collect();
+        assert_eq!(cc, ["-Wl,--as-needed", "-Llibs", "-lm"]);
+        assert_eq!(msvc, ["-Wl,--as-needed", "/LIBPATH:libs", "m.lib"]);
+    }
+
+    #[test]
+    fn include_stack_message() {
+        let pos = |line| Position { line, col: 1 };
+        let error = CompileError::ParseFile {
+            path: "b.bf".to_string(),
+            error: Box::new(ParseError::UnmatchedBracket { pos: pos(4) }),
+            included_from: vec![
+                ("a.bf".to_string(), pos(3)),
+                ("main.bf".to_string(), pos(1)),
+            ],
+        };
+        assert_eq!(
+            error.to_string(),
+            "parse error in b.bf: unmatched bracket at 4:1\n    included from a.bf:3\n    included from main.bf:1"
+        );
+    }
+
+    #[test]
+    fn read_source_is_lossy() {
+        let path = std::env::temp_dir().join(format!("bf-latin1-{}.bf", std::process::id()));
+        fs::write(&path, b"+ \xe9t\xe9 .").unwrap();
+        let source = read_source(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(source.unwrap(), "+ \u{FFFD}t\u{FFFD} .");
+    }
+
+    #[test]
+    fn loaded_files_include_included_ones() {
+        let dir = std::env::temp_dir().join(format!("bf-loaded-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.bf");
+        let lib = dir.join("lib.bf");
+        fs::write(&main, "+\n%include \"lib.bf\"\n.").unwrap();
+        // An error, so nothing gets written, and the files still come back.
+        fs::write(&lib, "]").unwrap();
+        let mut loaded = Vec::new();
+        let paths = [main.to_str().unwrap()];
+        let result = compile_files_loading(&paths, &CompileOptions::default(), &mut loaded);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+        assert_eq!(loaded, [main.to_str().unwrap(), lib.to_str().unwrap()]);
+    }
+
+    #[test]
+    fn start_cell_off_the_tape() {
+        let opts = CompileOptions {
+            cells: 10,
+            start_cell: 10,
+            ..Default::default()
+        };
+        assert!(matches!(
+            compile_to_object("+.", &opts),
+            Err(CompileError::Options(_))
+        ));
+        assert_eq!(
+            compile_to_c("+.", &opts).unwrap_err().to_string(),
+            "bad options: start_cell 10 is off a tape of 10 cells"
+        );
+        assert!(matches!(
+            jit::run_jit("+.", b"", &opts),
+            Err(CompileError::Options(_))
+        ));
+        // Optimizing on its own doesn't overflow either.
+        optimizer::optimize(vec![Op::ValueInc(1), Op::Output(1)], &opts);
+    }
+
+    #[test]
+    fn errors_come_back_together() {
+        let ctx = Context::create();
+        let opts = CompileOptions::default();
+        let Err(CompileError::Errors(errors)) = generate(&ctx, "]+]", &opts) else {
+            panic!("expected several errors");
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[1],
+            CompileError::Parse(ParseError::UnmatchedBracket { .. })
+        ));
+        // A single one on its own.
+        assert!(matches!(
+            generate(&ctx, "+]", &opts),
+            Err(CompileError::Parse(_))
+        ));
+        let opts = CompileOptions {
+            max_errors: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            generate(&ctx, "]+]", &opts),
+            Err(CompileError::Parse(_))
+        ));
+    }
+}
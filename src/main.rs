@@ -1,411 +1,576 @@
-use std::collections::{HashMap, VecDeque};
-use std::path::Path;
-use std::process::Command;
-use std::{default, fs};
+use std::io::{Read, Write};
 
-use inkwell::basic_block::BasicBlock;
-use inkwell::builder::Builder;
-use inkwell::context::Context;
-use inkwell::module::Module;
-use inkwell::targets::{
-    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
-};
-use inkwell::types::{FunctionType, VoidType};
-use inkwell::values::{BasicValueEnum, FunctionValue, PointerValue};
-use inkwell::{AddressSpace, OptimizationLevel};
+use bf_llvm::diagnostics::{self, ColorChoice};
+use bf_llvm::dialect::{Dialect, Substitution};
+use bf_llvm::directive;
+use bf_llvm::dump;
+use bf_llvm::formatter;
+use bf_llvm::interpreter;
+use bf_llvm::jit;
+use bf_llvm::lexer::Lexer;
+use bf_llvm::ops::Op;
+use bf_llvm::optimizer::{optimize, Passes};
+use bf_llvm::repl;
+use bf_llvm::watch;
+use bf_llvm::{Backend, CompileError, CompileOptions, LinkArg, OutputEncoding, TapeStorage};
+use inkwell::targets::{CodeModel, RelocMode};
+use inkwell::OptimizationLevel;
 
-#[derive(Clone, Debug)]
-enum Op {
-    // BF
-    PointerInc(usize),
-    PointerDec(usize),
-    ValueInc(usize),
-    ValueDec(usize),
-    Output,
-    Input,
-    LLoop,
-    RLoop,
-    // BF++
-    Proc(char),
+/// What `--emit` writes instead of compiling, after optimizing.
+enum Emit {
+    /// The ops as JSON, see `dump`.
+    Ops,
+    /// The ops as BF source.
+    Bf,
 }
 
-struct Lexer {
-    buffer: Vec<char>,
-    ptr: usize,
+/// What `--help` prints.
+const USAGE: &str = "\
+usage: bf [options] file...
+       bf fmt [--minify] file...
+       bf repl [options]
+
+Compiles BF programs to native executables with LLVM.
+
+Output:
+  --run                 run the program after building it
+  --watch               rebuild whenever a source file changes
+  --interpret           run the program in the interpreter instead
+  --jit                 run the program with LLVM's JIT instead
+  --no-link             stop after writing the object file
+  --shared              build a shared library exporting the procs
+  --reusable            generate `int bf_run(cell *tape)` as well
+  --entry-symbol NAME   name main NAME, implies --no-link
+  --header PATH         write a C header declaring the procs
+  --emit=ops|bf[=PATH]  print the optimized ops as JSON or BF instead
+  --from-ops PATH       compile an op dump instead of source
+  --backend=llvm|c      generate code with LLVM or as C
+
+Language:
+  --dialect bf|ook|blub pick the dialect instead of the file extension
+  --map CHARS           use other characters for ><+-.,[]
+  --strict              reject characters that aren't ops
+  --debug-ops           treat # as dumping the tape
+
+Tape and cells:
+  --cells N             number of cells (1000)
+  --start-cell N        cell the pointer starts on
+  --cell-size 8|16|32   width of a cell in bits
+  --signed-cells        sign-extend input and UTF-8 output
+  --wrap-pointer        make the tape circular
+  --grow                reallocate the tape when running off the end
+  --tape=heap|stack|global
+                        where the tape lives
+  --exit-cell           exit with the value of the current cell
+
+Input and output:
+  --output=byte|utf8    how . writes a cell
+  --input-file PATH     read input from PATH
+  --input-fd FD         read input from a file descriptor
+  --output-fd FD        write output to a file descriptor
+  --flush-on-input      flush stdout before each read
+
+Optimization:
+  -O0, -O1, -O2, -O3    LLVM optimization level
+  --no-opt              turn off every optimization
+  --no-pass NAME        skip one BF level pass
+  --loop-hints          tell LLVM loops are likely to run
+
+Debugging:
+  -g                    add DWARF debug info
+  --trace               print every op as it runs
+  --max-steps N         stop after N loop iterations
+  --print-cells N       print the first N cells at the end
+  --verbose             print notes on what the optimizer did
+  --dump-ast            print the ops before and after optimizing
+  --stats               print op counts for each pass
+  --timings             print how long each phase took
+  --color auto|always|never
+                        color error messages
+
+Targets and linking:
+  --target TRIPLE       build for another target
+  --cpu NAME            target cpu, or native
+  --features FEATURES   target features
+  --reloc pic|static|dynamic-no-pic
+  --code-model small|kernel|medium|large
+  --static              link statically
+  --freestanding        use Linux system calls instead of the C runtime
+  --link-arg ARG        pass ARG to the linker
+  -L DIR, -l LIB        add a library directory or library
+
+  -h, --help            print this and exit
+  -V, --version         print the version and exit
+";
+
+/// Prints `error`, with the source line for parse errors, and exits.
+/// Other dialects get no snippet, since their positions point into the
+/// source translated to BF.
+fn fail(error: &CompileError, paths: &[String], options: &CompileOptions, color: bool) -> ! {
+    let snippets = matches!(options.dialect, Dialect::Brainfuck);
+    let read = |path: &str| snippets.then(|| bf_llvm::read_source(path).ok()).flatten();
+    let path = paths.first().map_or("", String::as_str);
+    eprint!("{}", diagnostics::render(error, path, read, color));
+    std::process::exit(1);
 }
 
-impl Lexer {
-    fn new(source: Vec<char>) -> Self {
-        Self {
-            buffer: source,
-            ptr: 0,
-        }
+/// Reads and lexes the files at `paths` as one program, exiting with the
+/// error if it doesn't parse.
+fn lex_files(paths: &[String], options: &CompileOptions, color: bool) -> (Vec<Op>, Lexer) {
+    match bf_llvm::lex_files(paths, options) {
+        Ok(lexed) => lexed,
+        Err(e) => fail(&e, paths, options, color),
     }
+}
 
-    fn peek(&self) -> Option<&char> {
-        return self.buffer.get(self.ptr);
-    }
+/// Prints `message` and the usage text, and exits with status 2, the way
+/// a bad command line is reported.
+fn usage_error(message: &str) -> ! {
+    eprint!("error: {}\n\n{}", message, USAGE);
+    std::process::exit(2);
+}
 
-    fn eat(&mut self) -> Option<&char> {
-        let ptr = self.ptr;
-        self.ptr += 1;
-        return self.buffer.get(ptr);
+/// Exits with `message` as a usage error unless `ok`.
+fn require(ok: bool, message: &str) {
+    if !ok {
+        usage_error(message);
     }
+}
 
-    fn eat_while_same(&mut self, c: &char) -> Op {
-        let mut count = 0;
-        while let Some(ch) = self.peek() {
-            if ch == c {
-                self.eat();
-                count += 1;
-            } else {
-                break;
-            }
-        }
-        match c {
-            '>' => Op::PointerInc(count),
-            '<' => Op::PointerDec(count),
-            '+' => Op::ValueInc(count),
-            '-' => Op::ValueDec(count),
-            t => unreachable!("Illegal character {}", t),
-        }
+fn main() {
+    let mut paths = Vec::new();
+    let mut interpret = false;
+    let mut jit = false;
+    let mut debug = false;
+    let mut minify = false;
+    let mut watching = false;
+    let mut run = false;
+    let mut no_opt = false;
+    let mut color = ColorChoice::Auto;
+    let mut dialect = None;
+    // What to emit, and the file to write it to, or stdout for `None`.
+    let mut emit = None;
+    let mut from_ops = None;
+    let mut options = CompileOptions::default();
+    let cli: Vec<String> = std::env::args().skip(1).collect();
+    if cli.is_empty() {
+        eprint!("{}", USAGE);
+        std::process::exit(2);
     }
-
-    fn get_op(&mut self) -> Option<Op> {
-        let c = if let Some(c) = self.peek() {
-            c.clone()
-        } else {
-            return None;
-        };
-        match c {
-            '>' | '<' | '+' | '-' => Some(self.eat_while_same(&c)),
-            '.' => {
-                self.eat();
-                Some(Op::Output)
+    let mut args = cli.iter().cloned().peekable();
+    let format = args.next_if_eq("fmt").is_some();
+    let interactive = args.next_if_eq("repl").is_some();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return;
             }
-            ',' => {
-                self.eat();
-                Some(Op::Input)
+            "-V" | "--version" => {
+                println!("bf {}", env!("CARGO_PKG_VERSION"));
+                return;
             }
-            '[' => {
-                self.eat();
-                Some(Op::LLoop)
+            "--verbose" => options.verbose = true,
+            "--dump-ast" => options.dump_ast = true,
+            "--stats" => options.stats = true,
+            "--timings" => options.timings = true,
+            "--interpret" => interpret = true,
+            "--jit" => jit = true,
+            "--minify" => minify = true,
+            "--watch" => watching = true,
+            "--run" => run = true,
+            "--no-opt" => no_opt = true,
+            "--color" => {
+                color = args
+                    .next()
+                    .and_then(|v| ColorChoice::from_name(&v))
+                    .unwrap_or_else(|| usage_error("--color takes auto, always or never"));
             }
-            ']' => {
-                self.eat();
-                Some(Op::RLoop)
+            "--dialect" => {
+                dialect = Some(
+                    args.next()
+                        .and_then(|v| Dialect::from_name(&v))
+                        .unwrap_or_else(|| usage_error("--dialect takes bf, ook or blub")),
+                );
             }
-            '\n' | '\r' | ' ' | '\t' => {
-                self.eat().unwrap();
-                self.get_op()
+            "--map" => {
+                dialect = Some(Dialect::Map(
+                    args.next()
+                        .and_then(|v| Substitution::new(&v))
+                        .unwrap_or_else(|| {
+                            usage_error(
+                                "--map takes ><+-.,[]= and the eight characters to use instead",
+                            )
+                        }),
+                ));
             }
-            ch => {
-                if !ch.is_alphanumeric() {
-                    self.eat();
-                    return Some(Op::Proc(ch));
-                }
-                panic!("Illegal character! {}", ch);
+            "-g" => debug = true,
+            "--trace" => options.trace = true,
+            "--debug-ops" => options.debug_ops = true,
+            "--strict" => options.strict = true,
+            "--loop-hints" => options.loop_hints = true,
+            "--wrap-pointer" => options.wrap_pointer = true,
+            "--grow" => options.grow = true,
+            "--reusable" => options.reusable = true,
+            "--exit-cell" => options.exit_cell = true,
+            "--shared" => options.shared = true,
+            "--no-link" => options.no_link = true,
+            "--entry-symbol" => {
+                let name = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--entry-symbol takes a symbol name"));
+                options.entry_symbol = Some(name);
+                options.no_link = true;
             }
-        }
-    }
-
-    fn run(&mut self) -> Vec<Op> {
-        let mut vec = Vec::new();
-        loop {
-            if let Some(op) = self.get_op() {
-                vec.push(op);
-            } else {
-                break;
+            "--static" => options.static_link = true,
+            "--freestanding" => options.freestanding = true,
+            "--link-arg" => {
+                let arg = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--link-arg takes an argument"));
+                options.link_args.push(LinkArg::Raw(arg));
             }
+            "-L" => {
+                let dir = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("-L takes a directory"));
+                options.link_args.push(LinkArg::SearchDir(dir));
+            }
+            "-l" => {
+                let lib = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("-l takes a library name"));
+                options.link_args.push(LinkArg::Lib(lib));
+            }
+            "--header" => {
+                options.header = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--header takes a path")),
+                );
+            }
+            "--target" => {
+                options.target = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--target takes a target triple")),
+                );
+            }
+            "--cpu" => {
+                options.cpu = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--cpu takes a cpu name or native"));
+            }
+            "--features" => {
+                options.features = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--features takes a feature string"));
+            }
+            "--reloc" => {
+                options.reloc_mode = match args.next().as_deref() {
+                    Some("pic") => RelocMode::PIC,
+                    Some("static") => RelocMode::Static,
+                    Some("dynamic-no-pic") => RelocMode::DynamicNoPic,
+                    _ => usage_error("--reloc takes pic, static or dynamic-no-pic"),
+                };
+            }
+            "--code-model" => {
+                options.code_model = match args.next().as_deref() {
+                    Some("small") => CodeModel::Small,
+                    Some("kernel") => CodeModel::Kernel,
+                    Some("medium") => CodeModel::Medium,
+                    Some("large") => CodeModel::Large,
+                    _ => usage_error("--code-model takes small, kernel, medium or large"),
+                };
+            }
+            "--cells" => {
+                options.cells = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|v| *v > 0)
+                    .unwrap_or_else(|| usage_error("--cells takes a positive number"));
+            }
+            "--no-pass" => {
+                let name = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--no-pass takes a pass name"));
+                let pass = options.passes.by_name(&name).unwrap_or_else(|| {
+                    usage_error(
+                        "no such pass, try fold_moves, fold_loops, fold_clears, partial_eval or remove_dead_loops",
+                    )
+                });
+                *pass = false;
+            }
+            "--start-cell" => {
+                options.start_cell = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(|| usage_error("--start-cell takes a cell index"));
+            }
+            "--print-cells" => {
+                options.print_cells = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(|| usage_error("--print-cells takes a number of cells"));
+            }
+            "--max-steps" => {
+                options.max_steps = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(|| usage_error("--max-steps takes a number"));
+            }
+            "--backend=llvm" => options.backend = Backend::Llvm,
+            "--backend=c" => options.backend = Backend::C,
+            "--output=byte" => options.output_encoding = OutputEncoding::Byte,
+            "--output=utf8" => options.output_encoding = OutputEncoding::Utf8,
+            "--tape=heap" => options.tape = TapeStorage::Heap,
+            "--tape=stack" => options.tape = TapeStorage::Stack,
+            "--tape=global" => options.tape = TapeStorage::Global,
+            "--output-fd" => {
+                options.output_fd = Some(
+                    args.next()
+                        .and_then(|v| v.parse().ok())
+                        .filter(|v| *v >= 0)
+                        .unwrap_or_else(|| usage_error("--output-fd takes a file descriptor")),
+                );
+            }
+            "--input-file" => {
+                options.input_file = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--input-file takes a path")),
+                );
+            }
+            "--flush-on-input" => options.flush_on_input = true,
+            "--input-fd" => {
+                options.input_fd = Some(
+                    args.next()
+                        .and_then(|v| v.parse().ok())
+                        .filter(|v| *v >= 0)
+                        .unwrap_or_else(|| usage_error("--input-fd takes a file descriptor")),
+                );
+            }
+            "-O0" => options.opt_level = OptimizationLevel::None,
+            "-O1" => options.opt_level = OptimizationLevel::Less,
+            "-O2" => options.opt_level = OptimizationLevel::Default,
+            "-O3" => options.opt_level = OptimizationLevel::Aggressive,
+            "--cell-size" => {
+                options.cell_bits = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|v| matches!(v, 8 | 16 | 32))
+                    .unwrap_or_else(|| usage_error("--cell-size takes 8, 16 or 32"));
+            }
+            "--signed-cells" => options.signed_cells = true,
+            _ if arg.starts_with("--emit=") => {
+                let spec = &arg["--emit=".len()..];
+                let (kind, path) = match spec.split_once('=') {
+                    Some((kind, path)) => (kind, Some(path.to_string())),
+                    None => (spec, None),
+                };
+                let kind = match kind {
+                    "ops" => Emit::Ops,
+                    "bf" => Emit::Bf,
+                    _ => usage_error("--emit takes ops or bf, optionally followed by =path"),
+                };
+                emit = Some((kind, path));
+            }
+            "--from-ops" => {
+                from_ops = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--from-ops takes a path")),
+                );
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ => paths.push(arg),
         }
-        vec
     }
-}
-
-struct CodeGen<'a> {
-    ctx: &'a Context,
-    builder: Builder<'a>,
-    ptr: VecDeque<PointerValue<'a>>,
-    module: Module<'a>,
-    loops: VecDeque<(BasicBlock<'a>, BasicBlock<'a>)>,
-    ast: Vec<Op>,
-    procs: HashMap<char, Option<FunctionValue<'a>>>,
-}
-
-impl<'a> CodeGen<'a> {
-    fn ptr_manipulate(&mut self, count: usize, dec: bool) {
-        let v = self
-            .builder
-            .build_load(*self.ptr.back().unwrap(), "load_ptr")
-            .unwrap();
-        let mut int_val = self.ctx.i64_type().const_int(count as u64, false);
-        if dec {
-            int_val = int_val.const_neg();
-        }
-        let ptr = unsafe {
-            let inc = self
-                .builder
-                .build_gep(v.into_pointer_value(), &[int_val.into()], "gep");
-            inc.unwrap()
-        };
-        let _ = self.builder.build_store(*self.ptr.back().unwrap(), ptr);
+    if interactive {
+        require(
+            options.start_cell < options.cells,
+            "--start-cell has to be on the tape, below --cells",
+        );
+        repl::repl(&options).unwrap();
+        return;
     }
-
-    fn val_manipulate(&mut self, count: usize, dec: bool) {
-        let v = self
-            .builder
-            .build_load(*self.ptr.back().unwrap(), "load_ptr")
-            .unwrap();
-        let val = self
-            .builder
-            .build_load(v.into_pointer_value(), "load_val")
-            .unwrap();
-        let int_val = self.ctx.i64_type().const_int(count as u64, false);
-        let new_val = if !dec {
-            let val = self
-                .builder
-                .build_int_add(val.into_int_value(), int_val, "add")
-                .unwrap();
-            val
-        } else {
-            let val = self
-                .builder
-                .build_int_sub(val.into_int_value(), int_val, "sub")
-                .unwrap();
-            val
+    if let Some(dump) = &from_ops {
+        require(
+            paths.is_empty(),
+            &format!("--from-ops reads {} instead of source files", dump),
+        );
+        require(
+            !(debug || options.trace || interpret || format || watching || emit.is_some()),
+            "--from-ops can't be combined with -g, --trace, --interpret, fmt, --watch or --emit",
+        );
+    } else {
+        require(!paths.is_empty(), "no source file given");
+    }
+    // Settings on a `#!` line in the first file, unless flags say otherwise.
+    let source = paths
+        .first()
+        .and_then(|path| bf_llvm::read_source(path).ok());
+    let settings = source.as_deref().and_then(directive::read);
+    if let Some(line) = settings {
+        let given = |key: &str| {
+            cli.iter()
+                .filter_map(|arg| arg.strip_prefix("--"))
+                .any(|flag| flag.split('=').next() == Some(key))
         };
-        let _ = self
-            .builder
-            .build_store(v.into_pointer_value(), new_val)
-            .unwrap();
+        for warning in directive::apply(line, &mut options, given) {
+            eprintln!("warning: {}: {}", paths[0], warning);
+        }
     }
-
-    fn out(&mut self) {
-        let v = self
-            .builder
-            .build_load(*self.ptr.back().unwrap(), "load_ptr")
-            .unwrap();
-        let val = self
-            .builder
-            .build_load(v.into_pointer_value(), "load_val")
-            .unwrap();
-        let putchar = self.module.get_function("putchar").unwrap();
-        let _call = self
-            .builder
-            .build_call(putchar, &[val.into()], "out")
-            .unwrap();
+    // Wins over any -O, wherever it is on the command line.
+    if no_opt {
+        options.passes = Passes::NONE;
+        options.opt_level = OptimizationLevel::None;
     }
-
-    fn input(&mut self) {
-        let v = self
-            .builder
-            .build_load(*self.ptr.back().unwrap(), "load_ptr")
-            .unwrap();
-        let putchar = self.module.get_function("getchar").unwrap();
-        let call = self
-            .builder
-            .build_call(putchar, &[], "in")
-            .unwrap()
-            .try_as_basic_value()
-            .left()
-            .unwrap();
-        let _ = self.builder.build_store(v.into_pointer_value(), call);
+    require(
+        !(options.grow && (options.wrap_pointer || options.shared)),
+        "--grow can't be combined with --wrap-pointer or --shared",
+    );
+    require(
+        !(options.grow && options.tape != TapeStorage::Heap),
+        "--grow needs --tape=heap",
+    );
+    require(
+        options.start_cell < options.cells,
+        "--start-cell has to be on the tape, below --cells",
+    );
+    require(
+        !(options.static_link && options.shared),
+        "--static can't be combined with --shared",
+    );
+    require(
+        !(options.reusable && (options.grow || options.shared)),
+        "--reusable can't be combined with --grow or --shared",
+    );
+    require(
+        !(options.entry_symbol.is_some() && options.shared),
+        "--entry-symbol can't be combined with --shared",
+    );
+    let triple = options.target_triple();
+    if triple.starts_with("wasm32") {
+        require(
+            !(options.grow || options.shared),
+            "--grow and --shared aren't supported for WebAssembly",
+        );
     }
-
-    fn loop_start(&mut self) {
-        let start_block = self.builder.get_insert_block().unwrap();
-        let main = start_block.get_parent().unwrap();
-        let cond_block = self.ctx.append_basic_block(main, "cond_block");
-        let body_block = self.ctx.append_basic_block(main, "body_block");
-        let end_block = self.ctx.append_basic_block(main, "end_block");
-        self.loops.push_back((cond_block, end_block));
-        self.builder.build_unconditional_branch(cond_block);
-        self.builder.position_at_end(cond_block);
-        let v = self
-            .builder
-            .build_load(*self.ptr.back().unwrap(), "load_ptr")
-            .unwrap();
-        let val = self
-            .builder
-            .build_load(v.into_pointer_value(), "load_val")
-            .unwrap();
-        let comp = self
-            .builder
-            .build_int_compare(
-                inkwell::IntPredicate::NE,
-                val.into_int_value(),
-                self.ctx.i8_type().const_zero(),
-                "ne_zero",
-            )
-            .unwrap();
-        self.builder
-            .build_conditional_branch(comp, body_block, end_block);
-        self.builder.position_at_end(body_block);
+    if options.freestanding {
+        require(
+            triple.contains("linux")
+                && (triple.starts_with("x86_64") || triple.starts_with("aarch64")),
+            "--freestanding is only supported on Linux, for x86_64 and aarch64",
+        );
+        let combined = options.shared
+            || options.entry_symbol.is_some()
+            || options.grow
+            || options.tape == TapeStorage::Stack;
+        require(
+            !combined,
+            "--freestanding can't be combined with --shared, --entry-symbol, --grow or --tape=stack",
+        );
+        require(
+            !(options.trace
+                || options.debug_ops
+                || options.max_steps > 0
+                || options.print_cells > 0),
+            "--trace, --debug-ops, --max-steps and --print-cells need a C runtime",
+        );
     }
-
-    fn loop_end(&mut self) {
-        let (cond_block, end_block) = self.loops.pop_back().unwrap();
-        self.builder.build_unconditional_branch(cond_block);
-        self.builder.position_at_end(end_block);
+    if triple.starts_with("wasm32") && !triple.contains("wasi") {
+        require(
+            !(options.trace
+                || options.debug_ops
+                || options.max_steps > 0
+                || options.print_cells > 0),
+            "--trace, --debug-ops, --max-steps and --print-cells need a C runtime, try wasm32-wasi",
+        );
     }
-
-    fn proc(&mut self, ident: char) {
-        match self.procs.get(&ident) {
-            None => {
-                let i8_ptr = self.ctx.i8_type().ptr_type(AddressSpace::default());
-                let f = self.module.add_function(
-                    &ident.to_string(),
-                    self.ctx.void_type().fn_type(&[i8_ptr.into()], false),
-                    None,
-                );
-                let ptr= f.get_first_param().unwrap();
-                
-                let entry = self.ctx.append_basic_block(f, "entry");
-                self.builder.position_at_end(entry);
-                let ptr_val = self.builder.build_alloca(i8_ptr, "ptr").unwrap();
-                self.builder.build_store(ptr_val, ptr).unwrap();
-                self.ptr.push_back(ptr_val);
-
-                self.procs.insert(ident, None);
+    if debug {
+        options.debug_source = Some(paths[0].clone());
+    }
+    if let Some(path) = paths.first() {
+        options.dialect = dialect.unwrap_or_else(|| Dialect::from_path(path));
+    }
+    let color = color.enabled();
+    if format {
+        let (ast, mut lexer) = lex_files(&paths, &options, color);
+        if let Some(line) = settings {
+            println!("#!{}", line);
+        }
+        print!("{}", formatter::format(&ast, minify));
+        if let Some(input) = lexer.take_input() {
+            print!("!{}", input);
+        }
+        return;
+    }
+    if interpret {
+        let (ast, mut lexer) = lex_files(&paths, &options, color);
+        let ast = optimize(ast, &options);
+        let input = match (&options.input_file, lexer.take_input()) {
+            (Some(path), _) => std::fs::read(path).expect("can't read --input-file"),
+            (None, Some(input)) => input.into_bytes(),
+            (None, None) => {
+                let mut input = Vec::new();
+                std::io::stdin().read_to_end(&mut input).unwrap();
+                input
             }
-            Some(None) => {
-                self.builder.build_return(None).unwrap();
-                self.ptr.pop_back().unwrap();
-                let main = self.module.get_function("main").unwrap();
-                let last_block = *main.get_basic_blocks().last().unwrap();
-                let f = self.module.get_function(&ident.to_string()).unwrap();
-                self.builder.position_at_end(last_block);
-                self.procs.insert(ident, Some(f));
+        };
+        let tape = interpreter::Tape::of(&options);
+        match interpreter::interpret(&ast, &input, tape, interpreter::Limits::default()) {
+            Ok(output) => {
+                let bytes = interpreter::encode(&output, options.output_encoding);
+                std::io::stdout().write_all(&bytes).unwrap()
             }
-            Some(Some(f)) => {
-                let ptr = self.builder.build_load(*self.ptr.back().unwrap(), "load_ptr").unwrap();
-                self.builder.build_call(
-                    *f, &[inkwell::values::BasicMetadataValueEnum::PointerValue(ptr.into_pointer_value())],
-                    &ident.to_string(),
-                ).unwrap();
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
             }
         }
+        return;
     }
-
-    fn new(ctx: &'a Context, ast: Vec<Op>) -> Self {
-        let builder = ctx.create_builder();
-        let module = ctx.create_module("main");
-        let i8_type = ctx.i8_type();
-        let i32_type = ctx.i32_type();
-        let i8_ptr = i8_type.ptr_type(AddressSpace::default());
-        let i64_type = ctx.i64_type();
-        let _putchar =
-            module.add_function("putchar", i8_type.fn_type(&[i32_type.into()], false), None);
-        let _getchar = module.add_function("getchar", i8_type.fn_type(&[], false), None);
-        let calloc = module.add_function(
-            "calloc",
-            i8_ptr.fn_type(&[i64_type.into(), i64_type.into()], false),
-            None,
-        );
-        let fn_type = i8_type.fn_type(&[], false);
-        let func = module.add_function("main", fn_type, None);
-        let block = ctx.append_basic_block(func, "entry");
-        builder.position_at_end(block);
-
-        let ptr_val = builder.build_alloca(i8_ptr, "ptr").unwrap();
-
-        let args = (
-            i64_type.const_int(1000, false),
-            i64_type.const_int(1, false),
-        );
-        let calloc_block = builder
-            .build_call(calloc, &[args.0.into(), args.1.into()], "block")
-            .unwrap()
-            .try_as_basic_value()
-            .left();
-        let _i = builder.build_store(ptr_val, calloc_block.unwrap()).unwrap();
-        Self {
-            ctx: &ctx,
-            builder,
-            ptr: VecDeque::from([ptr_val]),
-            module,
-            loops: VecDeque::new(),
-            ast,
-            procs: HashMap::new(),
+    if jit {
+        require(paths.len() == 1, "--jit runs a single source file");
+        let result = bf_llvm::read_source(&paths[0])
+            .map_err(CompileError::from)
+            .and_then(|source| jit::run_jit_stdio(&source, &options));
+        match result {
+            Ok(code) => std::process::exit(code),
+            Err(e) => fail(&e, &paths, &options, color),
         }
     }
-
-    pub fn run(&mut self) {
-        for op in self.ast.clone().drain(..) {
-            match op {
-                Op::PointerInc(v) => {
-                    self.ptr_manipulate(v, false);
-                }
-                Op::PointerDec(v) => {
-                    self.ptr_manipulate(v, true);
-                }
-                Op::ValueInc(v) => {
-                    self.val_manipulate(v, false);
-                }
-                Op::ValueDec(v) => {
-                    self.val_manipulate(v, true);
-                }
-                Op::Output => {
-                    self.out();
-                }
-                Op::Input => {
-                    self.input();
-                }
-                Op::LLoop => {
-                    self.loop_start();
-                }
-                Op::RLoop => {
-                    self.loop_end();
-                }
-                Op::Proc(ident) => {
-                    self.proc(ident);
+    if let Some((kind, out)) = emit {
+        let (ast, mut lexer) = lex_files(&paths, &options, color);
+        let ast = optimize(ast, &options);
+        let text = match kind {
+            Emit::Ops => dump::to_json(&ast),
+            Emit::Bf => {
+                let mut source = formatter::format(&ast, minify);
+                if let Some(input) = lexer.take_input() {
+                    source.push('!');
+                    source.push_str(&input);
                 }
+                source
             }
+        };
+        match out {
+            Some(path) => std::fs::write(path, text).expect("can't write --emit output"),
+            None => print!("{}", text),
         }
-        let _ret = self
-            .builder
-            .build_return(Some(&self.ctx.i8_type().const_int(0, false)));
+        return;
     }
-
-    pub fn generate_machine_code(&self, path: &str) {
-        Target::initialize_all(&InitializationConfig::default());
-        let target_triple = TargetMachine::get_default_triple();
-        let target = Target::from_triple(&target_triple).unwrap();
-        let reloc_model = RelocMode::PIC;
-        let code_model = CodeModel::Default;
-        let opt_level = OptimizationLevel::Aggressive;
-        let target_machine = target
-            .create_target_machine(
-                &target_triple,
-                "generic",
-                "",
-                opt_level,
-                reloc_model,
-                code_model,
-            )
-            .unwrap();
-        let file_type = FileType::Object;
-        target_machine
-            .write_to_file(&self.module, file_type, Path::new(path))
-            .unwrap();
-
-        let mut command = Command::new("link");
-        command
-            .arg(path)
-            .arg("/entry:main")
-            .arg("/out:main.exe")
-            .arg("ucrt.lib");
-        let r = command.output().unwrap();
+    if watching {
+        watch::watch(&paths, &options, run);
+    }
+    let compiled = match &from_ops {
+        Some(dump) => bf_llvm::compile_ops_file(dump, &options),
+        None => bf_llvm::compile_files(&paths, &options),
+    };
+    match compiled {
+        Ok(timings) if options.timings => eprint!("timings:\n{}", timings),
+        Ok(_) => {}
+        Err(e) => fail(&e, &paths, &options, color),
+    }
+    if run && !options.no_link && !options.shared {
+        let status = watch::run_program().expect("can't run the program");
+        std::process::exit(status.code().unwrap_or(1));
     }
-}
-
-fn main() {
-    let path = std::env::args().nth(1).unwrap();
-    let file = fs::read_to_string(path).unwrap();
-    let mut lexer = Lexer::new(file.chars().collect());
-    let ast = lexer.run();
-    let ctx = Context::create();
-    let mut cdg = CodeGen::new(&ctx, ast);
-    cdg.run();
-    cdg.generate_machine_code("main.o");
 }
@@ -0,0 +1,45 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    // BF
+    PointerInc(usize),
+    PointerDec(usize),
+    ValueInc(usize),
+    ValueDec(usize),
+    Output(usize),
+    Input,
+    LLoop,
+    RLoop,
+    // Optimizations
+    ScanInc(usize),
+    ScanDec(usize),
+    Set(u8),
+    /// Writes the values of byte cells, worked out at compile time.
+    Print(Vec<u8>),
+    /// Zeroes this many cells starting at the pointer, without moving it.
+    Clear(usize),
+    // BF++
+    ProcDef(String),
+    ProcCall(String),
+    ProcEnd,
+    // Debugging
+    /// Prints the cells around the pointer to stderr. Only lexed from `#`
+    /// when debug ops are enabled.
+    DumpTape,
+}
+
+/// The UTF-8 that `--output=utf8` writes for the byte cell values of a
+/// `Print`. With `signed_cells` the values from 0x80 up are negative, so
+/// like any other negative cell they come out as U+FFFD.
+pub fn print_utf8(bytes: &[u8], signed_cells: bool) -> Vec<u8> {
+    bytes
+        .iter()
+        .map(|&b| {
+            if signed_cells && b >= 0x80 {
+                '\u{FFFD}'
+            } else {
+                b as char
+            }
+        })
+        .collect::<String>()
+        .into_bytes()
+}
@@ -0,0 +1,739 @@
+use crate::ops::Op;
+use crate::stats::Stats;
+use crate::CompileOptions;
+
+/// Which passes `optimize` runs, all of them by default. Passes that don't
+/// apply to the tape, like `fold_clears` with `wrap_pointer`, are skipped
+/// either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Passes {
+    pub fold_moves: bool,
+    pub fold_loops: bool,
+    pub fold_clears: bool,
+    pub partial_eval: bool,
+    pub remove_dead_loops: bool,
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Passes {
+            fold_moves: true,
+            fold_loops: true,
+            fold_clears: true,
+            partial_eval: true,
+            remove_dead_loops: true,
+        }
+    }
+}
+
+impl Passes {
+    /// No passes at all, leaving the op stream as lexed.
+    pub const NONE: Passes = Passes {
+        fold_moves: false,
+        fold_loops: false,
+        fold_clears: false,
+        partial_eval: false,
+        remove_dead_loops: false,
+    };
+
+    /// The switch for the pass called `name`, `None` if there is no such
+    /// pass.
+    pub fn by_name(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "fold_moves" => Some(&mut self.fold_moves),
+            "fold_loops" => Some(&mut self.fold_loops),
+            "fold_clears" => Some(&mut self.fold_clears),
+            "partial_eval" => Some(&mut self.partial_eval),
+            "remove_dead_loops" => Some(&mut self.remove_dead_loops),
+            _ => None,
+        }
+    }
+}
+
+/// Merges runs of pointer moves (`>>><<`) into their net move, dropping
+/// runs that end where they started.
+fn fold_moves(ast: Vec<Op>) -> Vec<Op> {
+    let mut out = Vec::with_capacity(ast.len());
+    let mut ops = ast.into_iter().peekable();
+    while let Some(op) = ops.next() {
+        let mut net = match op {
+            Op::PointerInc(v) => v as isize,
+            Op::PointerDec(v) => -(v as isize),
+            op => {
+                out.push(op);
+                continue;
+            }
+        };
+        while let Some(op) = ops.next_if(|op| matches!(op, Op::PointerInc(_) | Op::PointerDec(_))) {
+            match op {
+                Op::PointerInc(v) => net += v as isize,
+                Op::PointerDec(v) => net -= v as isize,
+                _ => unreachable!(),
+            }
+        }
+        match net {
+            0 => {}
+            n if n > 0 => out.push(Op::PointerInc(n as usize)),
+            n => out.push(Op::PointerDec(n.unsigned_abs())),
+        }
+    }
+    out
+}
+
+/// Replaces loops whose body is a single pointer move (`[>]`, `[<<]`, ...)
+/// with a scan op that searches for the next zero cell, and clear loops
+/// (`[-]`, `[+]`) with a store of zero.
+fn fold_loops(ast: Vec<Op>) -> Vec<Op> {
+    let mut out = Vec::with_capacity(ast.len());
+    let mut i = 0;
+    while i < ast.len() {
+        match &ast[i..] {
+            [Op::LLoop, Op::PointerInc(v), Op::RLoop, ..] => {
+                out.push(Op::ScanInc(*v));
+                i += 3;
+            }
+            [Op::LLoop, Op::PointerDec(v), Op::RLoop, ..] => {
+                out.push(Op::ScanDec(*v));
+                i += 3;
+            }
+            [Op::LLoop, Op::ValueInc(1) | Op::ValueDec(1), Op::RLoop, ..] => {
+                out.push(Op::Set(0));
+                i += 3;
+            }
+            _ => {
+                out.push(ast[i].clone());
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Turns runs of clears walking right (`[-]>[-]>[-]`) into a single
+/// `Clear`, which codegen emits as a memset. Runs on folded loops, so the
+/// clears are `Set(0)` already.
+fn fold_clears(ast: Vec<Op>) -> Vec<Op> {
+    let mut out = Vec::with_capacity(ast.len());
+    let mut i = 0;
+    while i < ast.len() {
+        let mut cells = 0;
+        let mut moved = 0;
+        let mut end = i;
+        while let Some(Op::Set(0)) = ast.get(end) {
+            cells += 1;
+            end += 1;
+            if let Some(Op::PointerInc(1)) = ast.get(end) {
+                moved += 1;
+                end += 1;
+            } else {
+                break;
+            }
+        }
+        if cells < 2 {
+            out.push(ast[i].clone());
+            i += 1;
+            continue;
+        }
+        out.push(Op::Clear(cells));
+        if moved > 0 {
+            out.push(Op::PointerInc(moved));
+        }
+        i = end;
+    }
+    out
+}
+
+/// Net pointer movement of one iteration of the loop opening at
+/// `ops[start]`. `None` if that can't be known statically: the body scans,
+/// defines a proc or holds a nested loop that isn't balanced itself.
+fn loop_pointer_balance(ops: &[Op], start: usize) -> Option<isize> {
+    if !matches!(ops.get(start), Some(Op::LLoop)) {
+        return None;
+    }
+    // Movement so far in each loop we're inside, innermost last.
+    let mut balances = vec![0isize];
+    for op in &ops[start + 1..] {
+        match op {
+            Op::PointerInc(v) => *balances.last_mut().unwrap() += *v as isize,
+            Op::PointerDec(v) => *balances.last_mut().unwrap() -= *v as isize,
+            Op::LLoop => balances.push(0),
+            Op::RLoop => {
+                let balance = balances.pop().unwrap();
+                if balances.is_empty() {
+                    return Some(balance);
+                }
+                if balance != 0 {
+                    return None;
+                }
+            }
+            Op::ScanInc(_) | Op::ScanDec(_) | Op::ProcDef(_) | Op::ProcEnd => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Drops loops that can never be entered because the current cell is known
+/// to be zero: at program start if `tape_zeroed`, right after another loop
+/// and after `Set(0)`. Returns the new op stream and the number of removed
+/// loops.
+fn remove_dead_loops(ast: Vec<Op>, tape_zeroed: bool) -> (Vec<Op>, usize) {
+    let mut out = Vec::with_capacity(ast.len());
+    let mut removed = 0;
+    let mut known_zero = tape_zeroed;
+    let mut i = 0;
+    while i < ast.len() {
+        match ast[i] {
+            Op::LLoop if known_zero => {
+                let mut depth = 0;
+                let mut end = i;
+                for (j, op) in ast.iter().enumerate().skip(i) {
+                    match op {
+                        Op::LLoop => depth += 1,
+                        Op::RLoop => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        end = j;
+                        break;
+                    }
+                }
+                // Procs are defined where they appear, executed or not, so a
+                // loop holding a proc marker has to stay.
+                let body = &ast[i..=end];
+                if depth == 0 && !body.iter().any(|op| matches!(op, Op::ProcDef(_))) {
+                    removed += 1;
+                    i = end + 1;
+                    continue;
+                }
+                known_zero = false;
+            }
+            Op::ScanInc(_) | Op::ScanDec(_) if known_zero => {
+                removed += 1;
+                i += 1;
+                continue;
+            }
+            Op::LLoop => known_zero = false,
+            Op::RLoop | Op::ScanInc(_) | Op::ScanDec(_) => known_zero = true,
+            Op::Set(v) => known_zero = v == 0,
+            Op::Clear(_) => known_zero = true,
+            Op::Output(_) | Op::Print(_) | Op::DumpTape => {}
+            Op::PointerInc(_)
+            | Op::PointerDec(_)
+            | Op::ValueInc(_)
+            | Op::ValueDec(_)
+            | Op::Input
+            | Op::ProcDef(_)
+            | Op::ProcCall(_)
+            | Op::ProcEnd => known_zero = false,
+        }
+        out.push(ast[i].clone());
+        i += 1;
+    }
+    (out, removed)
+}
+
+/// For each op, whether it is a `[` whose cell is known not to be zero, so
+/// the loop runs at least once and can be tested at the bottom only. The
+/// current cell's value is followed through straight-line code from
+/// `Set`, clears and loop exits, and from program start if `tape_zeroed`,
+/// wrapping at `cell_bits` like the generated code.
+pub fn entered_loops(ast: &[Op], cell_bits: u32, tape_zeroed: bool) -> Vec<bool> {
+    let mask = (1u64 << cell_bits) - 1;
+    let mut known = tape_zeroed.then_some(0u64);
+    ast.iter()
+        .map(|op| {
+            let entered = matches!(op, Op::LLoop) && matches!(known, Some(v) if v != 0);
+            known = match *op {
+                Op::ValueInc(v) => known.map(|k| k.wrapping_add(v as u64) & mask),
+                Op::ValueDec(v) => known.map(|k| k.wrapping_sub(v as u64) & mask),
+                Op::Set(v) => Some(v as u64 & mask),
+                Op::Clear(_) | Op::RLoop | Op::ScanInc(_) | Op::ScanDec(_) => Some(0),
+                Op::Output(_) | Op::Print(_) | Op::DumpTape => known,
+                Op::PointerInc(_)
+                | Op::PointerDec(_)
+                | Op::Input
+                | Op::LLoop
+                | Op::ProcDef(_)
+                | Op::ProcCall(_)
+                | Op::ProcEnd => None,
+            };
+            entered
+        })
+        .collect()
+}
+
+/// Upper bound on the ops `partial_eval` executes, so compiling a program
+/// with a long-running prefix doesn't take forever.
+const PARTIAL_EVAL_STEPS: usize = 100_000;
+
+/// Runs `ops` on a compile-time tape. Returns false if it hits something that
+/// can't be evaluated (input, procs, leaving the tape or the step budget), in
+/// which case the state is left half-updated.
+fn eval(
+    ops: &[Op],
+    tape: &mut [u8],
+    ptr: &mut usize,
+    output: &mut Vec<u8>,
+    steps: &mut usize,
+) -> bool {
+    let mut jumps = vec![0; ops.len()];
+    let mut stack = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::LLoop => stack.push(i),
+            Op::RLoop => {
+                let Some(start) = stack.pop() else {
+                    return false;
+                };
+                jumps[start] = i;
+                jumps[i] = start;
+            }
+            _ => {}
+        }
+    }
+    if !stack.is_empty() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < ops.len() {
+        if *steps == 0 {
+            return false;
+        }
+        *steps -= 1;
+        match &ops[i] {
+            Op::PointerInc(v) => {
+                if *ptr + v >= tape.len() {
+                    return false;
+                }
+                *ptr += v;
+            }
+            Op::PointerDec(v) => {
+                if *v > *ptr {
+                    return false;
+                }
+                *ptr -= v;
+            }
+            Op::ValueInc(v) => tape[*ptr] = tape[*ptr].wrapping_add(*v as u8),
+            Op::ValueDec(v) => tape[*ptr] = tape[*ptr].wrapping_sub(*v as u8),
+            Op::Output(v) => output.resize(output.len() + v, tape[*ptr]),
+            Op::Set(v) => tape[*ptr] = *v,
+            Op::Print(bytes) => output.extend_from_slice(bytes),
+            Op::Clear(v) => {
+                if *ptr + v > tape.len() {
+                    return false;
+                }
+                tape[*ptr..*ptr + v].fill(0);
+            }
+            Op::ScanInc(v) | Op::ScanDec(v) => {
+                let dec = matches!(ops[i], Op::ScanDec(_));
+                while tape[*ptr] != 0 {
+                    if *steps == 0 || (dec && *v > *ptr) || (!dec && *ptr + v >= tape.len()) {
+                        return false;
+                    }
+                    *steps -= 1;
+                    if dec {
+                        *ptr -= v;
+                    } else {
+                        *ptr += v;
+                    }
+                }
+            }
+            Op::LLoop => {
+                if tape[*ptr] == 0 {
+                    i = jumps[i];
+                }
+            }
+            Op::RLoop => {
+                if tape[*ptr] != 0 {
+                    i = jumps[i];
+                }
+            }
+            Op::Input | Op::ProcDef(_) | Op::ProcCall(_) | Op::ProcEnd | Op::DumpTape => {
+                return false
+            }
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Executes the prefix of the program that doesn't depend on input at compile
+/// time, and replaces it with a single constant write of its output followed
+/// by stores of the resulting tape.
+fn partial_eval(ast: Vec<Op>, cells: u64) -> Vec<Op> {
+    let mut tape = vec![0u8; cells as usize];
+    let mut ptr = 0;
+    let mut output = Vec::new();
+    let mut steps = PARTIAL_EVAL_STEPS;
+
+    let mut i = 0;
+    while i < ast.len() {
+        let end = match ast[i] {
+            Op::LLoop => {
+                let mut depth = 0;
+                let end = ast[i..].iter().position(|op| {
+                    match op {
+                        Op::LLoop => depth += 1,
+                        Op::RLoop => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                });
+                match end {
+                    Some(end) => i + end,
+                    None => break,
+                }
+            }
+            _ => i,
+        };
+        let snapshot = (tape.clone(), ptr, output.len());
+        if !eval(&ast[i..=end], &mut tape, &mut ptr, &mut output, &mut steps) {
+            tape = snapshot.0;
+            ptr = snapshot.1;
+            output.truncate(snapshot.2);
+            break;
+        }
+        i = end + 1;
+    }
+    if i == 0 {
+        return ast;
+    }
+
+    let mut out = Vec::new();
+    if !output.is_empty() {
+        out.push(Op::Print(output));
+    }
+    let mut at = 0;
+    let targets = tape
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| **v != 0)
+        .map(|(idx, v)| (idx, Some(*v)))
+        .chain(std::iter::once((ptr, None)));
+    for (idx, value) in targets {
+        if idx > at {
+            out.push(Op::PointerInc(idx - at));
+        } else if idx < at {
+            out.push(Op::PointerDec(at - idx));
+        }
+        at = idx;
+        if let Some(value) = value {
+            out.push(Op::Set(value));
+        }
+    }
+    out.extend_from_slice(&ast[i..]);
+    out
+}
+
+fn dump(label: &str, ast: &[Op]) {
+    eprintln!("{}:", label);
+    for op in ast {
+        eprintln!("    {:?}", op);
+    }
+}
+
+/// With `--stats`, prints how many ops are left after `pass`.
+fn pass_stats(options: &CompileOptions, pass: &str, ast: &[Op]) {
+    if options.stats {
+        eprintln!("stats: {} ops after {}", ast.len(), pass);
+    }
+}
+
+pub fn optimize(ast: Vec<Op>, options: &CompileOptions) -> Vec<Op> {
+    if options.dump_ast {
+        dump("ast before optimization", &ast);
+    }
+    if options.stats {
+        eprint!("stats before optimization:\n{}", Stats::of(&ast));
+    }
+    let passes = options.passes;
+    let mut ast = ast;
+    // First, so that `[>><]` is a scan by the time loops are folded.
+    if passes.fold_moves {
+        ast = fold_moves(ast);
+        pass_stats(options, "fold_moves", &ast);
+    }
+    if passes.fold_loops {
+        ast = fold_loops(ast);
+        pass_stats(options, "fold_loops", &ast);
+    }
+    // A cleared run could wrap around the end of a circular tape, or run
+    // past the end of a growing one, which a single memset can't handle.
+    if passes.fold_clears && !options.wrap_pointer && !options.grow {
+        ast = fold_clears(ast);
+        pass_stats(options, "fold_clears", &ast);
+    }
+    // `bf_run` starts on whatever tape it is given, which can't be known at
+    // compile time.
+    let tape_zeroed = !options.reusable;
+    // Evaluation treats the pointer's start as cell 0, so only the cells
+    // from there on are known to exist, none if it starts off the tape.
+    let known_cells = options.cells.saturating_sub(options.start_cell);
+    // Compile-time evaluation assumes byte cells.
+    if passes.partial_eval && options.cell_bits == 8 && tape_zeroed && known_cells > 0 {
+        ast = partial_eval(ast, known_cells);
+        pass_stats(options, "partial_eval", &ast);
+    }
+    let mut removed = 0;
+    if passes.remove_dead_loops {
+        (ast, removed) = remove_dead_loops(ast, tape_zeroed);
+        pass_stats(options, "remove_dead_loops", &ast);
+    }
+    if options.verbose {
+        eprintln!("note: removed {} dead loop(s)", removed);
+        let unbalanced = (0..ast.len())
+            .filter(|&i| matches!(ast[i], Op::LLoop) && loop_pointer_balance(&ast, i) != Some(0))
+            .count();
+        if unbalanced > 0 {
+            eprintln!(
+                "note: {} loop(s) move the pointer by an unknown or non-zero amount",
+                unbalanced
+            );
+        }
+    }
+    if options.dump_ast {
+        dump("ast after optimization", &ast);
+    }
+    if options.stats {
+        eprint!("stats after optimization:\n{}", Stats::of(&ast));
+    }
+    ast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Op::*;
+
+    #[test]
+    fn removes_loop_at_program_start() {
+        let ast = vec![LLoop, ValueInc(1), Output(1), RLoop, ValueInc(2)];
+        assert_eq!(remove_dead_loops(ast, true), (vec![ValueInc(2)], 1));
+    }
+
+    #[test]
+    fn optimize_drops_leading_loop() {
+        let options = CompileOptions {
+            passes: Passes {
+                remove_dead_loops: true,
+                ..Passes::NONE
+            },
+            ..Default::default()
+        };
+        let ast = crate::lexer::parse("[-[>.<]]+.").unwrap();
+        assert_eq!(optimize(ast, &options), vec![ValueInc(1), Output(1)]);
+    }
+
+    #[test]
+    fn removes_loop_right_after_a_loop() {
+        let ast = vec![
+            ValueInc(1),
+            LLoop,
+            ValueDec(1),
+            RLoop,
+            LLoop,
+            Output(1),
+            RLoop,
+        ];
+        let kept = vec![ValueInc(1), LLoop, ValueDec(1), RLoop];
+        assert_eq!(remove_dead_loops(ast, true), (kept, 1));
+    }
+
+    #[test]
+    fn removes_loop_after_clear() {
+        let ast = vec![ValueInc(1), Set(0), LLoop, ValueInc(1), RLoop];
+        assert_eq!(remove_dead_loops(ast, true), (vec![ValueInc(1), Set(0)], 1));
+    }
+
+    #[test]
+    fn keeps_leading_loop_on_given_tape() {
+        let ast = vec![LLoop, Output(1), RLoop, LLoop, Input, RLoop];
+        let kept = vec![LLoop, Output(1), RLoop];
+        assert_eq!(remove_dead_loops(ast, false), (kept, 1));
+    }
+
+    #[test]
+    fn keeps_loops_on_unknown_cells() {
+        let ast = vec![
+            Input,
+            LLoop,
+            Output(1),
+            RLoop,
+            Set(3),
+            LLoop,
+            ValueDec(1),
+            RLoop,
+        ];
+        assert_eq!(remove_dead_loops(ast.clone(), true), (ast, 0));
+    }
+
+    #[test]
+    fn keeps_loop_defining_a_proc() {
+        let ast = vec![LLoop, ProcDef("a".into()), ProcEnd, RLoop];
+        assert_eq!(remove_dead_loops(ast.clone(), true), (ast, 0));
+    }
+
+    #[test]
+    fn balanced_loop() {
+        let ops = vec![
+            LLoop,
+            ValueDec(1),
+            PointerInc(2),
+            ValueInc(1),
+            PointerDec(2),
+            RLoop,
+        ];
+        assert_eq!(loop_pointer_balance(&ops, 0), Some(0));
+    }
+
+    #[test]
+    fn unbalanced_loop() {
+        let ops = vec![ValueInc(1), LLoop, PointerInc(3), PointerDec(1), RLoop];
+        assert_eq!(loop_pointer_balance(&ops, 1), Some(2));
+    }
+
+    #[test]
+    fn nested_balanced_loop_is_looked_through() {
+        let ops = vec![
+            LLoop,
+            PointerDec(1),
+            LLoop,
+            PointerInc(1),
+            ValueInc(1),
+            PointerDec(1),
+            RLoop,
+            RLoop,
+        ];
+        assert_eq!(loop_pointer_balance(&ops, 0), Some(-1));
+        assert_eq!(loop_pointer_balance(&ops, 2), Some(0));
+    }
+
+    #[test]
+    fn unknown_balance() {
+        // The inner loop moves the pointer by a different amount each time
+        // round, as does a scan.
+        let nested = vec![LLoop, LLoop, PointerInc(1), RLoop, RLoop];
+        assert_eq!(loop_pointer_balance(&nested, 0), None);
+        let scan = vec![LLoop, ScanInc(1), RLoop];
+        assert_eq!(loop_pointer_balance(&scan, 0), None);
+        let unclosed = vec![LLoop, PointerInc(1)];
+        assert_eq!(loop_pointer_balance(&unclosed, 0), None);
+        assert_eq!(loop_pointer_balance(&scan, 1), None);
+    }
+
+    fn parse(source: &str) -> Vec<Op> {
+        crate::lexer::parse(source).unwrap()
+    }
+
+    #[test]
+    fn folds_moves() {
+        assert_eq!(fold_moves(parse(">>><<")), [PointerInc(1)]);
+        assert_eq!(fold_moves(parse("<<<>")), [PointerDec(2)]);
+        assert_eq!(fold_moves(parse(">><<")), []);
+        assert_eq!(
+            fold_moves(parse("+>><<.<>>")),
+            [ValueInc(1), Output(1), PointerInc(1)]
+        );
+    }
+
+    #[test]
+    fn folds_scans_and_clears() {
+        assert_eq!(
+            fold_loops(parse("[>>][<][-][+]")),
+            [ScanInc(2), ScanDec(1), Set(0), Set(0)]
+        );
+        // Only loops of exactly one move or one step.
+        let kept = parse("[--][>+][<>]");
+        assert_eq!(fold_loops(kept.clone()), kept);
+    }
+
+    #[test]
+    fn folds_runs_of_clears() {
+        let ast = fold_loops(parse("[-]>[-]>[-]+"));
+        assert_eq!(fold_clears(ast), [Clear(3), PointerInc(2), ValueInc(1)]);
+        let ast = fold_loops(parse("[-]>[-]>"));
+        assert_eq!(fold_clears(ast), [Clear(2), PointerInc(2)]);
+        // A single clear, or clears walking left, stay as they are.
+        let single = fold_loops(parse("[-]>+"));
+        assert_eq!(fold_clears(single.clone()), single);
+        let left = fold_loops(parse("[-]<[-]"));
+        assert_eq!(fold_clears(left.clone()), left);
+    }
+
+    #[test]
+    fn evaluates_prefix() {
+        // The loop multiplies, then input stops evaluation.
+        let ast = parse("++[>+++<-]>.>+,.");
+        assert_eq!(
+            partial_eval(ast, 10),
+            [
+                Print(vec![6]),
+                PointerInc(1),
+                Set(6),
+                PointerInc(1),
+                Set(1),
+                Input,
+                Output(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn evaluates_whole_program() {
+        let ast = parse("+++.[-]>++.");
+        assert_eq!(
+            partial_eval(ast, 10),
+            [Print(vec![3, 2]), PointerInc(1), Set(2)]
+        );
+    }
+
+    #[test]
+    fn stops_evaluating_where_it_must() {
+        // Nothing to do before input, or before a proc.
+        let input = parse(",+.");
+        assert_eq!(partial_eval(input.clone(), 10), input);
+        let proc = parse("@p{+}@p;.");
+        assert_eq!(partial_eval(proc.clone(), 10), proc);
+        // Leaving the tape, in either direction.
+        let left = parse("<+.");
+        assert_eq!(partial_eval(left.clone(), 10), left);
+        let right = parse(">>+.");
+        assert_eq!(partial_eval(right.clone(), 2), right);
+        // A loop that never ends runs out of steps, and is left with the
+        // state from before it.
+        assert_eq!(
+            partial_eval(parse("+.+[]"), 10),
+            [Print(vec![1]), Set(2), LLoop, RLoop]
+        );
+    }
+
+    #[test]
+    fn entered_loops_follow_known_values() {
+        // The first loop starts on 2, the second on the 1 that follows
+        // the first loop's exit.
+        let ast = parse("++[-]+[-]");
+        let entered: Vec<usize> = entered_loops(&ast, 8, true)
+            .iter()
+            .enumerate()
+            .filter(|(_, entered)| **entered)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(entered, [1, 5]);
+        // At start only on a zeroed tape, and never after input or a move.
+        assert_eq!(entered_loops(&parse("+[-]"), 8, false), [false; 4]);
+        assert!(!entered_loops(&parse("+,[-]"), 8, true)[2]);
+        assert!(!entered_loops(&parse("+>[-]"), 8, true)[2]);
+    }
+
+    #[test]
+    fn entered_loops_wrap_at_cell_width() {
+        // 256 steps come back to zero in a byte, not in a wider cell.
+        let ast = vec![ValueInc(256), LLoop, ValueDec(1), RLoop];
+        assert!(!entered_loops(&ast, 8, true)[1]);
+        assert!(entered_loops(&ast, 16, true)[1]);
+        let ast = vec![ValueDec(1), LLoop, ValueInc(1), RLoop];
+        assert!(entered_loops(&ast, 32, true)[1]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::{self, BufRead, Write};
+
+use crate::interpreter::{self, Limits, Session, Tape};
+use crate::lexer::{Lexer, ParseError};
+use crate::CompileOptions;
+
+/// Ops each line may run, so an endless loop gives the prompt back.
+const LINE_STEPS: u64 = 100_000_000;
+
+/// Whether lexing only failed because a loop or proc is still open, so
+/// more lines could finish it.
+fn is_incomplete(e: &ParseError, source: &str) -> bool {
+    match e {
+        ParseError::UnterminatedProc { .. } => true,
+        ParseError::UnmatchedBracket { .. } => {
+            source.matches('[').count() > source.matches(']').count()
+        }
+        _ => false,
+    }
+}
+
+/// Every cell up to the last one in use or the pointer, 16 to a row.
+fn dump_all(session: &Session) -> String {
+    let tape = session.tape();
+    let used = tape
+        .iter()
+        .rposition(|cell| *cell != 0)
+        .unwrap_or(0)
+        .max(session.ptr())
+        + 1;
+    let digits = session.shape().cell_bits as usize / 4;
+    tape[..used]
+        .chunks(16)
+        .enumerate()
+        .map(|(row, cells)| {
+            let cells: Vec<String> = cells
+                .iter()
+                .map(|cell| format!("{:0digits$x}", cell, digits = digits))
+                .collect();
+            format!("{:5}: {}", row * 16, cells.join(" "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Reads BF from stdin a line at a time and runs each line as soon as it
+/// is complete, on a tape that persists for the whole session, printing
+/// its output and then the cells around the pointer. A line that leaves a
+/// loop or proc open is continued on the next, and an empty line gives up
+/// on it. `:dump` prints the tape, `:reset` starts over and `:quit` exits.
+///
+/// Lines run on the reference interpreter, on a tape shaped by `options`
+/// as a compiled program's would be, with their output encoded the same
+/// way. `,` reads from stdin, after the line it is on.
+pub fn repl(options: &CompileOptions) -> io::Result<()> {
+    options
+        .check_tape()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let shape = Tape::of(options);
+    let mut session = Session::new(shape);
+    // Lines already run. Each new line is lexed after them, so it can call
+    // the procs they define, and only its own ops are run.
+    let mut history = String::new();
+    let mut ran = 0;
+    let mut pending = String::new();
+    loop {
+        let prompt = if pending.is_empty() { "bf> " } else { "... " };
+        write!(stdout, "{}", prompt)?;
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        if pending.is_empty() {
+            match line.trim() {
+                ":quit" => break,
+                ":reset" => {
+                    session = Session::new(shape);
+                    history.clear();
+                    ran = 0;
+                    continue;
+                }
+                ":dump" => {
+                    println!("{}", dump_all(&session));
+                    continue;
+                }
+                _ => {}
+            }
+        } else if line.trim().is_empty() {
+            pending.clear();
+            continue;
+        }
+        pending.push_str(line.trim_end_matches(&['\r', '\n'][..]));
+        pending.push('\n');
+
+        let source = format!("{}{}", history, pending);
+        let mut lexer = Lexer::new(source.clone());
+        lexer.set_debug_ops(options.debug_ops);
+        let ops = match lexer.run() {
+            Ok(ops) => ops,
+            Err(e) if is_incomplete(&e, &source) => continue,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                pending.clear();
+                continue;
+            }
+        };
+        if lexer.take_input().is_some() {
+            eprintln!("error: input data can't be given in the repl");
+            pending.clear();
+            continue;
+        }
+        let limits = Limits {
+            max_steps: Some(LINE_STEPS),
+        };
+        match session.run(ops[ran..].to_vec(), &mut stdin.lock(), limits) {
+            Ok(output) => {
+                let output = interpreter::encode(&output, options.output_encoding);
+                stdout.write_all(&output)?;
+                if !output.is_empty() && !output.ends_with(b"\n") {
+                    writeln!(stdout)?;
+                }
+                history.push_str(&pending);
+                ran = ops.len();
+            }
+            Err(e) => eprintln!("error: {}", e),
+        }
+        pending.clear();
+        println!(
+            "{}",
+            interpreter::dump_tape(session.tape(), session.ptr(), shape.cell_bits)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::ops::Op;
+
+/// What an op stream is made of, for `--stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub ops: usize,
+    /// Number of ops of each kind, by variant name.
+    pub kinds: BTreeMap<&'static str, usize>,
+    pub loops: usize,
+    /// Deepest loop nesting, 0 when there are no loops.
+    pub max_depth: usize,
+    /// `[-]` and `[+]`.
+    pub clear_loops: usize,
+    /// Innermost loops that only move the pointer and change cells, end
+    /// where they started and count the current cell down by one, like
+    /// `[->+>+<<]`.
+    pub copy_loops: usize,
+    pub procs: usize,
+}
+
+fn kind(op: &Op) -> &'static str {
+    match op {
+        Op::PointerInc(_) => "PointerInc",
+        Op::PointerDec(_) => "PointerDec",
+        Op::ValueInc(_) => "ValueInc",
+        Op::ValueDec(_) => "ValueDec",
+        Op::Output(_) => "Output",
+        Op::Input => "Input",
+        Op::LLoop => "LLoop",
+        Op::RLoop => "RLoop",
+        Op::ScanInc(_) => "ScanInc",
+        Op::ScanDec(_) => "ScanDec",
+        Op::Set(_) => "Set",
+        Op::Print(_) => "Print",
+        Op::Clear(_) => "Clear",
+        Op::ProcDef(_) => "ProcDef",
+        Op::ProcCall(_) => "ProcCall",
+        Op::ProcEnd => "ProcEnd",
+        Op::DumpTape => "DumpTape",
+    }
+}
+
+/// Whether `body`, the ops between a pair of brackets, is a copy loop.
+fn is_copy_loop(body: &[Op]) -> bool {
+    let mut offset = 0isize;
+    let mut counter = 0isize;
+    let mut touched_other = false;
+    for op in body {
+        match op {
+            Op::PointerInc(v) => offset += *v as isize,
+            Op::PointerDec(v) => offset -= *v as isize,
+            Op::ValueInc(v) if offset == 0 => counter += *v as isize,
+            Op::ValueDec(v) if offset == 0 => counter -= *v as isize,
+            Op::ValueInc(_) | Op::ValueDec(_) => touched_other = true,
+            _ => return false,
+        }
+    }
+    offset == 0 && counter == -1 && touched_other
+}
+
+impl Stats {
+    pub fn of(ops: &[Op]) -> Self {
+        let mut stats = Stats {
+            ops: ops.len(),
+            ..Default::default()
+        };
+        // Where each open loop starts.
+        let mut open = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            *stats.kinds.entry(kind(op)).or_insert(0) += 1;
+            match op {
+                Op::LLoop => {
+                    open.push(i);
+                    stats.loops += 1;
+                    stats.max_depth = stats.max_depth.max(open.len());
+                }
+                Op::RLoop => {
+                    let Some(start) = open.pop() else {
+                        continue;
+                    };
+                    let body = &ops[start + 1..i];
+                    if matches!(body, [Op::ValueInc(1) | Op::ValueDec(1)]) {
+                        stats.clear_loops += 1;
+                    } else if is_copy_loop(body) {
+                        stats.copy_loops += 1;
+                    }
+                }
+                Op::ProcDef(_) => stats.procs += 1,
+                _ => {}
+            }
+        }
+        stats
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "    {:<14}{:>8}", "ops", self.ops)?;
+        writeln!(f, "    {:<14}{:>8}", "loops", self.loops)?;
+        writeln!(f, "    {:<14}{:>8}", "max depth", self.max_depth)?;
+        writeln!(f, "    {:<14}{:>8}", "clear loops", self.clear_loops)?;
+        writeln!(f, "    {:<14}{:>8}", "copy loops", self.copy_loops)?;
+        writeln!(f, "    {:<14}{:>8}", "procs", self.procs)?;
+        for (kind, count) in &self.kinds {
+            writeln!(f, "      {:<12}{:>8}", kind, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Op::*;
+
+    #[test]
+    fn empty() {
+        assert_eq!(Stats::of(&[]), Stats::default());
+    }
+
+    #[test]
+    fn counts_loops_and_procs() {
+        // +[-]>[->+>+<<]@p{[[.]]}
+        let ops = [
+            ValueInc(1),
+            LLoop,
+            ValueDec(1),
+            RLoop,
+            PointerInc(1),
+            LLoop,
+            ValueDec(1),
+            PointerInc(1),
+            ValueInc(1),
+            PointerInc(1),
+            ValueInc(1),
+            PointerDec(2),
+            RLoop,
+            ProcDef("p".to_string()),
+            LLoop,
+            LLoop,
+            Output(1),
+            RLoop,
+            RLoop,
+            ProcEnd,
+        ];
+        let stats = Stats::of(&ops);
+        assert_eq!(stats.ops, 20);
+        assert_eq!(stats.loops, 4);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.clear_loops, 1);
+        assert_eq!(stats.copy_loops, 1);
+        assert_eq!(stats.procs, 1);
+        assert_eq!(stats.kinds["LLoop"], 4);
+        assert_eq!(stats.kinds["ValueInc"], 3);
+        assert_eq!(stats.kinds["PointerDec"], 1);
+        assert!(!stats.kinds.contains_key("Input"));
+    }
+
+    #[test]
+    fn copy_loops_have_to_balance() {
+        // Ends one cell right of where it started, or counts down by two.
+        let moves = [LLoop, ValueDec(1), PointerInc(1), ValueInc(1), RLoop];
+        let twice = [
+            LLoop,
+            ValueDec(2),
+            PointerInc(1),
+            ValueInc(1),
+            PointerDec(1),
+            RLoop,
+        ];
+        assert_eq!(Stats::of(&moves).copy_loops, 0);
+        assert_eq!(Stats::of(&twice).copy_loops, 0);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        let stats = Stats::of(&[RLoop, LLoop, LLoop]);
+        assert_eq!((stats.loops, stats.max_depth), (2, 2));
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let source = format!("+{}-{}", "[".repeat(500), "]".repeat(500));
+        let stats = Stats::of(&crate::lexer::parse(&source).unwrap());
+        assert_eq!((stats.loops, stats.max_depth), (500, 500));
+        let siblings = crate::lexer::parse("+[[-][[-]]][-]").unwrap();
+        assert_eq!(Stats::of(&siblings).max_depth, 3);
+    }
+}
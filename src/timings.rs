@@ -0,0 +1,52 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Wall clock time spent in each phase of `compile_file`, for `--timings`.
+/// Phases that didn't run are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Translating a dialect to BF.
+    pub translate: Duration,
+    /// Lexing, along with checking brackets and procs.
+    pub lex: Duration,
+    /// The BF level optimizations.
+    pub optimize: Duration,
+    /// Building the LLVM module, or writing the C source.
+    pub codegen: Duration,
+    /// Verifying the module, running LLVM's code generation passes and
+    /// writing the object file.
+    pub emit: Duration,
+    /// Running the linker, or `cc` for the C backend.
+    pub link: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.translate + self.lex + self.optimize + self.codegen + self.emit + self.link
+    }
+}
+
+/// Runs `f`, adding the time it took to `phase`.
+pub(crate) fn time<T>(phase: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *phase += start.elapsed();
+    result
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (phase, duration) in [
+            ("translate", self.translate),
+            ("lex", self.lex),
+            ("optimize", self.optimize),
+            ("codegen", self.codegen),
+            ("emit", self.emit),
+            ("link", self.link),
+            ("total", self.total()),
+        ] {
+            writeln!(f, "    {:<14}{:>12}", phase, format!("{:.2?}", duration))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::io;
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{compile_files_loading, CompileOptions};
+
+/// How often the sources' modification times are checked.
+const POLL: Duration = Duration::from_millis(200);
+
+/// How long the source has to stay untouched after a change before it is
+/// rebuilt, so an editor saving in several writes only triggers one build.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// The modification time of each of `paths`.
+fn modified(paths: &[String]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// `paths` followed by the files they included, each once.
+fn watched(paths: &[String], loaded: Vec<String>) -> Vec<String> {
+    let mut files = paths.to_vec();
+    for file in loaded {
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
+    files
+}
+
+/// Runs the program `compile_files` linked, waiting for it to exit.
+pub fn run_program() -> io::Result<ExitStatus> {
+    let program = if cfg!(windows) { "main.exe" } else { "./main" };
+    Command::new(program).status()
+}
+
+/// Compiles `paths`, and runs the result if `run` is set and there is a
+/// program to run, then prints a status line with the compile time and
+/// exit code. Errors are printed, not returned, so watching carries on.
+/// Returns the files to watch for the next build, included ones too.
+fn rebuild(paths: &[String], opts: &CompileOptions, run: bool) -> Vec<String> {
+    let start = Instant::now();
+    let mut loaded = Vec::new();
+    let result = compile_files_loading(paths, opts, &mut loaded);
+    let files = watched(paths, loaded);
+    let path = paths.join(" ");
+    let elapsed = start.elapsed();
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        eprintln!("[watch] {} failed after {:.2?}", path, elapsed);
+        return files;
+    }
+    if !run || opts.no_link || opts.shared {
+        eprintln!("[watch] {} built in {:.2?}", path, elapsed);
+        return files;
+    }
+    match run_program() {
+        Ok(status) => {
+            let code = status
+                .code()
+                .map_or_else(|| "killed".to_string(), |code| code.to_string());
+            eprintln!("[watch] {} built in {:.2?}, exit {}", path, elapsed, code);
+        }
+        Err(e) => eprintln!(
+            "[watch] {} built in {:.2?}, can't run: {}",
+            path, elapsed, e
+        ),
+    }
+    files
+}
+
+/// Builds the program in `paths`, and runs it with `run`, every time one
+/// of them or a file they include changes, until interrupted. The included
+/// files are the ones the last build read. Ctrl-C goes to the program being
+/// run as well, so both stop together. Each build starts from scratch with
+/// a context of its own.
+pub fn watch(paths: &[String], opts: &CompileOptions, run: bool) -> ! {
+    let mut files = paths.to_vec();
+    let mut built = Vec::new();
+    loop {
+        let stamp = modified(&files);
+        // Only the files given have to be there, a missing include is
+        // reported by the build.
+        if stamp[..paths.len()].iter().all(Option::is_some) && stamp != built {
+            thread::sleep(SETTLE);
+            if modified(&files) == stamp {
+                built = stamp;
+                let now = rebuild(paths, opts, run);
+                if now != files {
+                    files = now;
+                    built = modified(&files);
+                }
+            }
+            continue;
+        }
+        thread::sleep(POLL);
+    }
+}
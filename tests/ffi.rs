@@ -0,0 +1,100 @@
+//! Compiles through the C API the way a C or ctypes caller would. Only
+//! built with the `ffi` feature. These call the functions directly;
+//! `examples/ffi_smoke.py` loads the shared library itself, and CI runs it.
+#![cfg(feature = "ffi")]
+
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use bf_llvm::ffi::*;
+
+/// What `bf_compile_to_object` returns for `source`, with the object's
+/// bytes on success.
+fn compile(source: &str, opts: Option<&BfOptions>) -> (i32, Vec<u8>) {
+    let source = CString::new(source).unwrap();
+    let opts = opts.map_or(ptr::null(), |opts| opts as *const BfOptions);
+    let mut buf = ptr::null_mut();
+    let mut len = 0;
+    // SAFETY: the arguments are a NUL-terminated string, null or a valid
+    // BfOptions, and two writable locals.
+    let code = unsafe { bf_compile_to_object(source.as_ptr(), opts, &mut buf, &mut len) };
+    if code != 0 {
+        return (code, Vec::new());
+    }
+    // SAFETY: on success `buf` holds `len` bytes until it is freed.
+    let object = unsafe { std::slice::from_raw_parts(buf, len) }.to_vec();
+    unsafe { bf_free_buffer(buf, len) };
+    (code, object)
+}
+
+fn last_error() -> String {
+    let message = bf_last_error_message();
+    assert!(!message.is_null());
+    // SAFETY: a non-null message is a NUL-terminated string that stays
+    // valid until the next failing call on this thread.
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn defaults() -> BfOptions {
+    let mut opts = MaybeUninit::uninit();
+    // SAFETY: bf_default_options fills in the whole struct.
+    unsafe {
+        bf_default_options(opts.as_mut_ptr());
+        opts.assume_init()
+    }
+}
+
+#[test]
+fn compiles_hello_world() {
+    let hello = include_str!("../examples/hello_world.bf");
+    let (code, object) = compile(hello, None);
+    assert_eq!(code, 0);
+    let magic: [&[u8]; 5] = [
+        b"\x7fELF",
+        b"\x64\x86",
+        b"\x4c\x01",
+        b"\x64\xaa",
+        b"\xcf\xfa\xed\xfe",
+    ];
+    assert!(magic.iter().any(|m| object.starts_with(m)));
+
+    let opts = BfOptions {
+        cell_bits: 16,
+        exit_cell: 1,
+        ..defaults()
+    };
+    assert_eq!(compile(hello, Some(&opts)).0, 0);
+}
+
+#[test]
+fn default_options() {
+    let opts = defaults();
+    assert_eq!((opts.cells, opts.cell_bits), (1000, 8));
+    assert_eq!(
+        (opts.wrap_pointer, opts.signed_cells, opts.exit_cell),
+        (0, 0, 0)
+    );
+}
+
+#[test]
+fn errors() {
+    assert_eq!(compile("+]", None).0, BF_ERROR_PARSE);
+    assert_eq!(last_error(), "parse error: unmatched bracket at 1:2");
+
+    let opts = BfOptions {
+        cell_bits: 12,
+        ..defaults()
+    };
+    assert_eq!(compile("+", Some(&opts)).0, BF_ERROR_ARGUMENT);
+    assert!(last_error().starts_with("bad options"));
+
+    let mut len = 0;
+    // SAFETY: a null source is checked before anything is read.
+    let code =
+        unsafe { bf_compile_to_object(ptr::null(), ptr::null(), &mut ptr::null_mut(), &mut len) };
+    assert_eq!(code, BF_ERROR_ARGUMENT);
+    assert_eq!(last_error(), "null argument");
+}
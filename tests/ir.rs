@@ -0,0 +1,240 @@
+//! Compares the IR of a few small programs, unoptimized, against the
+//! golden files in `tests/snapshots`. Run with `BLESS=1` to rewrite them
+//! after a deliberate codegen change, and review the diff.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use bf_llvm::codegen::CodeGen;
+use bf_llvm::lexer::parse;
+use bf_llvm::optimizer::{self, Passes};
+use bf_llvm::{compile_to_object, dump, generate, CompileOptions};
+use inkwell::context::Context;
+use inkwell::values::{AnyValue, InstructionOpcode};
+use inkwell::OptimizationLevel;
+
+/// Drops the host dependent module header and renumbers unnamed values in
+/// order of appearance, so unrelated changes don't shift every line.
+fn normalize(ir: &str) -> String {
+    let mut numbers = HashMap::new();
+    let mut out = String::new();
+    for line in ir.lines() {
+        if line.starts_with("; ModuleID")
+            || line.starts_with("source_filename")
+            || line.starts_with("target ")
+        {
+            continue;
+        }
+        let mut rest = line;
+        while let Some(at) = rest.find('%') {
+            out.push_str(&rest[..=at]);
+            rest = &rest[at + 1..];
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            if digits > 0 {
+                let next = numbers.len();
+                let n = *numbers.entry(rest[..digits].to_string()).or_insert(next);
+                out.push_str(&n.to_string());
+                rest = &rest[digits..];
+            }
+        }
+        out.push_str(rest);
+        out.push('\n');
+    }
+    out.trim().to_string() + "\n"
+}
+
+fn check(name: &str, source: &str) {
+    let opts = CompileOptions {
+        opt_level: OptimizationLevel::None,
+        passes: Passes::NONE,
+        ..Default::default()
+    };
+    let ctx = Context::create();
+    let cdg = generate(&ctx, source, &opts).unwrap();
+    cdg.verify().unwrap();
+    let ir = normalize(&cdg.module().print_to_string().to_string());
+
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.ll", name));
+    if std::env::var_os("BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &ir).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (run with BLESS=1 to create it)", path.display(), e));
+    assert!(
+        ir == expected,
+        "IR for {} differs from {}, run with BLESS=1 to update it:\n{}",
+        name,
+        path.display(),
+        ir
+    );
+}
+
+#[test]
+fn empty() {
+    check("empty", "");
+}
+
+#[test]
+fn inc_output() {
+    check("inc_output", "+.");
+}
+
+#[test]
+fn simple_loop() {
+    check("loop", "++[>+<-]");
+}
+
+#[test]
+fn nested_loops() {
+    check("nested_loops", "++[>++[>+<-]<-]");
+}
+
+#[test]
+fn proc_call() {
+    check("proc", "*+.*>*");
+}
+
+#[test]
+fn abort_declared_once() {
+    // The tape allocation and every place it can grow abort on failure.
+    let opts = CompileOptions {
+        grow: true,
+        passes: Passes::NONE,
+        ..Default::default()
+    };
+    let ctx = Context::create();
+    let cdg = generate(&ctx, "+>+[>+]>>,", &opts).unwrap();
+    cdg.verify().unwrap();
+    let ir = cdg.ir();
+    assert_eq!(ir.matches("declare void @abort()").count(), 1);
+    assert!(ir.matches("call void @abort()").count() >= 2, "{}", ir);
+}
+
+#[test]
+fn op_dump_round_trip() {
+    // Dumped after optimizing, as --emit=ops does, and compiled as read
+    // back, as --from-ops does.
+    let programs = [
+        include_str!("../examples/hello_world.bf"),
+        include_str!("../examples/procs.bf"),
+        include_str!("../examples/what_is_your_name.bf"),
+        "+[>[-]>[-]>[-]<<<-]>[>]<[<]@p{,.}@p;",
+    ];
+    let opts = CompileOptions::default();
+    for source in programs {
+        let ctx = Context::create();
+        let direct = generate(&ctx, source, &opts).unwrap().ir();
+
+        let json = dump::to_json(&optimizer::optimize(parse(source).unwrap(), &opts));
+        let ops = dump::from_json(&json).unwrap();
+        dump::validate(&ops).unwrap();
+        let ctx = Context::create();
+        let mut cdg = CodeGen::new(&ctx, ops, None, &opts);
+        cdg.run().unwrap();
+        assert!(cdg.ir() == direct, "{}", source);
+    }
+}
+
+/// For each loop in the entry function of `source`, in order, whether it is tested at
+/// the bottom only: a body that branches back to itself, with no block
+/// testing the cell before it.
+fn bottom_tested(source: &str, opts: &CompileOptions) -> Vec<bool> {
+    let ctx = Context::create();
+    let cdg = generate(&ctx, source, opts).unwrap();
+    cdg.verify().unwrap();
+    let entry = if opts.reusable { "bf_run" } else { "main" };
+    let blocks = cdg.module().get_function(entry).unwrap().get_basic_blocks();
+    let name = |i: usize| blocks[i].get_name().to_str().unwrap().to_string();
+    (1..blocks.len())
+        .filter(|&i| name(i).starts_with("body_block"))
+        .map(|i| {
+            let branch = blocks[i].get_terminator().unwrap();
+            assert_eq!(branch.get_opcode(), InstructionOpcode::Br);
+            let branch = branch.print_to_string().to_string();
+            if branch.contains("br i1") {
+                // Back to the top of the body or out.
+                assert!(
+                    branch.contains(&format!("label %{},", name(i))),
+                    "{}",
+                    source
+                );
+                assert!(!name(i - 1).starts_with("cond_block"), "{}", source);
+                true
+            } else {
+                // Back to the block testing the cell.
+                assert!(name(i - 1).starts_with("cond_block"), "{}", source);
+                assert!(
+                    branch.ends_with(&format!("label %{}", name(i - 1))),
+                    "{}",
+                    source
+                );
+                false
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn entered_loops_tested_at_bottom() {
+    let opts = CompileOptions {
+        opt_level: OptimizationLevel::None,
+        passes: Passes::NONE,
+        ..Default::default()
+    };
+    // Entered: 3 on a zeroed tape, and the 1 left after a clear.
+    assert_eq!(bottom_tested("+++[>+<-]", &opts), [true]);
+    assert_eq!(bottom_tested(",[-]+[>+<-]", &opts), [false, true]);
+    // Not known: input, a move, and a zero cell after a loop.
+    assert_eq!(bottom_tested(",[>+<-]", &opts), [false]);
+    assert_eq!(bottom_tested("+>[-]", &opts), [false]);
+    assert_eq!(bottom_tested("+[-][-]", &opts), [true, false]);
+    // bf_run's tape isn't zeroed.
+    let reusable = CompileOptions {
+        reusable: true,
+        ..opts
+    };
+    assert_eq!(bottom_tested("+[-]", &reusable), [false]);
+}
+
+#[test]
+fn freestanding_runtime() {
+    for (target, syscall) in [
+        ("x86_64-unknown-linux-gnu", "syscall"),
+        ("aarch64-unknown-linux-gnu", "svc #0"),
+    ] {
+        let opts = CompileOptions {
+            target: Some(target.to_string()),
+            freestanding: true,
+            ..Default::default()
+        };
+        let ctx = Context::create();
+        let cdg = generate(&ctx, ",[.,]", &opts).unwrap();
+        cdg.verify().unwrap();
+        for name in ["_start", "putchar", "getchar", "memset"] {
+            let f = cdg.module().get_function(name).unwrap();
+            assert!(f.count_basic_blocks() > 0, "{}: {}", target, name);
+        }
+        let ir = cdg.module().print_to_string().to_string();
+        assert!(ir.contains(syscall), "{}", target);
+        assert!(ir.contains("\"no-builtins\""), "{}", target);
+
+        // Nothing is left for the C runtime to provide.
+        let object = compile_to_object(",[.,]", &opts).unwrap();
+        for name in ["calloc", "free", "fwrite", "fflush", "abort", "exit"] {
+            let name = format!("\0{}\0", name);
+            assert!(
+                !object.windows(name.len()).any(|w| w == name.as_bytes()),
+                "{}: {}",
+                target,
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,608 @@
+//! Builds programs into native executables with the `bf` binary and runs
+//! them. Without a C toolchain to link with, the tests print why and pass.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use bf_llvm::{compile_to_object, CompileOptions};
+use inkwell::targets::RelocMode;
+
+/// A directory of its own for one test's `main.o` and `main`, removed
+/// when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bf-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// The program `bf` builds in this directory.
+    fn exe(&self) -> PathBuf {
+        self.0.join(if cfg!(windows) { "main.exe" } else { "main" })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Whether the linker `bf` runs for the host is there, printing a note if
+/// it isn't.
+fn have_linker() -> bool {
+    let linker = if cfg!(windows) { "link" } else { "cc" };
+    let found = Command::new(linker)
+        .arg(if cfg!(windows) { "/?" } else { "--version" })
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok();
+    if !found {
+        eprintln!("skipping: no {} to link with", linker);
+    }
+    found
+}
+
+/// Compiles `source` with `args` in `dir`, panicking with the compiler's
+/// errors if that fails.
+fn build(dir: &TempDir, source: &str, args: &[&str]) {
+    fs::write(dir.0.join("main.bf"), source).unwrap();
+    let build = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .arg("main.bf")
+        .args(args)
+        .current_dir(&dir.0)
+        .output()
+        .unwrap();
+    assert!(
+        build.status.success(),
+        "bf failed:\n{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+}
+
+/// Runs `command` with `input` as its stdin, capturing stdout and stderr.
+fn run(command: &mut Command, input: &[u8]) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Compiles `source` with `args` in a fresh directory and runs the result
+/// with `input` as its stdin. `None` if there is no linker.
+fn build_and_run(name: &str, source: &str, args: &[&str], input: &[u8]) -> Option<Output> {
+    if !have_linker() {
+        return None;
+    }
+    let dir = TempDir::new(name);
+    build(&dir, source, args);
+    Some(run(&mut Command::new(dir.exe()), input))
+}
+
+#[test]
+fn hello_world() {
+    let source = include_str!("../examples/hello_world.bf");
+    let Some(out) = build_and_run("hello", source, &[], b"") else {
+        return;
+    };
+    assert_eq!(out.stdout, b"Hello, World!");
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn echo() {
+    let Some(out) = build_and_run("echo", ",+[-.,+]", &[], b"echo\n\x01\xfe") else {
+        return;
+    };
+    assert_eq!(out.stdout, b"echo\n\x01\xfe");
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn cells_wrap() {
+    // 0 - 1 is 255, and 256 increments come back around to 0.
+    let source = format!("-.>{}.", "+".repeat(256));
+    let Some(out) = build_and_run("wrap", &source, &[], b"") else {
+        return;
+    };
+    assert_eq!(out.stdout, [255, 0]);
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn putchar_gets_the_low_byte() {
+    let source = format!("{}.", "+".repeat(200));
+    let Some(out) = build_and_run("putchar", &source, &["--no-opt"], b"") else {
+        return;
+    };
+    assert_eq!(out.stdout, [0xc8]);
+    let source = format!("{}.", "+".repeat(456));
+    let args = ["--no-opt", "--cell-size", "16"];
+    let out = build_and_run("putchar-16", &source, &args, b"").unwrap();
+    assert_eq!(out.stdout, [0xc8]);
+}
+
+#[test]
+fn exit_code() {
+    let Some(out) = build_and_run("exit", "+++++", &["--exit-cell"], b"") else {
+        return;
+    };
+    assert_eq!(out.stdout, b"");
+    assert_eq!(out.status.code(), Some(5));
+    let source = "+".repeat(256);
+    let out = build_and_run("exit-wrap", &source, &["--exit-cell"], b"").unwrap();
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn header_round_trip() {
+    if cfg!(windows) {
+        eprintln!("skipping: the caller is built with cc");
+        return;
+    }
+    if !have_linker() {
+        return;
+    }
+    let dir = TempDir::new("header");
+    build(&dir, "@up{+}@hi{++++++++[>+++++++++<-]>.<}", &["--shared"]);
+    let caller = r#"
+        #include <stdio.h>
+        #include "main.h"
+
+        int main(void) {
+            static unsigned char tape[BF_TAPE_SIZE];
+            bf_proc_up(tape);
+            bf_proc_up(tape);
+            bf_proc_hi(tape);
+            printf(" %d %d %d\n", tape[0], tape[1], BF_TAPE_SIZE);
+            return 0;
+        }
+    "#;
+    fs::write(dir.0.join("caller.c"), caller).unwrap();
+    let status = Command::new("cc")
+        .args(["caller.c", "-o", "caller", "-L.", "-lmain"])
+        .current_dir(&dir.0)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let out = run(
+        Command::new(dir.0.join("caller"))
+            .env("LD_LIBRARY_PATH", &dir.0)
+            .env("DYLD_LIBRARY_PATH", &dir.0),
+        b"",
+    );
+    // 2 from the two calls and 8 more, times 9 is 'Z'.
+    assert_eq!(out.stdout, b"Z 0 90 1000\n");
+}
+
+#[test]
+fn trace() {
+    let Some(out) = build_and_run("trace", ">+[-<+>]<.", &["--trace", "--no-opt"], b"") else {
+        return;
+    };
+    let expected = "\
+1:1 PointerInc(1) ptr=1 cell=0
+1:2 ValueInc(1) ptr=1 cell=1
+1:3 LLoop ptr=1 cell=1
+1:4 ValueDec(1) ptr=1 cell=0
+1:5 PointerDec(1) ptr=0 cell=0
+1:6 ValueInc(1) ptr=0 cell=1
+1:7 PointerInc(1) ptr=1 cell=0
+1:8 RLoop ptr=1 cell=0
+1:9 PointerDec(1) ptr=0 cell=1
+1:10 Output(1) ptr=0 cell=1
+";
+    assert_eq!(String::from_utf8_lossy(&out.stderr), expected);
+    assert_eq!(out.stdout, [1]);
+}
+
+#[test]
+fn step_limit() {
+    let Some(out) = build_and_run("steps", "+[]", &["--max-steps", "100"], b"") else {
+        return;
+    };
+    assert_eq!(out.status.code(), Some(3));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "step limit exceeded\n"
+    );
+}
+
+#[test]
+fn backends_agree() {
+    let corpus: [(&str, &[u8]); 5] = [
+        (include_str!("../examples/hello_world.bf"), b""),
+        (include_str!("../examples/what_is_your_name.bf"), b"Bob\n"),
+        (include_str!("../examples/print_char_proc.bf"), b""),
+        (",+[-.,+]", b"cat\xff\x00"),
+        ("+++++@dec{-[@dec;]}@dec;.>+.-.", b""),
+    ];
+    for (i, (source, input)) in corpus.into_iter().enumerate() {
+        let Some(llvm) = build_and_run(&format!("llvm{}", i), source, &[], input) else {
+            return;
+        };
+        let c = build_and_run(&format!("c{}", i), source, &["--backend=c"], input).unwrap();
+        assert_eq!(llvm.stdout, c.stdout, "{}", source);
+        assert_eq!(llvm.status.code(), c.status.code(), "{}", source);
+    }
+}
+
+#[test]
+fn backends_agree_on_signed_cells() {
+    // Cells above 127 printed by the program and at compile time, and a
+    // byte above 127 read in.
+    let source = format!("{}.>,.", "+".repeat(200));
+    for no_opt in [false, true] {
+        let mut args = vec!["--signed-cells", "--output=utf8"];
+        if no_opt {
+            args.push("--no-opt");
+        }
+        let Some(llvm) = build_and_run("signed-llvm", &source, &args, b"\x90") else {
+            return;
+        };
+        args.push("--backend=c");
+        let c = build_and_run("signed-c", &source, &args, b"\x90").unwrap();
+        assert_eq!(llvm.stdout, "\u{FFFD}\u{FFFD}".as_bytes());
+        assert_eq!(c.stdout, llvm.stdout);
+    }
+}
+
+#[test]
+fn embedded_input_file() {
+    if !have_linker() {
+        return;
+    }
+    let dir = TempDir::new("input-file");
+    fs::write(dir.0.join("data.txt"), "baked in\n").unwrap();
+    build(&dir, ",+[-.,+]", &["--input-file", "data.txt"]);
+    fs::remove_file(dir.0.join("data.txt")).unwrap();
+    for _ in 0..2 {
+        let out = Command::new(dir.exe())
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert_eq!(out.stdout, b"baked in\n");
+    }
+}
+
+/// Relocation types in `object`, listed by `llvm-readobj`. `None` if that
+/// isn't installed.
+fn relocation_types(dir: &TempDir, object: &[u8]) -> Option<Vec<String>> {
+    let path = dir.0.join("main.o");
+    fs::write(&path, object).unwrap();
+    let Ok(out) = Command::new("llvm-readobj")
+        .arg("--relocations")
+        .arg(&path)
+        .output()
+    else {
+        eprintln!("skipping: no llvm-readobj to read relocations with");
+        return None;
+    };
+    let listing = String::from_utf8(out.stdout).unwrap();
+    Some(
+        listing
+            .split_whitespace()
+            .filter(|word| word.starts_with("R_"))
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+#[test]
+fn static_relocations() {
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        eprintln!("skipping: relocation types are checked for x86-64 ELF");
+        return;
+    }
+    let source = include_str!("../examples/what_is_your_name.bf");
+    let dir = TempDir::new("reloc");
+    let absolute = |reloc_mode| {
+        let opts = CompileOptions {
+            reloc_mode,
+            ..Default::default()
+        };
+        let object = compile_to_object(source, &opts).unwrap();
+        let types = relocation_types(&dir, &object)?;
+        Some(
+            types
+                .iter()
+                .any(|t| t == "R_X86_64_32" || t == "R_X86_64_32S"),
+        )
+    };
+    // Position independent code can't hold absolute addresses, while
+    // static code refers to its own data by them.
+    let Some(pic) = absolute(RelocMode::PIC) else {
+        return;
+    };
+    assert!(!pic);
+    assert_eq!(absolute(RelocMode::Static), Some(true));
+}
+
+/// What `ldd` says `exe` loads. `None` if there is no `ldd`.
+fn shared_libraries(exe: &Path) -> Option<String> {
+    match Command::new("ldd").arg(exe).output() {
+        Ok(out) => Some(String::from_utf8_lossy(&out.stdout).into_owned()),
+        Err(_) => {
+            eprintln!("skipping: no ldd to list shared libraries with");
+            None
+        }
+    }
+}
+
+#[test]
+fn static_executable() {
+    if !cfg!(target_os = "linux") || !have_linker() {
+        return;
+    }
+    let source = include_str!("../examples/hello_world.bf");
+    let dynamic = TempDir::new("dynamic");
+    build(&dynamic, source, &[]);
+    let Some(libraries) = shared_libraries(&dynamic.exe()) else {
+        return;
+    };
+    assert!(libraries.contains("=>"), "{}", libraries);
+
+    let dir = TempDir::new("static");
+    build(&dir, source, &["--static"]);
+    let libraries = shared_libraries(&dir.exe()).unwrap();
+    assert!(!libraries.contains("=>"), "{}", libraries);
+    let out = run(&mut Command::new(dir.exe()), b"");
+    assert_eq!(out.stdout, b"Hello, World!");
+}
+
+#[test]
+fn link_args() {
+    if cfg!(windows) || !have_linker() {
+        return;
+    }
+    // A raw argument reaches cc as is, here asking for a link map.
+    let dir = TempDir::new("link-args");
+    build(&dir, "+.", &["--link-arg", "-Wl,-Map=main.map", "-l", "m"]);
+    assert!(dir.0.join("main.map").exists());
+
+    let missing = TempDir::new("link-missing");
+    fs::write(missing.0.join("main.bf"), "+.").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(["main.bf", "-L", "libs", "-l", "bf_no_such_library"])
+        .current_dir(&missing.0)
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("cc failed"), "{}", stderr);
+    assert!(stderr.contains("bf_no_such_library"), "{}", stderr);
+}
+
+#[test]
+fn reproducible() {
+    // Different directories, so a path leaking into the output shows.
+    let source = include_str!("../examples/what_is_your_name.bf");
+    let dirs = [TempDir::new("repro-a"), TempDir::new("repro-b-longer")];
+    let read = |dir: &TempDir, name: &str| fs::read(dir.0.join(name)).unwrap();
+    for dir in &dirs {
+        build(dir, source, &["-g", "--no-link"]);
+    }
+    assert!(read(&dirs[0], "main.o") == read(&dirs[1], "main.o"));
+
+    if !have_linker() {
+        return;
+    }
+    for dir in &dirs {
+        build(dir, source, &["-g"]);
+    }
+    let exe = dirs[0].exe();
+    let name = exe.file_name().unwrap().to_str().unwrap();
+    assert!(read(&dirs[0], name) == read(&dirs[1], name));
+}
+
+#[test]
+fn proc_from_another_file() {
+    let dir = TempDir::new("two-files");
+    fs::write(dir.0.join("lib.bf"), "@up{+}").unwrap();
+    fs::write(dir.0.join("main.bf"), "@up;@up;.").unwrap();
+    let bf = || {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_bf"));
+        command.args(["lib.bf", "main.bf"]).current_dir(&dir.0);
+        command
+    };
+    let out = run(bf().arg("--interpret"), b"");
+    assert_eq!(out.stdout, [2]);
+
+    if !have_linker() {
+        return;
+    }
+    assert!(bf().status().unwrap().success());
+    let out = run(&mut Command::new(dir.exe()), b"");
+    assert_eq!(out.stdout, [2]);
+}
+
+#[test]
+fn jit_reads_stdin() {
+    let dir = TempDir::new("jit");
+    // Echoes until `,` reads -1.
+    fs::write(dir.0.join("cat.bf"), ",+[-.,+]").unwrap();
+    fs::write(dir.0.join("exit.bf"), ",+").unwrap();
+    let bf = |args: &[&str]| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_bf"));
+        command.args(args).current_dir(&dir.0);
+        command
+    };
+    let out = run(&mut bf(&["--jit", "cat.bf"]), b"hello, jit\n");
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert_eq!(out.stdout, b"hello, jit\n");
+
+    let out = run(&mut bf(&["--jit", "--exit-cell", "exit.bf"]), b"");
+    assert_eq!(out.status.code(), Some(0));
+    let out = run(&mut bf(&["--jit", "--exit-cell", "exit.bf"]), b"\x04");
+    assert_eq!(out.status.code(), Some(5));
+
+    let out = run(&mut bf(&["--jit", "--max-steps", "10", "cat.bf"]), b"");
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("can't run in the JIT"), "{}", stderr);
+}
+
+#[test]
+fn help_and_version() {
+    let bf = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_bf"))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    for flag in ["-h", "--help"] {
+        let out = bf(&[flag]);
+        assert_eq!(out.status.code(), Some(0));
+        let usage = String::from_utf8(out.stdout).unwrap();
+        assert!(
+            usage.starts_with("usage: bf [options] file..."),
+            "{}",
+            usage
+        );
+        assert!(usage.contains("-V, --version"), "{}", usage);
+    }
+    for flag in ["-V", "--version"] {
+        let out = bf(&[flag]);
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(
+            String::from_utf8(out.stdout).unwrap(),
+            format!("bf {}\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+    // No arguments is a usage error, not a panic.
+    let out = bf(&[]);
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(out.stdout, b"");
+    assert!(out.stderr.starts_with(b"usage: bf"));
+    // So are a bad value, a missing one and an unknown flag.
+    let errors = [
+        (
+            &["main.bf", "--cell-size", "12"][..],
+            "--cell-size takes 8, 16 or 32",
+        ),
+        (&["main.bf", "--cells"], "--cells takes a positive number"),
+        (&["--cels", "10", "main.bf"], "unknown option --cels"),
+    ];
+    for (args, message) in errors {
+        let out = bf(args);
+        assert_eq!(out.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let expected = format!("error: {}\n\nusage: bf", message);
+        assert!(stderr.starts_with(&expected), "{}", stderr);
+    }
+}
+
+#[test]
+fn duplicate_proc_across_files() {
+    let dir = TempDir::new("duplicate");
+    fs::write(dir.0.join("lib.bf"), "@up{+}").unwrap();
+    fs::write(dir.0.join("main.bf"), "@up{-}").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(["lib.bf", "main.bf", "--interpret"])
+        .current_dir(&dir.0)
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains(
+            "parse error in main.bf: proc 'up' defined again at 1:1, first at lib.bf:1:1"
+        ),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn entry_symbol() {
+    let source = include_str!("../examples/hello_world.bf");
+    let opts = CompileOptions {
+        entry_symbol: Some("run_hello".to_string()),
+        ..Default::default()
+    };
+    let object = compile_to_object(source, &opts).unwrap();
+    let name = b"run_hello\0";
+    assert!(object.windows(name.len()).any(|w| w == name));
+
+    if cfg!(windows) || !have_linker() {
+        return;
+    }
+    let dir = TempDir::new("entry-symbol");
+    build(&dir, source, &["--entry-symbol", "run_hello"]);
+    // The caller's main would clash with one left in the object.
+    let caller = "int run_hello(void);\nint main(void) { return run_hello(); }\n";
+    fs::write(dir.0.join("caller.c"), caller).unwrap();
+    let status = Command::new("cc")
+        .args(["caller.c", "main.o", "-o", "caller"])
+        .current_dir(&dir.0)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let out = run(&mut Command::new(dir.0.join("caller")), b"");
+    assert_eq!(out.stdout, b"Hello, World!");
+}
+
+#[test]
+fn freestanding() {
+    let bf = |dir: &TempDir, args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_bf"))
+            .arg("main.bf")
+            .args(args)
+            .current_dir(&dir.0)
+            .output()
+            .unwrap()
+    };
+    let dir = TempDir::new("freestanding-shared");
+    fs::write(dir.0.join("main.bf"), "+.").unwrap();
+    let out = bf(&dir, &["--freestanding", "--shared"]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        stderr.contains("--freestanding can't be combined"),
+        "{}",
+        stderr
+    );
+
+    let host = cfg!(target_os = "linux")
+        && (cfg!(target_arch = "x86_64") || cfg!(target_arch = "aarch64"));
+    if !host {
+        return;
+    }
+    if Command::new("ld").arg("--version").output().is_err() {
+        eprintln!("skipping: no ld to link with");
+        return;
+    }
+    let dir = TempDir::new("freestanding");
+    build(&dir, ",+[-.,+]", &["--freestanding"]);
+    let out = run(&mut Command::new(dir.exe()), b"echo\n\xfe");
+    assert_eq!(out.stdout, b"echo\n\xfe");
+    assert_eq!(out.status.code(), Some(0));
+    assert!(fs::metadata(dir.exe()).unwrap().len() < 1024);
+    if let Some(libraries) = shared_libraries(&dir.exe()) {
+        assert!(!libraries.contains("=>"), "{}", libraries);
+    }
+
+    // A long run of clears becomes a call to the module's own memset. The
+    // input moved into cell 1 keeps it from being folded away.
+    let source = format!(",[>+<-]{}{}.", "[-]>".repeat(20000), "<".repeat(19999));
+    build(&dir, &source, &["--freestanding", "--cells", "30000"]);
+    let out = run(&mut Command::new(dir.exe()), &[7]);
+    assert_eq!(out.stdout, [0]);
+
+    build(&dir, "+++++", &["--freestanding", "--exit-cell"]);
+    let out = run(&mut Command::new(dir.exe()), b"");
+    assert_eq!(out.status.code(), Some(5));
+}
@@ -0,0 +1,393 @@
+//! Runs well-known programs and checks their exact output, in the JIT
+//! where it can run and in the interpreter otherwise.
+
+use bf_llvm::interpreter::{self, Limits, Tape};
+use bf_llvm::jit::{run_jit, RunResult};
+use bf_llvm::lexer::parse;
+use bf_llvm::optimizer::{self, Passes};
+use bf_llvm::{generate, CompileError, CompileOptions, OutputEncoding};
+use inkwell::context::Context;
+use inkwell::targets::{InitializationConfig, Target};
+
+/// Stdout of `source` run with `input`.
+fn run(source: &str, input: &[u8]) -> Vec<u8> {
+    run_with(source, input, &CompileOptions::default())
+}
+
+fn run_with(source: &str, input: &[u8], opts: &CompileOptions) -> Vec<u8> {
+    match run_jit(source, input, opts) {
+        Ok(result) => {
+            assert_eq!(result.exit_code, 0);
+            result.output
+        }
+        Err(CompileError::Jit(reason)) => {
+            eprintln!("can't run in the JIT ({}), using the interpreter", reason);
+            interpret(source, input, opts)
+        }
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Stdout of `source` run with `input` by the reference interpreter.
+fn interpret(source: &str, input: &[u8], opts: &CompileOptions) -> Vec<u8> {
+    let ops = parse(source).unwrap();
+    let output = interpreter::interpret(&ops, input, Tape::of(opts), Limits::default());
+    interpreter::encode(&output.unwrap(), opts.output_encoding)
+}
+
+#[test]
+fn hello_world() {
+    let source = include_str!("../examples/hello_world.bf");
+    assert_eq!(run(source, b""), b"Hello, World!");
+}
+
+#[test]
+fn cat() {
+    // EOF reads as 255, so 1 is added before testing the cell.
+    let source = ",+[-.,+]";
+    assert_eq!(run(source, b"cat\n\x00\x7f"), b"cat\n\x00\x7f");
+    assert_eq!(run(source, b""), b"");
+}
+
+#[test]
+fn jit_captures_io() {
+    let opts = CompileOptions::default();
+    let hello = include_str!("../examples/hello_world.bf");
+    assert_eq!(
+        run_jit(hello, b"", &opts).unwrap(),
+        RunResult {
+            output: b"Hello, World!".to_vec(),
+            exit_code: 0,
+        }
+    );
+    let echo = run_jit(",.", b"xy", &opts).unwrap();
+    assert_eq!((echo.output, echo.exit_code), (b"x".to_vec(), 0));
+    // Data after a `!` is read instead.
+    assert_eq!(run_jit(",.!q", b"x", &opts).unwrap().output, b"q");
+    // Each thread has its own stdin and stdout.
+    let threads: Vec<_> = (b'a'..=b'h')
+        .map(|byte| std::thread::spawn(move || run_jit(",.", &[byte], &CompileOptions::default())))
+        .collect();
+    for (byte, thread) in (b'a'..).zip(threads) {
+        assert_eq!(thread.join().unwrap().unwrap().output, [byte]);
+    }
+    for opts in [
+        CompileOptions {
+            max_steps: 10,
+            ..Default::default()
+        },
+        CompileOptions {
+            freestanding: true,
+            ..Default::default()
+        },
+    ] {
+        assert!(matches!(
+            run_jit(",.", b"", &opts),
+            Err(CompileError::Jit(_))
+        ));
+    }
+}
+
+#[test]
+fn exit_cell() {
+    let opts = CompileOptions {
+        exit_cell: true,
+        ..Default::default()
+    };
+    let exit_code =
+        |source: &str, opts: &CompileOptions| run_jit(source, b"", opts).unwrap().exit_code;
+    assert_eq!(exit_code("+++++", &opts), 5);
+    assert_eq!(exit_code(&"+".repeat(256), &opts), 0);
+    // The current cell, not the first one.
+    assert_eq!(exit_code("+++>++", &opts), 2);
+    // Without the BF level passes the cell is counted up at run time.
+    let unoptimized = CompileOptions {
+        passes: Passes::NONE,
+        ..opts.clone()
+    };
+    assert_eq!(exit_code(&"+".repeat(261), &unoptimized), 5);
+    assert_eq!(exit_code("+++++", &CompileOptions::default()), 0);
+}
+
+#[test]
+fn countdown() {
+    // 10 turns, printing '9' down to '0'.
+    let source = "++++++++++>>+++++++[<++++++++>-]<+<[>.-<-]";
+    assert_eq!(run(source, b""), b"9876543210");
+}
+
+#[test]
+fn proc_defined_after_loop() {
+    // Without the BF level passes the loop stays, so the proc's body is
+    // generated while main is past the loop's blocks.
+    let source = "+[+]*++.*>*<+.";
+    let opts = CompileOptions {
+        passes: Passes::NONE,
+        ..Default::default()
+    };
+    let ctx = Context::create();
+    generate(&ctx, source, &opts).unwrap().verify().unwrap();
+    assert_eq!(run_with(source, b"", &opts), [2, 1]);
+}
+
+#[test]
+fn recursive_proc() {
+    // Counts the cell down to zero, one call deeper for each step, before
+    // printing it and the next cell after an increment.
+    let source = "+++++@dec{-[@dec;]}@dec;.>+.";
+    let opts = CompileOptions::default();
+    assert_eq!(run(source, b""), [0, 1]);
+    assert_eq!(interpret(source, b"", &opts), [0, 1]);
+}
+
+#[test]
+fn wide_cell_loops() {
+    // Each loop only ends once its counter is zero at the full width, so
+    // moving 256, or 256 * 256, into the next cell gets all of it there.
+    let counts = [
+        (16, format!("{}[>+<-]>", "+".repeat(256)), 256),
+        (
+            32,
+            format!("{}[>{}<-]>", "+".repeat(256), "+".repeat(256)),
+            65536,
+        ),
+    ];
+    for (cell_bits, source, expected) in counts {
+        let opts = CompileOptions {
+            cell_bits,
+            exit_cell: true,
+            passes: Passes::NONE,
+            ..Default::default()
+        };
+        let ctx = Context::create();
+        generate(&ctx, &source, &opts).unwrap().verify().unwrap();
+        let result = run_jit(&source, b"", &opts).unwrap();
+        assert_eq!(result.exit_code, expected, "{} bit cells", cell_bits);
+    }
+}
+
+#[test]
+fn putchar_gets_the_low_byte() {
+    // 200, and at the wider widths 0x1c8, both print 0xc8.
+    for (cell_bits, count) in [(8, 200), (16, 456), (32, 456)] {
+        let opts = CompileOptions {
+            cell_bits,
+            passes: Passes::NONE,
+            ..Default::default()
+        };
+        let source = format!("{}.", "+".repeat(count));
+        let ctx = Context::create();
+        let cdg = generate(&ctx, &source, &opts).unwrap();
+        cdg.verify().unwrap();
+        let ir = cdg.module().print_to_string().to_string();
+        assert!(ir.contains("@putchar(i32 %"), "{}", ir);
+        let result = run_jit(&source, b"", &opts).unwrap();
+        assert_eq!(result.output, [0xc8], "{} bit cells", cell_bits);
+    }
+}
+
+#[test]
+fn long_output_runs() {
+    // Up to a buffer, exactly one and two, and a few over.
+    for count in [3, 256, 512, 1000] {
+        let source = format!(",{}", ".".repeat(count));
+        let ctx = Context::create();
+        let cdg = generate(&ctx, &source, &CompileOptions::default()).unwrap();
+        cdg.verify().unwrap();
+        assert_eq!(run(&source, b"A"), vec![b'A'; count]);
+    }
+    // Runs in main and in a proc share their function's one buffer.
+    let source = format!(",{0}>,{0}@p{{{0}}}@p;", ".".repeat(300));
+    let ctx = Context::create();
+    let ir = generate(&ctx, &source, &CompileOptions::default())
+        .unwrap()
+        .ir();
+    assert_eq!(ir.matches("alloca [256 x i8]").count(), 2, "{}", ir);
+    let expected = [vec![b'A'; 300], vec![b'B'; 600]].concat();
+    assert_eq!(run(&source, b"AB"), expected);
+}
+
+#[test]
+fn call_before_definition() {
+    assert_eq!(run("@up;@up;.@up{+}", b""), [2]);
+}
+
+#[test]
+fn high_output_bytes() {
+    // 200 is written as is, or as U+00C8 in UTF-8, whether it's printed at
+    // compile time or by the generated code.
+    let source = format!("{}.", "+".repeat(200));
+    for passes in [Passes::default(), Passes::NONE] {
+        for (output_encoding, expected) in [
+            (OutputEncoding::Byte, &[0xC8][..]),
+            (OutputEncoding::Utf8, &[0xC3, 0x88][..]),
+        ] {
+            let opts = CompileOptions {
+                passes,
+                output_encoding,
+                ..Default::default()
+            };
+            assert_eq!(run_with(&source, b"", &opts), expected);
+            assert_eq!(interpret(&source, b"", &opts), expected);
+        }
+    }
+}
+
+#[test]
+fn signed_output() {
+    // A cell of 200 is -56 when signed, which isn't a code point.
+    let source = format!("{}.", "+".repeat(200));
+    for passes in [Passes::default(), Passes::NONE] {
+        for (signed_cells, expected) in [(false, "\u{C8}"), (true, "\u{FFFD}")] {
+            let opts = CompileOptions {
+                passes,
+                signed_cells,
+                output_encoding: OutputEncoding::Utf8,
+                ..Default::default()
+            };
+            assert_eq!(run_with(&source, b"", &opts), expected.as_bytes());
+            // Optimized, so the interpreter runs the Print ops too.
+            let ops = optimizer::optimize(parse(&source).unwrap(), &opts);
+            let output = interpreter::interpret(&ops, b"", Tape::of(&opts), Limits::default());
+            let output = interpreter::encode(&output.unwrap(), opts.output_encoding);
+            assert_eq!(output, expected.as_bytes());
+        }
+    }
+}
+
+#[test]
+fn signed_input() {
+    // 0x90 is read as -112 when signed. EOF is -1, so adding one gives
+    // zero at any width, where unsigned it is 255 and only wraps to zero
+    // in a byte.
+    for (signed_cells, read, eof) in [(false, "\u{90}", "\u{100}"), (true, "\u{FFFD}", "\0")] {
+        let opts = CompileOptions {
+            signed_cells,
+            cell_bits: 16,
+            output_encoding: OutputEncoding::Utf8,
+            ..Default::default()
+        };
+        assert_eq!(run_with(",.", b"\x90", &opts), read.as_bytes());
+        assert_eq!(interpret(",.", b"\x90", &opts), read.as_bytes());
+        assert_eq!(run_with(",+.", b"", &opts), eof.as_bytes());
+        assert_eq!(interpret(",+.", b"", &opts), eof.as_bytes());
+    }
+}
+
+/// Compiles `source` with `--reusable` and calls `bf_run` in the JIT on
+/// each of `tapes` in turn, returning what each call returned.
+fn bf_run(source: &str, opts: &CompileOptions, tapes: &mut [Vec<u8>]) -> Vec<i32> {
+    let opts = CompileOptions {
+        reusable: true,
+        ..opts.clone()
+    };
+    let ctx = Context::create();
+    let cdg = generate(&ctx, source, &opts).unwrap();
+    cdg.verify().unwrap();
+    Target::initialize_native(&InitializationConfig::default()).unwrap();
+    let engine = cdg
+        .module()
+        .create_jit_execution_engine(opts.opt_level)
+        .unwrap();
+    let bf_run =
+        unsafe { engine.get_function::<unsafe extern "C" fn(*mut u8) -> i32>("bf_run") }.unwrap();
+    tapes
+        .iter_mut()
+        .map(|tape| unsafe { bf_run.call(tape.as_mut_ptr()) })
+        .collect()
+}
+
+#[test]
+fn reusable_runs_on_given_tapes() {
+    // Moves the first cell into the second and adds 1, so the result shows
+    // whether the loop ran on what the tape held.
+    let opts = CompileOptions {
+        exit_cell: true,
+        ..Default::default()
+    };
+    let mut tapes = vec![vec![0u8; opts.cells as usize]; 3];
+    tapes[2][0] = 41;
+    assert_eq!(bf_run("[->+<]>+", &opts, &mut tapes), [1, 1, 42]);
+    assert_eq!(tapes[0][..2], [0, 1]);
+    assert_eq!(tapes[1][..2], [0, 1]);
+    assert_eq!(tapes[2][..2], [0, 42]);
+}
+
+#[test]
+fn interpreter_matches_jit() {
+    let corpus: [(&str, &[u8]); 5] = [
+        (include_str!("../examples/hello_world.bf"), b""),
+        (include_str!("../examples/what_is_your_name.bf"), b"Ada\n"),
+        (include_str!("../examples/print_char_proc.bf"), b""),
+        (",.>,.>,.>,.", b"\x00\x7f\x80\xfe"),
+        (",[>+>+<<-]>[-<+>]>-.<<-.>.", b"\x03"),
+    ];
+    // The examples count on cells wrapping, which takes too long with 32
+    // bits.
+    let shapes = [
+        CompileOptions::default(),
+        CompileOptions {
+            passes: Passes::NONE,
+            ..Default::default()
+        },
+        CompileOptions {
+            cell_bits: 16,
+            signed_cells: true,
+            ..Default::default()
+        },
+        CompileOptions {
+            cell_bits: 16,
+            wrap_pointer: true,
+            ..Default::default()
+        },
+        CompileOptions {
+            start_cell: 10,
+            output_encoding: OutputEncoding::Utf8,
+            ..Default::default()
+        },
+    ];
+    for opts in &shapes {
+        for (source, input) in corpus {
+            assert_eq!(
+                run_jit(source, input, opts).unwrap().output,
+                interpret(source, input, opts),
+                "{} with {:?}",
+                source,
+                opts
+            );
+        }
+    }
+}
+
+#[test]
+fn proc_on_wide_cells() {
+    let source = format!("@inc{{+}}{}@inc;>@inc;@inc;[-<+>]<", "+".repeat(256));
+    let opts = CompileOptions {
+        cell_bits: 32,
+        exit_cell: true,
+        ..Default::default()
+    };
+    let ctx = Context::create();
+    let cdg = generate(&ctx, &source, &opts).unwrap();
+    cdg.verify().unwrap();
+    assert!(cdg.ir().contains("@bf_proc_inc(i32* %0)"), "{}", cdg.ir());
+    assert_eq!(run_jit(&source, b"", &opts).unwrap().exit_code, 259);
+}
+
+#[test]
+fn start_cell() {
+    let opts = CompileOptions {
+        start_cell: 5,
+        ..Default::default()
+    };
+    let mut tapes = vec![vec![0u8; opts.cells as usize]];
+    bf_run("<+", &opts, &mut tapes);
+    assert_eq!(tapes[0][..6], [0, 0, 0, 0, 1, 0]);
+
+    // Five cells to the left, and no more.
+    let tape = Tape::of(&opts);
+    let ops = parse("<<<<<").unwrap();
+    assert!(interpreter::interpret(&ops, b"", tape, Limits::default()).is_ok());
+    let ops = parse("<<<<<<").unwrap();
+    assert!(interpreter::interpret(&ops, b"", tape, Limits::default()).is_err());
+}
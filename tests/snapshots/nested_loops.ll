@@ -0,0 +1,77 @@
+@tape = private global i8* null
+@abort_msg = private unnamed_addr constant [24 x i8] c"tape allocation failed\0A\00", align 1
+@stderr = external global i8*
+
+declare i8 @putchar(i32)
+
+declare i8 @getchar()
+
+declare i64 @fwrite(i8*, i64, i64, i8*)
+
+declare i8* @calloc(i64, i64)
+
+declare i8* @memchr(i8*, i32, i64)
+
+declare i8* @realloc(i8*, i64)
+
+declare void @abort()
+
+define i32 @main() {
+entry:
+  %block = call i8* @calloc(i64 1000, i64 1)
+  store i8* %block, i8** @tape, align 8
+  %alloc_failed = icmp eq i8* %block, null
+  br i1 %alloc_failed, label %alloc_failed1, label %alloc_ok
+
+alloc_failed1:                                    ; preds = %entry
+  %stream = load i8*, i8** @stderr, align 8
+  %0 = call i32 @fputs(i8* getelementptr inbounds ([24 x i8], [24 x i8]* @abort_msg, i32 0, i32 0), i8* %stream)
+  call void @abort()
+  unreachable
+
+alloc_ok:                                         ; preds = %entry
+  %load_val = load i8, i8* %block, align 1
+  %add = add i8 %load_val, 2
+  store i8 %add, i8* %block, align 1
+  br label %body_block
+
+body_block:                                       ; preds = %end_block6, %alloc_ok
+  %loop_ptr = phi i8* [ %block, %alloc_ok ], [ %gep16, %end_block6 ]
+  %gep = getelementptr i8, i8* %loop_ptr, i64 1
+  %load_val2 = load i8, i8* %gep, align 1
+  %add3 = add i8 %load_val2, 2
+  store i8 %add3, i8* %gep, align 1
+  %gep4 = getelementptr i8, i8* %loop_ptr, i64 1
+  br label %cond_block
+
+end_block:                                        ; preds = %end_block6
+  ret i32 0
+
+cond_block:                                       ; preds = %body_block5, %body_block
+  %loop_ptr7 = phi i8* [ %gep4, %body_block ], [ %loop_ptr7, %body_block5 ]
+  %load_val8 = load i8, i8* %loop_ptr7, align 1
+  %ne_zero = icmp ne i8 %load_val8, 0
+  br i1 %ne_zero, label %body_block5, label %end_block6
+
+body_block5:                                      ; preds = %cond_block
+  %gep9 = getelementptr i8, i8* %loop_ptr7, i64 1
+  %load_val10 = load i8, i8* %gep9, align 1
+  %add11 = add i8 %load_val10, 1
+  store i8 %add11, i8* %gep9, align 1
+  %load_val12 = load i8, i8* %loop_ptr7, align 1
+  %sub = sub i8 %load_val12, 1
+  store i8 %sub, i8* %loop_ptr7, align 1
+  br label %cond_block
+
+end_block6:                                       ; preds = %cond_block
+  %gep13 = getelementptr i8, i8* %loop_ptr7, i64 -1
+  %load_val14 = load i8, i8* %gep13, align 1
+  %sub15 = sub i8 %load_val14, 1
+  store i8 %sub15, i8* %gep13, align 1
+  %gep16 = getelementptr i8, i8* %loop_ptr7, i64 -1
+  %load_val17 = load i8, i8* %gep16, align 1
+  %ne_zero18 = icmp ne i8 %load_val17, 0
+  br i1 %ne_zero18, label %body_block, label %end_block
+}
+
+declare i32 @fputs(i8*, i8*)
@@ -0,0 +1,49 @@
+@tape = private global i8* null
+@abort_msg = private unnamed_addr constant [24 x i8] c"tape allocation failed\0A\00", align 1
+@stderr = external global i8*
+
+declare i8 @putchar(i32)
+
+declare i8 @getchar()
+
+declare i64 @fwrite(i8*, i64, i64, i8*)
+
+declare i8* @calloc(i64, i64)
+
+declare i8* @memchr(i8*, i32, i64)
+
+declare i8* @realloc(i8*, i64)
+
+declare void @abort()
+
+define i32 @main() {
+entry:
+  %block = call i8* @calloc(i64 1000, i64 1)
+  store i8* %block, i8** @tape, align 8
+  %alloc_failed = icmp eq i8* %block, null
+  br i1 %alloc_failed, label %alloc_failed1, label %alloc_ok
+
+alloc_failed1:                                    ; preds = %entry
+  %stream = load i8*, i8** @stderr, align 8
+  %0 = call i32 @fputs(i8* getelementptr inbounds ([24 x i8], [24 x i8]* @abort_msg, i32 0, i32 0), i8* %stream)
+  call void @abort()
+  unreachable
+
+alloc_ok:                                         ; preds = %entry
+  %gep = getelementptr i8, i8* %block, i64 1
+  call void @bf_cproc_2a(i8* %gep)
+  ret i32 0
+}
+
+declare i32 @fputs(i8*, i8*)
+
+define internal void @bf_cproc_2a(i8* %0) {
+entry:
+  %load_val = load i8, i8* %0, align 1
+  %add = add i8 %load_val, 1
+  store i8 %add, i8* %0, align 1
+  %load_val1 = load i8, i8* %0, align 1
+  %c = zext i8 %load_val1 to i32
+  %out = call i8 @putchar(i32 %c)
+  ret void
+}
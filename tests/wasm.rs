@@ -0,0 +1,123 @@
+//! Builds programs for WebAssembly and runs them, under `wasmtime` for
+//! WASI and `node` for `wasm32-unknown-unknown`. Without the linker or the
+//! runtime the tests print why and pass.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use bf_llvm::{compile_to_object, CompileOptions};
+
+/// A directory of its own for one test's `main.wasm`, removed when
+/// dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bf-wasm-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Whether all of `tools` are installed, printing a note if one isn't.
+fn have(tools: &[&str]) -> bool {
+    for tool in tools {
+        let found = Command::new(tool)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok();
+        if !found {
+            eprintln!("skipping: no {}", tool);
+            return false;
+        }
+    }
+    true
+}
+
+/// Compiles hello world for `target` in `dir`, giving `main.wasm`.
+fn build_hello(dir: &TempDir, target: &str) {
+    fs::write(
+        dir.0.join("main.bf"),
+        include_str!("../examples/hello_world.bf"),
+    )
+    .unwrap();
+    let build = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(["main.bf", "--target", target])
+        .current_dir(&dir.0)
+        .output()
+        .unwrap();
+    assert!(
+        build.status.success(),
+        "bf failed:\n{}",
+        String::from_utf8_lossy(&build.stderr)
+    );
+}
+
+#[test]
+fn object_is_wasm() {
+    let opts = CompileOptions {
+        target: Some("wasm32-unknown-unknown".to_string()),
+        ..Default::default()
+    };
+    let object = compile_to_object(include_str!("../examples/hello_world.bf"), &opts).unwrap();
+    assert_eq!(object[..4], *b"\0asm");
+}
+
+#[test]
+fn wasi_hello_world() {
+    if !have(&["clang", "wasmtime"]) {
+        return;
+    }
+    let dir = TempDir::new("wasi");
+    build_hello(&dir, "wasm32-wasi");
+    let out = Command::new("wasmtime")
+        .arg("main.wasm")
+        .current_dir(&dir.0)
+        .output()
+        .unwrap();
+    assert_eq!(out.stdout, b"Hello, World!");
+    assert_eq!(out.status.code(), Some(0));
+}
+
+/// The node harness from the README.
+const HARNESS: &str = r#"
+const bytes = require("fs").readFileSync("main.wasm");
+let memory;
+const decoder = new TextDecoder();
+const env = {
+  putchar: (c) => (process.stdout.write(String.fromCharCode(c & 255)), c),
+  getchar: () => -1,
+  read: () => 0,
+  write: (fd, ptr, len) => (process.stdout.write(decoder.decode(new Uint8Array(memory.buffer, ptr, len))), len),
+};
+WebAssembly.instantiate(bytes, { env }).then(({ instance }) => {
+  memory = instance.exports.memory;
+  instance.exports.main();
+});
+"#;
+
+#[test]
+fn unknown_unknown_hello_world() {
+    if !have(&["wasm-ld", "node"]) {
+        return;
+    }
+    let dir = TempDir::new("unknown");
+    build_hello(&dir, "wasm32-unknown-unknown");
+    fs::write(dir.0.join("run.js"), HARNESS).unwrap();
+    let out = Command::new("node")
+        .arg("run.js")
+        .current_dir(&dir.0)
+        .output()
+        .unwrap();
+    assert_eq!(out.stdout, b"Hello, World!");
+    assert_eq!(out.status.code(), Some(0));
+}